@@ -0,0 +1,1101 @@
+use crate::memory::Memory;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+use crate::registers::{
+    NR10_ADDRESS, NR11_ADDRESS, NR12_ADDRESS, NR13_ADDRESS, NR14_ADDRESS, NR21_ADDRESS,
+    NR22_ADDRESS, NR23_ADDRESS, NR24_ADDRESS, NR30_ADDRESS, NR31_ADDRESS, NR32_ADDRESS,
+    NR33_ADDRESS, NR34_ADDRESS, NR41_ADDRESS, NR42_ADDRESS, NR43_ADDRESS, NR44_ADDRESS,
+    NR50_ADDRESS, NR51_ADDRESS, NR52_ADDRESS, WAVE_RAM_ADDRESS,
+};
+
+pub const SAMPLE_RATE: u32 = 48000;
+const CLOCK_RATE: u64 = 4194304;
+// Samples nobody collects are thrown away past this point (half a second of stereo audio)
+const MAX_BUFFERED_SAMPLES: usize = SAMPLE_RATE as usize;
+// Charge factor of the output capacitor for each emulated clock
+const HIGH_PASS_CHARGE_PER_CLOCK: f64 = 0.999958;
+
+const REGISTERS_START: u16 = NR10_ADDRESS;
+const REGISTERS_END: u16 = NR52_ADDRESS;
+const WAVE_RAM_SIZE: usize = 16;
+
+// Bits that always read back as 1 for each register from NR10 to NR52
+const READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // Unused, NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // Unused, NR41-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+];
+
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+fn dac_output(enabled: bool, digital: u8) -> f32 {
+    if enabled {
+        digital as f32 / 7.5 - 1.0
+    } else {
+        0.0
+    }
+}
+
+struct Envelope {
+    initial_volume: u8,
+    increasing: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope {
+            initial_volume: 0,
+            increasing: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    fn set(&mut self, data: u8) {
+        self.initial_volume = data >> 4;
+        self.increasing = data & 0b00001000 != 0;
+        self.period = data & 0b00000111;
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    fn tick(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increasing && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increasing && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    shadow_frequency: u16,
+    timer: u8,
+    enabled: bool,
+}
+
+impl Sweep {
+    fn new() -> Self {
+        Sweep {
+            period: 0,
+            negate: false,
+            shift: 0,
+            shadow_frequency: 0,
+            timer: 0,
+            enabled: false,
+        }
+    }
+
+    fn set(&mut self, data: u8) {
+        self.period = (data & 0b01110000) >> 4;
+        self.negate = data & 0b00001000 != 0;
+        self.shift = data & 0b00000111;
+    }
+
+    fn reload_timer(&mut self) {
+        // A period of zero is treated as eight by the sweep timer
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn next_frequency(&self) -> u16 {
+        let delta = self.shadow_frequency >> self.shift;
+        if self.negate {
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        }
+    }
+}
+
+struct SquareChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    duty: u8,
+    duty_position: u8,
+    length_counter: u8,
+    length_enabled: bool,
+    frequency: u16,
+    timer: u32,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+}
+
+impl SquareChannel {
+    fn new(has_sweep: bool) -> Self {
+        SquareChannel {
+            enabled: false,
+            dac_enabled: false,
+            duty: 0,
+            duty_position: 0,
+            length_counter: 0,
+            length_enabled: false,
+            frequency: 0,
+            timer: 0,
+            envelope: Envelope::new(),
+            sweep: if has_sweep { Some(Sweep::new()) } else { None },
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    fn set_length(&mut self, data: u8) {
+        self.duty = data >> 6;
+        self.length_counter = 64 - (data & 0b00111111);
+    }
+
+    fn set_envelope(&mut self, data: u8) {
+        self.envelope.set(data);
+        self.dac_enabled = data & 0b11111000 != 0;
+        if !self.dac_enabled {
+            self.enabled = false;
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length_counter == 0 {
+            self.length_counter = 64;
+        }
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        let frequency = self.frequency;
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow_frequency = frequency;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            // The overflow check also runs immediately when the shift is non-zero
+            if sweep.shift != 0 && sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles > 0 {
+            if self.timer > cycles {
+                self.timer -= cycles;
+                break;
+            }
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+    }
+
+    fn tick_length(&mut self) {
+        if self.length_enabled && self.length_counter > 0 {
+            self.length_counter -= 1;
+            if self.length_counter == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn tick_sweep(&mut self) {
+        let Some(sweep) = self.sweep.as_mut() else {
+            return;
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer != 0 {
+            return;
+        }
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+
+        let new_frequency = sweep.next_frequency();
+        if new_frequency > 2047 {
+            self.enabled = false;
+        } else if sweep.shift != 0 {
+            sweep.shadow_frequency = new_frequency;
+            self.frequency = new_frequency;
+            // Check overflow again with the updated frequency
+            if sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let digital = if self.enabled {
+            DUTY_PATTERNS[self.duty as usize][self.duty_position as usize] * self.envelope.volume
+        } else {
+            0
+        };
+        dac_output(self.dac_enabled, digital)
+    }
+}
+
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    length_counter: u16,
+    length_enabled: bool,
+    volume_code: u8,
+    frequency: u16,
+    timer: u32,
+    position: u8,
+    sample_buffer: u8,
+}
+
+impl WaveChannel {
+    fn new() -> Self {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            length_counter: 0,
+            length_enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample_buffer: 0,
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    fn set_dac(&mut self, data: u8) {
+        self.dac_enabled = data & 0b10000000 != 0;
+        if !self.dac_enabled {
+            self.enabled = false;
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length_counter == 0 {
+            self.length_counter = 256;
+        }
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    fn step(&mut self, cycles: u32, wave_ram: &[u8; WAVE_RAM_SIZE]) {
+        let mut cycles = cycles;
+        while cycles > 0 {
+            if self.timer > cycles {
+                self.timer -= cycles;
+                break;
+            }
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+            let sample_byte = wave_ram[(self.position / 2) as usize];
+            // The high nibble is played first
+            self.sample_buffer = if self.position & 1 == 0 {
+                sample_byte >> 4
+            } else {
+                sample_byte & 0x0F
+            };
+        }
+    }
+
+    fn tick_length(&mut self) {
+        if self.length_enabled && self.length_counter > 0 {
+            self.length_counter -= 1;
+            if self.length_counter == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let digital = if self.enabled {
+            match self.volume_code {
+                0 => 0,
+                code => self.sample_buffer >> (code - 1),
+            }
+        } else {
+            0
+        };
+        dac_output(self.dac_enabled, digital)
+    }
+}
+
+struct NoiseChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    length_counter: u8,
+    length_enabled: bool,
+    envelope: Envelope,
+    clock_shift: u8,
+    narrow_width: bool,
+    divisor_code: u8,
+    timer: u32,
+    lfsr: u16,
+}
+
+impl NoiseChannel {
+    fn new() -> Self {
+        NoiseChannel {
+            enabled: false,
+            dac_enabled: false,
+            length_counter: 0,
+            length_enabled: false,
+            envelope: Envelope::new(),
+            clock_shift: 0,
+            narrow_width: false,
+            divisor_code: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+        }
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    fn set_length(&mut self, data: u8) {
+        self.length_counter = 64 - (data & 0b00111111);
+    }
+
+    fn set_envelope(&mut self, data: u8) {
+        self.envelope.set(data);
+        self.dac_enabled = data & 0b11111000 != 0;
+        if !self.dac_enabled {
+            self.enabled = false;
+        }
+    }
+
+    fn set_polynomial(&mut self, data: u8) {
+        self.clock_shift = data >> 4;
+        self.narrow_width = data & 0b00001000 != 0;
+        self.divisor_code = data & 0b00000111;
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length_counter == 0 {
+            self.length_counter = 64;
+        }
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles > 0 {
+            if self.timer > cycles {
+                self.timer -= cycles;
+                break;
+            }
+            cycles -= self.timer;
+            self.timer = self.period();
+
+            let feedback = (self.lfsr & 0b01) ^ ((self.lfsr & 0b10) >> 1);
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            if self.narrow_width {
+                self.lfsr = (self.lfsr & !0b01000000) | (feedback << 6);
+            }
+        }
+    }
+
+    fn tick_length(&mut self) {
+        if self.length_enabled && self.length_counter > 0 {
+            self.length_counter -= 1;
+            if self.length_counter == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let digital = if self.enabled && self.lfsr & 0b01 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        };
+        dac_output(self.dac_enabled, digital)
+    }
+}
+
+// Removes the DC offset the DACs produce, like the capacitors on real hardware
+struct HighPass {
+    capacitor: f32,
+    charge: f32,
+}
+
+impl HighPass {
+    fn new(sample_rate: u32) -> Self {
+        let clocks_per_sample = CLOCK_RATE as f64 / sample_rate as f64;
+        HighPass {
+            capacitor: 0.0,
+            charge: HIGH_PASS_CHARGE_PER_CLOCK.powf(clocks_per_sample) as f32,
+        }
+    }
+
+    fn filter(&mut self, input: f32, dacs_enabled: bool) -> f32 {
+        if !dacs_enabled {
+            return 0.0;
+        }
+        let output = input - self.capacitor;
+        self.capacitor = input - output * self.charge;
+        output
+    }
+}
+
+pub struct APU {
+    registers: [u8; (REGISTERS_END - REGISTERS_START + 1) as usize],
+    wave_ram: [u8; WAVE_RAM_SIZE],
+    powered: bool,
+    square_one: SquareChannel,
+    square_two: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+    frame_sequencer_step: u8,
+    last_div_bit: bool,
+    sample_rate: u32,
+    sample_counter: u64,
+    left_filter: HighPass,
+    right_filter: HighPass,
+    cartridge_output: f32,
+    samples: Vec<f32>,
+}
+
+impl APU {
+    pub fn new() -> Self {
+        APU {
+            registers: [0; (REGISTERS_END - REGISTERS_START + 1) as usize],
+            wave_ram: [0; WAVE_RAM_SIZE],
+            powered: false,
+            square_one: SquareChannel::new(true),
+            square_two: SquareChannel::new(false),
+            wave: WaveChannel::new(),
+            noise: NoiseChannel::new(),
+            frame_sequencer_step: 0,
+            last_div_bit: false,
+            sample_rate: SAMPLE_RATE,
+            sample_counter: 0,
+            left_filter: HighPass::new(SAMPLE_RATE),
+            right_filter: HighPass::new(SAMPLE_RATE),
+            cartridge_output: 0.0,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, cycles: u32, div: u8) {
+        // The frame sequencer is clocked by the falling edge of bit 4 of DIV
+        let div_bit = div & 0b00010000 != 0;
+        if self.powered {
+            if self.last_div_bit && !div_bit {
+                self.step_frame_sequencer();
+            }
+            self.square_one.step(cycles);
+            self.square_two.step(cycles);
+            self.wave.step(cycles, &self.wave_ram);
+            self.noise.step(cycles);
+        }
+        self.last_div_bit = div_bit;
+
+        self.sample_counter += cycles as u64 * self.sample_rate as u64;
+        while self.sample_counter >= CLOCK_RATE {
+            self.sample_counter -= CLOCK_RATE;
+            self.push_sample();
+        }
+    }
+
+    // Resamples the output to match the rate the audio device runs at
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.sample_counter = 0;
+        self.left_filter = HighPass::new(sample_rate);
+        self.right_filter = HighPass::new(sample_rate);
+        self.samples.clear();
+    }
+
+    // Some cartridges have their own speaker that plays alongside the console
+    pub fn set_cartridge_output(&mut self, output: f32) {
+        self.cartridge_output = output;
+    }
+
+    // Returns the interleaved stereo samples produced since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        match self.frame_sequencer_step {
+            0 | 4 => self.tick_lengths(),
+            2 | 6 => {
+                self.tick_lengths();
+                self.square_one.tick_sweep();
+            }
+            7 => {
+                self.square_one.envelope.tick();
+                self.square_two.envelope.tick();
+                self.noise.envelope.tick();
+            }
+            _ => (),
+        }
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+    }
+
+    fn tick_lengths(&mut self) {
+        self.square_one.tick_length();
+        self.square_two.tick_length();
+        self.wave.tick_length();
+        self.noise.tick_length();
+    }
+
+    fn push_sample(&mut self) {
+        let outputs = [
+            self.square_one.output(),
+            self.square_two.output(),
+            self.wave.output(),
+            self.noise.output(),
+        ];
+        let panning = self.register(NR51_ADDRESS);
+        let master_volume = self.register(NR50_ADDRESS);
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, output) in outputs.iter().enumerate() {
+            if panning & (0b00010000 << i) != 0 {
+                left += output;
+            }
+            if panning & (0b00000001 << i) != 0 {
+                right += output;
+            }
+        }
+        let left_volume = ((master_volume >> 4) & 0b111) as f32 + 1.0;
+        let right_volume = (master_volume & 0b111) as f32 + 1.0;
+
+        // Scale so all four channels at full volume stay within -1.0..=1.0
+        let dacs_enabled = self.powered
+            && (self.square_one.dac_enabled
+                || self.square_two.dac_enabled
+                || self.wave.dac_enabled
+                || self.noise.dac_enabled);
+        let left = self
+            .left_filter
+            .filter(left * left_volume / 32.0, dacs_enabled);
+        let right = self
+            .right_filter
+            .filter(right * right_volume / 32.0, dacs_enabled);
+
+        if self.samples.len() >= MAX_BUFFERED_SAMPLES {
+            self.samples.clear();
+        }
+        self.samples.push(left + self.cartridge_output);
+        self.samples.push(right + self.cartridge_output);
+    }
+
+    fn register(&self, address: u16) -> u8 {
+        self.registers[(address - REGISTERS_START) as usize]
+    }
+
+    fn channel_status(&self) -> u8 {
+        (self.square_one.enabled as u8)
+            | (self.square_two.enabled as u8) << 1
+            | (self.wave.enabled as u8) << 2
+            | (self.noise.enabled as u8) << 3
+    }
+
+    fn set_power(&mut self, powered: bool) {
+        if self.powered && !powered {
+            // Powering off clears every register except wave RAM
+            for address in REGISTERS_START..REGISTERS_END {
+                self.write(address, 0);
+            }
+            self.square_one = SquareChannel::new(true);
+            self.square_two = SquareChannel::new(false);
+            self.wave = WaveChannel::new();
+            self.noise = NoiseChannel::new();
+        } else if !self.powered && powered {
+            self.frame_sequencer_step = 0;
+        }
+        self.powered = powered;
+    }
+
+    fn write_frequency_high(&mut self, address: u16, data: u8) {
+        let high_bits = ((data & 0b00000111) as u16) << 8;
+        let length_enabled = data & 0b01000000 != 0;
+        let trigger = data & 0b10000000 != 0;
+        match address {
+            NR14_ADDRESS => {
+                let channel = &mut self.square_one;
+                channel.frequency = (channel.frequency & 0x00FF) | high_bits;
+                channel.length_enabled = length_enabled;
+                if trigger {
+                    channel.trigger();
+                }
+            }
+            NR24_ADDRESS => {
+                let channel = &mut self.square_two;
+                channel.frequency = (channel.frequency & 0x00FF) | high_bits;
+                channel.length_enabled = length_enabled;
+                if trigger {
+                    channel.trigger();
+                }
+            }
+            NR34_ADDRESS => {
+                let channel = &mut self.wave;
+                channel.frequency = (channel.frequency & 0x00FF) | high_bits;
+                channel.length_enabled = length_enabled;
+                if trigger {
+                    channel.trigger();
+                }
+            }
+            NR44_ADDRESS => {
+                let channel = &mut self.noise;
+                channel.length_enabled = length_enabled;
+                if trigger {
+                    channel.trigger();
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Memory for APU {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            NR52_ADDRESS => {
+                let power_bit = if self.powered { 0b10000000 } else { 0 };
+                READ_MASKS[(address - REGISTERS_START) as usize] | power_bit | self.channel_status()
+            }
+            REGISTERS_START..=REGISTERS_END => {
+                let index = (address - REGISTERS_START) as usize;
+                self.registers[index] | READ_MASKS[index]
+            }
+            wave_address @ 0xFF30..=0xFF3F => {
+                self.wave_ram[(wave_address - WAVE_RAM_ADDRESS) as usize]
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            NR52_ADDRESS => self.set_power(data & 0b10000000 != 0),
+            wave_address @ 0xFF30..=0xFF3F => {
+                self.wave_ram[(wave_address - WAVE_RAM_ADDRESS) as usize] = data;
+            }
+            // Everything else is read-only while the APU is powered off
+            _ if !self.powered => (),
+            REGISTERS_START..=REGISTERS_END => {
+                self.registers[(address - REGISTERS_START) as usize] = data;
+                match address {
+                    NR10_ADDRESS => {
+                        if let Some(sweep) = self.square_one.sweep.as_mut() {
+                            sweep.set(data);
+                        }
+                    }
+                    NR11_ADDRESS => self.square_one.set_length(data),
+                    NR12_ADDRESS => self.square_one.set_envelope(data),
+                    NR13_ADDRESS => {
+                        let channel = &mut self.square_one;
+                        channel.frequency = (channel.frequency & 0x0700) | data as u16;
+                    }
+                    NR21_ADDRESS => self.square_two.set_length(data),
+                    NR22_ADDRESS => self.square_two.set_envelope(data),
+                    NR23_ADDRESS => {
+                        let channel = &mut self.square_two;
+                        channel.frequency = (channel.frequency & 0x0700) | data as u16;
+                    }
+                    NR30_ADDRESS => self.wave.set_dac(data),
+                    NR31_ADDRESS => self.wave.length_counter = 256 - data as u16,
+                    NR32_ADDRESS => self.wave.volume_code = (data & 0b01100000) >> 5,
+                    NR33_ADDRESS => {
+                        let channel = &mut self.wave;
+                        channel.frequency = (channel.frequency & 0x0700) | data as u16;
+                    }
+                    NR41_ADDRESS => self.noise.set_length(data),
+                    NR42_ADDRESS => self.noise.set_envelope(data),
+                    NR43_ADDRESS => self.noise.set_polynomial(data),
+                    NR14_ADDRESS | NR24_ADDRESS | NR34_ADDRESS | NR44_ADDRESS => {
+                        self.write_frequency_high(address, data)
+                    }
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl SaveState for Envelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.initial_volume);
+        state.write_bool(self.increasing);
+        state.write_u8(self.period);
+        state.write_u8(self.volume);
+        state.write_u8(self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.initial_volume = state.read_u8()?;
+        self.increasing = state.read_bool()?;
+        self.period = state.read_u8()?;
+        self.volume = state.read_u8()?;
+        self.timer = state.read_u8()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Sweep {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.period);
+        state.write_bool(self.negate);
+        state.write_u8(self.shift);
+        state.write_u16(self.shadow_frequency);
+        state.write_u8(self.timer);
+        state.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.period = state.read_u8()?;
+        self.negate = state.read_bool()?;
+        self.shift = state.read_u8()?;
+        self.shadow_frequency = state.read_u16()?;
+        self.timer = state.read_u8()?;
+        self.enabled = state.read_bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for SquareChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.dac_enabled);
+        state.write_u8(self.duty);
+        state.write_u8(self.duty_position);
+        state.write_u8(self.length_counter);
+        state.write_bool(self.length_enabled);
+        state.write_u16(self.frequency);
+        state.write_u32(self.timer);
+        self.envelope.save_state(state);
+        if let Some(sweep) = &self.sweep {
+            sweep.save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.dac_enabled = state.read_bool()?;
+        self.duty = state.read_u8()?;
+        self.duty_position = state.read_u8()?;
+        self.length_counter = state.read_u8()?;
+        self.length_enabled = state.read_bool()?;
+        self.frequency = state.read_u16()?;
+        self.timer = state.read_u32()?;
+        self.envelope.load_state(state)?;
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.load_state(state)?;
+        }
+        Ok(())
+    }
+}
+
+impl SaveState for WaveChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.dac_enabled);
+        state.write_u16(self.length_counter);
+        state.write_bool(self.length_enabled);
+        state.write_u8(self.volume_code);
+        state.write_u16(self.frequency);
+        state.write_u32(self.timer);
+        state.write_u8(self.position);
+        state.write_u8(self.sample_buffer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.dac_enabled = state.read_bool()?;
+        self.length_counter = state.read_u16()?;
+        self.length_enabled = state.read_bool()?;
+        self.volume_code = state.read_u8()?;
+        self.frequency = state.read_u16()?;
+        self.timer = state.read_u32()?;
+        self.position = state.read_u8()?;
+        self.sample_buffer = state.read_u8()?;
+        Ok(())
+    }
+}
+
+impl SaveState for NoiseChannel {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.dac_enabled);
+        state.write_u8(self.length_counter);
+        state.write_bool(self.length_enabled);
+        self.envelope.save_state(state);
+        state.write_u8(self.clock_shift);
+        state.write_bool(self.narrow_width);
+        state.write_u8(self.divisor_code);
+        state.write_u32(self.timer);
+        state.write_u16(self.lfsr);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.dac_enabled = state.read_bool()?;
+        self.length_counter = state.read_u8()?;
+        self.length_enabled = state.read_bool()?;
+        self.envelope.load_state(state)?;
+        self.clock_shift = state.read_u8()?;
+        self.narrow_width = state.read_bool()?;
+        self.divisor_code = state.read_u8()?;
+        self.timer = state.read_u32()?;
+        self.lfsr = state.read_u16()?;
+        Ok(())
+    }
+}
+
+// The output sample rate and any samples waiting for the audio device belong to the host,
+// so they are left alone
+impl SaveState for APU {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.registers);
+        state.write_bytes(&self.wave_ram);
+        state.write_bool(self.powered);
+        self.square_one.save_state(state);
+        self.square_two.save_state(state);
+        self.wave.save_state(state);
+        self.noise.save_state(state);
+        state.write_u8(self.frame_sequencer_step);
+        state.write_bool(self.last_div_bit);
+        state.write_u64(self.sample_counter);
+        state.write_f32(self.left_filter.capacitor);
+        state.write_f32(self.right_filter.capacitor);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.registers)?;
+        state.read_bytes(&mut self.wave_ram)?;
+        self.powered = state.read_bool()?;
+        self.square_one.load_state(state)?;
+        self.square_two.load_state(state)?;
+        self.wave.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.frame_sequencer_step = state.read_u8()?;
+        self.last_div_bit = state.read_bool()?;
+        self.sample_counter = state.read_u64()?;
+        self.left_filter.capacitor = state.read_f32()?;
+        self.right_filter.capacitor = state.read_f32()?;
+        self.samples.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_apu() -> APU {
+        let mut apu = APU::new();
+        apu.write(NR52_ADDRESS, 0b10000000);
+        apu
+    }
+
+    // Produces the given number of falling edges on DIV bit 4
+    fn clock_frame_sequencer(apu: &mut APU, steps: u32) {
+        for _ in 0..steps {
+            apu.update(0, 0b00010000);
+            apu.update(0, 0);
+        }
+    }
+
+    #[test]
+    fn unused_register_bits_read_as_one() {
+        let mut apu = get_test_apu();
+        apu.write(NR11_ADDRESS, 0b10010101);
+        apu.write(NR13_ADDRESS, 0x12);
+        assert_eq!(apu.read(NR11_ADDRESS), 0b10111111);
+        assert_eq!(apu.read(NR13_ADDRESS), 0xFF);
+        assert_eq!(apu.read(0xFF27), 0xFF);
+    }
+
+    #[test]
+    fn triggering_a_channel_sets_its_status_bit() {
+        let mut apu = get_test_apu();
+        apu.write(NR22_ADDRESS, 0xF0);
+        apu.write(NR24_ADDRESS, 0b10000000);
+        assert_eq!(apu.read(NR52_ADDRESS), 0b11110010);
+    }
+
+    #[test]
+    fn channel_with_dac_disabled_does_not_turn_on() {
+        let mut apu = get_test_apu();
+        apu.write(NR22_ADDRESS, 0x00);
+        apu.write(NR24_ADDRESS, 0b10000000);
+        assert_eq!(apu.read(NR52_ADDRESS) & 0b00000010, 0);
+    }
+
+    #[test]
+    fn disabling_dac_turns_channel_off() {
+        let mut apu = get_test_apu();
+        apu.write(NR30_ADDRESS, 0b10000000);
+        apu.write(NR34_ADDRESS, 0b10000000);
+        assert_eq!(apu.read(NR52_ADDRESS) & 0b00000100, 0b00000100);
+        apu.write(NR30_ADDRESS, 0);
+        assert_eq!(apu.read(NR52_ADDRESS) & 0b00000100, 0);
+    }
+
+    #[test]
+    fn length_counter_turns_channel_off() {
+        let mut apu = get_test_apu();
+        apu.write(NR42_ADDRESS, 0xF0);
+        apu.write(NR41_ADDRESS, 62);
+        apu.write(NR44_ADDRESS, 0b11000000);
+        // Length is clocked on every other frame sequencer step
+        clock_frame_sequencer(&mut apu, 2);
+        assert_eq!(apu.read(NR52_ADDRESS) & 0b00001000, 0b00001000);
+        clock_frame_sequencer(&mut apu, 2);
+        assert_eq!(apu.read(NR52_ADDRESS) & 0b00001000, 0);
+    }
+
+    #[test]
+    fn length_counter_is_ignored_when_not_enabled() {
+        let mut apu = get_test_apu();
+        apu.write(NR42_ADDRESS, 0xF0);
+        apu.write(NR41_ADDRESS, 63);
+        apu.write(NR44_ADDRESS, 0b10000000);
+        clock_frame_sequencer(&mut apu, 8);
+        assert_eq!(apu.read(NR52_ADDRESS) & 0b00001000, 0b00001000);
+    }
+
+    #[test]
+    fn sweep_overflow_on_trigger_disables_channel_one() {
+        let mut apu = get_test_apu();
+        apu.write(NR10_ADDRESS, 0b00010001);
+        apu.write(NR12_ADDRESS, 0xF0);
+        apu.write(NR13_ADDRESS, 0xFF);
+        apu.write(NR14_ADDRESS, 0b10000111);
+        assert_eq!(apu.read(NR52_ADDRESS) & 0b00000001, 0);
+    }
+
+    #[test]
+    fn sweep_updates_frequency() {
+        let mut apu = get_test_apu();
+        apu.write(NR10_ADDRESS, 0b00010001);
+        apu.write(NR12_ADDRESS, 0xF0);
+        apu.write(NR13_ADDRESS, 0x00);
+        apu.write(NR14_ADDRESS, 0b10000001);
+        // Sweep is clocked on frame sequencer step 2
+        clock_frame_sequencer(&mut apu, 3);
+        assert_eq!(apu.square_one.frequency, 0x100 + 0x80);
+    }
+
+    #[test]
+    fn envelope_decreases_volume() {
+        let mut apu = get_test_apu();
+        apu.write(NR12_ADDRESS, 0xF1);
+        apu.write(NR14_ADDRESS, 0b10000000);
+        // Envelope is clocked on frame sequencer step 7
+        clock_frame_sequencer(&mut apu, 8);
+        assert_eq!(apu.square_one.envelope.volume, 14);
+    }
+
+    #[test]
+    fn powering_off_clears_registers_and_blocks_writes() {
+        let mut apu = get_test_apu();
+        apu.write(NR50_ADDRESS, 0x77);
+        apu.write(WAVE_RAM_ADDRESS, 0xAB);
+        apu.write(NR52_ADDRESS, 0);
+        assert_eq!(apu.read(NR50_ADDRESS), 0x00);
+        apu.write(NR50_ADDRESS, 0x77);
+        assert_eq!(apu.read(NR50_ADDRESS), 0x00);
+        assert_eq!(apu.read(WAVE_RAM_ADDRESS), 0xAB);
+        assert_eq!(apu.read(NR52_ADDRESS), 0x70);
+    }
+
+    #[test]
+    fn noise_lfsr_shifts_when_timer_expires() {
+        let mut apu = get_test_apu();
+        apu.write(NR42_ADDRESS, 0xF0);
+        apu.write(NR43_ADDRESS, 0x00);
+        apu.write(NR44_ADDRESS, 0b10000000);
+        apu.update(8, 0);
+        assert_eq!(apu.noise.lfsr, 0x3FFF);
+    }
+
+    #[test]
+    fn wave_channel_plays_high_nibble_first() {
+        let mut apu = get_test_apu();
+        apu.write(WAVE_RAM_ADDRESS, 0xAB);
+        apu.write(NR30_ADDRESS, 0b10000000);
+        apu.write(NR33_ADDRESS, 0xFF);
+        apu.write(NR34_ADDRESS, 0b10000111);
+        // Position zero is skipped until the timer first expires
+        apu.update(2 * 32, 0);
+        assert_eq!(apu.wave.sample_buffer, 0x0A);
+        apu.update(2, 0);
+        assert_eq!(apu.wave.sample_buffer, 0x0B);
+    }
+
+    #[test]
+    fn produces_stereo_samples_at_output_rate() {
+        let mut apu = get_test_apu();
+        // A quarter of a second
+        for _ in 0..CLOCK_RATE / 64 {
+            apu.update(16, 0);
+        }
+        assert_eq!(apu.take_samples().len(), SAMPLE_RATE as usize / 2);
+        assert!(apu.take_samples().is_empty());
+    }
+
+    #[test]
+    fn produces_samples_at_changed_rate() {
+        let mut apu = get_test_apu();
+        apu.set_sample_rate(32000);
+        // A quarter of a second
+        for _ in 0..CLOCK_RATE / 64 {
+            apu.update(16, 0);
+        }
+        assert_eq!(apu.take_samples().len(), 32000 / 2);
+    }
+
+    #[test]
+    fn channel_panned_left_is_silent_on_the_right() {
+        let mut apu = get_test_apu();
+        apu.write(NR50_ADDRESS, 0x77);
+        apu.write(NR51_ADDRESS, 0b00010000);
+        apu.write(NR11_ADDRESS, 0b10000000);
+        apu.write(NR12_ADDRESS, 0xF0);
+        apu.write(NR14_ADDRESS, 0b10000111);
+        for _ in 0..1000 {
+            apu.update(16, 0);
+        }
+        let samples = apu.take_samples();
+        assert!(samples.iter().step_by(2).any(|left| *left != 0.0));
+        assert!(samples.iter().skip(1).step_by(2).all(|right| *right == 0.0));
+    }
+}
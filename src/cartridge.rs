@@ -0,0 +1,374 @@
+use std::fmt;
+use std::io;
+
+const HEADER_END: usize = 0x0150;
+const ROM_BANK_SIZE: usize = 0x4000;
+
+const LOGO_ADDRESS: usize = 0x0104;
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+// Every MBC1 multicart is 1 MB split into four games of 256 KB
+const MULTICART_SIZE: usize = 0x100000;
+const MULTICART_GAME_SIZE: usize = 0x40000;
+// The MMM01 boots into a menu stored in the last 32 KB of the rom
+const MMM01_MENU_SIZE: usize = 0x8000;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    TooSmall(usize),
+    HeaderChecksum { expected: u8, actual: u8 },
+    GlobalChecksum { expected: u16, actual: u16 },
+    UnsupportedCartridgeType(u8),
+    UnsupportedRomSize(u8),
+    UnsupportedRamSize(u8),
+    RomSizeMismatch { declared: usize, actual: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "couldn't read rom: {e}"),
+            LoadError::TooSmall(length) => {
+                write!(f, "rom is {length} bytes, too small to hold a header")
+            }
+            LoadError::HeaderChecksum { expected, actual } => write!(
+                f,
+                "header checksum is {actual:#04X} but the header says {expected:#04X}"
+            ),
+            LoadError::GlobalChecksum { expected, actual } => write!(
+                f,
+                "global checksum is {actual:#06X} but the header says {expected:#06X}"
+            ),
+            LoadError::UnsupportedCartridgeType(cartridge_type) => {
+                write!(f, "cartridge type {cartridge_type:#04X} isn't supported")
+            }
+            LoadError::UnsupportedRomSize(code) => {
+                write!(f, "rom size code {code:#04X} isn't supported")
+            }
+            LoadError::UnsupportedRamSize(code) => {
+                write!(f, "ram size code {code:#04X} isn't supported")
+            }
+            LoadError::RomSizeMismatch { declared, actual } => write!(
+                f,
+                "header declares a {declared} byte rom but the file is {actual} bytes"
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+// Everything the cartridge header at 0x0100-0x014F describes
+pub struct CartridgeHeader {
+    pub title: String,
+    // All of 0x0134-0x0143 as stored, including any manufacturer code and the cgb flag
+    pub title_bytes: [u8; 16],
+    pub manufacturer: Option<String>,
+    pub cgb_flag: u8,
+    pub sgb_flag: u8,
+    pub old_licensee: u8,
+    pub new_licensee: [u8; 2],
+    pub cartridge_type: u8,
+    pub rom_banks: u16,
+    pub ram_size: usize,
+    pub version: u8,
+    pub global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<Self, LoadError> {
+        if rom.len() < HEADER_END {
+            return Err(LoadError::TooSmall(rom.len()));
+        }
+
+        // The boot rom refuses to start a game with a broken header
+        let header_checksum = rom[0x014D];
+        let actual = get_header_checksum(rom);
+        if actual != header_checksum {
+            return Err(LoadError::HeaderChecksum {
+                expected: header_checksum,
+                actual,
+            });
+        }
+
+        let cgb_flag = rom[0x0143];
+        // Newer cgb games give the end of the title to a four letter manufacturer code
+        let manufacturer_code = &rom[0x013F..0x0143];
+        let has_manufacturer = cgb_flag & 0b10000000 != 0
+            && manufacturer_code
+                .iter()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+        let (title_end, manufacturer) = if has_manufacturer {
+            (
+                0x013F,
+                Some(String::from_utf8_lossy(manufacturer_code).into_owned()),
+            )
+        } else if cgb_flag & 0b10000000 != 0 {
+            (0x0143, None)
+        } else {
+            (0x0144, None)
+        };
+        let title: Vec<u8> = rom[0x0134..title_end]
+            .iter()
+            .copied()
+            .take_while(|&c| c != 0)
+            .collect();
+
+        let rom_size_code = rom[0x0148];
+        let rom_banks = match rom_size_code {
+            0x00..=0x08 => 2 << rom_size_code,
+            // A few larger games use 1.1, 1.2 or 1.5 MB
+            0x52 => 72,
+            0x53 => 80,
+            0x54 => 96,
+            _ => return Err(LoadError::UnsupportedRomSize(rom_size_code)),
+        };
+        let ram_size_code = rom[0x0149];
+        let ram_size = match ram_size_code {
+            0x00 => 0,
+            0x01 => 0x800,
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x20000,
+            0x05 => 0x10000,
+            _ => return Err(LoadError::UnsupportedRamSize(ram_size_code)),
+        };
+
+        Ok(CartridgeHeader {
+            title: String::from_utf8_lossy(&title).into_owned(),
+            title_bytes: rom[0x0134..0x0144].try_into().unwrap(),
+            manufacturer,
+            cgb_flag,
+            sgb_flag: rom[0x0146],
+            old_licensee: rom[0x014B],
+            new_licensee: [rom[0x0144], rom[0x0145]],
+            cartridge_type: rom[0x0147],
+            rom_banks,
+            ram_size,
+            version: rom[0x014C],
+            global_checksum: u16::from_be_bytes([rom[0x014E], rom[0x014F]]),
+        })
+    }
+
+    // Hardware never checks this one, so plenty of homebrew and test roms get it wrong
+    pub fn verify_global_checksum(&self, rom: &[u8]) -> Result<(), LoadError> {
+        let actual = get_global_checksum(rom);
+        if actual != self.global_checksum {
+            return Err(LoadError::GlobalChecksum {
+                expected: self.global_checksum,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    // Catches files that were cut short before any bank gets read out of bounds
+    pub fn verify_rom_size(&self, rom: &[u8]) -> Result<(), LoadError> {
+        let declared = self.rom_banks as usize * ROM_BANK_SIZE;
+        if rom.len() < declared {
+            return Err(LoadError::RomSizeMismatch {
+                declared,
+                actual: rom.len(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn is_cgb_game(&self) -> bool {
+        self.cgb_flag == 0x80 || self.cgb_flag == 0xC0
+    }
+
+    pub fn has_sgb_support(&self) -> bool {
+        self.sgb_flag == 0x03
+    }
+}
+
+impl fmt::Display for CartridgeHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (version {}", self.title, self.version)?;
+        if let Some(manufacturer) = &self.manufacturer {
+            write!(f, ", manufacturer {manufacturer}")?;
+        }
+        if self.is_cgb_game() {
+            write!(f, ", cgb")?;
+        }
+        if self.has_sgb_support() {
+            write!(f, ", sgb")?;
+        }
+        write!(f, ", type {:#04X})", self.cartridge_type)
+    }
+}
+
+// Multicarts look like a normal MBC1 cartridge from the header, but each game they hold starts
+// with its own header and logo
+pub fn is_mbc1_multicart(rom: &[u8]) -> bool {
+    if rom.len() != MULTICART_SIZE {
+        return false;
+    }
+    let logos = (0..MULTICART_SIZE)
+        .step_by(MULTICART_GAME_SIZE)
+        .filter(|game| {
+            let logo_address = game + LOGO_ADDRESS;
+            rom[logo_address..logo_address + NINTENDO_LOGO.len()] == NINTENDO_LOGO
+        })
+        .count();
+    logos > 1
+}
+
+fn get_header_checksum(rom: &[u8]) -> u8 {
+    rom[0x0134..=0x014C]
+        .iter()
+        .fold(0u8, |sum, &byte| sum.wrapping_sub(byte).wrapping_sub(1))
+}
+
+// Sum of every byte in the rom except the two holding the checksum itself
+fn get_global_checksum(rom: &[u8]) -> u16 {
+    let sum = rom
+        .iter()
+        .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+    sum.wrapping_sub(rom[0x014E] as u16)
+        .wrapping_sub(rom[0x014F] as u16)
+}
+
+// MMM01 dumps start with the first game, the header that boots and names the real cartridge
+// type is the menu's
+pub fn get_mmm01_menu_header(rom: &[u8]) -> Option<CartridgeHeader> {
+    if rom.len() < MMM01_MENU_SIZE * 2 || !rom.len().is_multiple_of(MMM01_MENU_SIZE) {
+        return None;
+    }
+    let header = CartridgeHeader::parse(&rom[rom.len() - MMM01_MENU_SIZE..]).ok()?;
+    matches!(header.cartridge_type, 0x0B..=0x0D).then_some(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_rom(title: &[u8], cgb_flag: u8) -> Vec<u8> {
+        let mut rom = vec![0; ROM_BANK_SIZE * 2];
+        rom[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        rom[0x0143] = cgb_flag;
+        rom[0x0147] = 0x01;
+        rom[0x014D] = get_header_checksum(&rom);
+        let global_checksum = get_global_checksum(&rom);
+        rom[0x014E..0x0150].copy_from_slice(&global_checksum.to_be_bytes());
+        rom
+    }
+
+    #[test]
+    fn header_fields_are_parsed() {
+        let rom = get_test_rom(b"TETRIS", 0x00);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "TETRIS");
+        assert_eq!(header.manufacturer, None);
+        assert_eq!(header.cartridge_type, 0x01);
+        assert_eq!(header.rom_banks, 2);
+        assert_eq!(header.ram_size, 0);
+        assert_eq!(header.version, 0);
+        assert!(!header.is_cgb_game());
+        assert!(header.verify_global_checksum(&rom).is_ok());
+        assert!(header.verify_rom_size(&rom).is_ok());
+    }
+
+    #[test]
+    fn cgb_title_leaves_room_for_manufacturer() {
+        let rom = get_test_rom(b"POKEMON_GLDAAUE", 0x80);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "POKEMON_GLD");
+        assert_eq!(header.manufacturer.as_deref(), Some("AAUE"));
+        assert_eq!(header.title_bytes, *b"POKEMON_GLDAAUE\x80");
+        assert!(header.is_cgb_game());
+        assert_eq!(
+            header.to_string(),
+            "POKEMON_GLD (version 0, manufacturer AAUE, cgb, type 0x01)"
+        );
+    }
+
+    #[test]
+    fn broken_header_is_rejected() {
+        let mut rom = get_test_rom(b"TETRIS", 0x00);
+        rom[0x0134] = b'X';
+        assert!(matches!(
+            CartridgeHeader::parse(&rom),
+            Err(LoadError::HeaderChecksum { .. })
+        ));
+        assert!(matches!(
+            CartridgeHeader::parse(&rom[..0x0100]),
+            Err(LoadError::TooSmall(0x0100))
+        ));
+    }
+
+    #[test]
+    fn odd_rom_sizes_and_small_ram_are_supported() {
+        let mut rom = get_test_rom(b"TETRIS", 0x00);
+        rom[0x0148] = 0x52;
+        rom[0x0149] = 0x01;
+        rom[0x014D] = get_header_checksum(&rom);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.rom_banks, 72);
+        assert_eq!(header.ram_size, 0x800);
+
+        rom[0x0148] = 0x08;
+        rom[0x014D] = get_header_checksum(&rom);
+        assert_eq!(CartridgeHeader::parse(&rom).unwrap().rom_banks, 512);
+
+        rom[0x0148] = 0x09;
+        rom[0x014D] = get_header_checksum(&rom);
+        assert!(matches!(
+            CartridgeHeader::parse(&rom),
+            Err(LoadError::UnsupportedRomSize(0x09))
+        ));
+    }
+
+    #[test]
+    fn global_checksum_and_size_are_checked() {
+        let mut rom = get_test_rom(b"TETRIS", 0x00);
+        rom[0x4000] = 0xFF;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(matches!(
+            header.verify_global_checksum(&rom),
+            Err(LoadError::GlobalChecksum { .. })
+        ));
+        assert!(matches!(
+            header.verify_rom_size(&rom[..ROM_BANK_SIZE]),
+            Err(LoadError::RomSizeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn multicarts_are_found_by_their_logos() {
+        let mut rom = vec![0; MULTICART_SIZE];
+        for game in [0, MULTICART_GAME_SIZE * 2] {
+            let logo_address = game + LOGO_ADDRESS;
+            rom[logo_address..logo_address + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+        }
+        assert!(is_mbc1_multicart(&rom));
+
+        rom[MULTICART_GAME_SIZE * 2 + LOGO_ADDRESS] = 0;
+        assert!(!is_mbc1_multicart(&rom));
+    }
+
+    #[test]
+    fn mmm01_menu_header_is_found_at_the_end() {
+        let mut rom = get_test_rom(b"GAME", 0x00);
+        rom.resize(MMM01_MENU_SIZE * 4, 0);
+        assert!(get_mmm01_menu_header(&rom).is_none());
+
+        let mut menu = get_test_rom(b"MENU", 0x00);
+        menu[0x0147] = 0x0B;
+        menu[0x014D] = get_header_checksum(&menu);
+        let menu_start = rom.len() - MMM01_MENU_SIZE;
+        rom[menu_start..menu_start + menu.len()].copy_from_slice(&menu);
+        let header = get_mmm01_menu_header(&rom).unwrap();
+        assert_eq!(header.title, "MENU");
+        assert_eq!(header.cartridge_type, 0x0B);
+    }
+}
@@ -0,0 +1,224 @@
+use std::fmt;
+
+use crate::memory::Memory;
+use crate::registers::SVBK_ADDRESS;
+
+// Patches what the cpu reads from the rom, optionally only while the original byte matches
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GameGenieCode {
+    address: u16,
+    value: u8,
+    compare: Option<u8>,
+}
+
+// Pokes a value into ram every frame, codes for 0xD000-0xDFFF can pick the cgb wram bank
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GameSharkCode {
+    wram_bank: Option<u8>,
+    address: u16,
+    value: u8,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CheatError {
+    line: usize,
+    code: String,
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}: {} isn't a Game Genie or GameShark code",
+            self.line, self.code
+        )
+    }
+}
+
+#[derive(Default)]
+pub struct Cheats {
+    enabled: bool,
+    game_genie_codes: Vec<GameGenieCode>,
+    game_shark_codes: Vec<GameSharkCode>,
+}
+
+impl Cheats {
+    // One code per line, anything after the code is a description and # starts a comment
+    pub fn parse(text: &str) -> Result<Self, CheatError> {
+        let mut cheats = Cheats {
+            enabled: true,
+            ..Cheats::default()
+        };
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let Some(code) = line.split_whitespace().next() else {
+                continue;
+            };
+            if let Some(game_genie_code) = parse_game_genie(code) {
+                cheats.game_genie_codes.push(game_genie_code);
+            } else if let Some(game_shark_code) = parse_game_shark(code) {
+                cheats.game_shark_codes.push(game_shark_code);
+            } else {
+                return Err(CheatError {
+                    line: i + 1,
+                    code: code.to_string(),
+                });
+            }
+        }
+        Ok(cheats)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.game_genie_codes.is_empty() && self.game_shark_codes.is_empty()
+    }
+
+    // Returns whether cheats are now on
+    pub fn toggle(&mut self) -> bool {
+        self.enabled = !self.enabled;
+        self.enabled
+    }
+
+    pub fn patch_rom_read(&self, address: u16, data: u8) -> u8 {
+        if !self.enabled {
+            return data;
+        }
+        self.game_genie_codes
+            .iter()
+            .find(|code| code.address == address && code.compare.is_none_or(|c| c == data))
+            .map_or(data, |code| code.value)
+    }
+
+    pub fn apply_ram_writes(&self, memory: &mut impl Memory) {
+        if !self.enabled {
+            return;
+        }
+        for code in &self.game_shark_codes {
+            match code.wram_bank {
+                // Switch banks just for the write and put the game's bank back afterwards
+                Some(bank) if (0xD000..=0xDFFF).contains(&code.address) => {
+                    let svbk = memory.read(SVBK_ADDRESS);
+                    memory.write(SVBK_ADDRESS, bank);
+                    memory.write(code.address, code.value);
+                    memory.write(SVBK_ADDRESS, svbk);
+                }
+                _ => memory.write(code.address, code.value),
+            }
+        }
+    }
+}
+
+fn parse_hex_digits(code: &str) -> Option<Vec<u8>> {
+    code.chars()
+        .map(|c| c.to_digit(16).map(|digit| digit as u8))
+        .collect()
+}
+
+// ABC-DEF or ABC-DEF-GHI, AB is the new value, FCDE the address with the top nibble inverted
+// and GI the scrambled compare value. H is only there to make codes harder to guess.
+fn parse_game_genie(code: &str) -> Option<GameGenieCode> {
+    let groups: Vec<&str> = code.split('-').collect();
+    if !(groups.len() == 2 || groups.len() == 3) || groups.iter().any(|group| group.len() != 3) {
+        return None;
+    }
+    let digits = parse_hex_digits(&groups.concat())?;
+
+    let value = (digits[0] << 4) | digits[1];
+    let address = ((digits[5] ^ 0xF) as u16) << 12
+        | (digits[2] as u16) << 8
+        | (digits[3] as u16) << 4
+        | digits[4] as u16;
+    if address > 0x7FFF {
+        return None;
+    }
+    let compare =
+        (groups.len() == 3).then(|| ((digits[6] << 4) | digits[8]).rotate_right(2) ^ 0xBA);
+    Some(GameGenieCode {
+        address,
+        value,
+        compare,
+    })
+}
+
+// TTVVLLHH, the type byte is 0x01 for a plain write or 0x90-0x97 to pick a wram bank
+fn parse_game_shark(code: &str) -> Option<GameSharkCode> {
+    if code.len() != 8 {
+        return None;
+    }
+    let digits = parse_hex_digits(code)?;
+    let bytes: Vec<u8> = digits
+        .chunks(2)
+        .map(|pair| (pair[0] << 4) | pair[1])
+        .collect();
+
+    let wram_bank = match bytes[0] {
+        0x00 | 0x01 => None,
+        bank @ 0x90..=0x97 => Some(bank & 0b00000111),
+        _ => return None,
+    };
+    Some(GameSharkCode {
+        wram_bank,
+        address: u16::from_le_bytes([bytes[2], bytes[3]]),
+        value: bytes[1],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem_manager::MemManager;
+
+    #[test]
+    fn game_genie_codes_are_decoded() {
+        assert_eq!(
+            parse_game_genie("00A-17B-C49"),
+            Some(GameGenieCode {
+                address: 0x4A17,
+                value: 0x00,
+                compare: Some(0xC8),
+            })
+        );
+        assert_eq!(
+            parse_game_genie("3E2-4AF"),
+            Some(GameGenieCode {
+                address: 0x024A,
+                value: 0x3E,
+                compare: None,
+            })
+        );
+        // Codes can only patch the rom
+        assert_eq!(parse_game_genie("00A-170-C49"), None);
+    }
+
+    #[test]
+    fn game_genie_patches_only_matching_reads() {
+        let cheats = Cheats::parse("00A-17B-C49 infinite lives").unwrap();
+        assert_eq!(cheats.patch_rom_read(0x4A17, 0xC8), 0x00);
+        assert_eq!(cheats.patch_rom_read(0x4A17, 0xC9), 0xC9);
+        assert_eq!(cheats.patch_rom_read(0x4A18, 0xC8), 0xC8);
+    }
+
+    #[test]
+    fn game_shark_writes_ram_every_frame() {
+        let mut cheats = Cheats::parse("# comment\n\n010138CD\n9203FFD0").unwrap();
+        let mut memory = MemManager::new();
+        memory.write(SVBK_ADDRESS, 5);
+        cheats.apply_ram_writes(&mut memory);
+        assert_eq!(memory.read(0xCD38), 0x01);
+        assert_eq!(memory.read(SVBK_ADDRESS) & 0b00000111, 5);
+        assert_eq!(memory.read(0xD0FF), 0x00);
+        memory.write(SVBK_ADDRESS, 2);
+        assert_eq!(memory.read(0xD0FF), 0x03);
+
+        memory.write(0xCD38, 0x00);
+        cheats.toggle();
+        cheats.apply_ram_writes(&mut memory);
+        assert_eq!(memory.read(0xCD38), 0x00);
+    }
+
+    #[test]
+    fn bad_codes_report_their_line() {
+        let error = Cheats::parse("010138CD\nnonsense").err().unwrap();
+        assert_eq!(error.line, 2);
+        assert_eq!(error.code, "nonsense");
+    }
+}
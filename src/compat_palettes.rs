@@ -0,0 +1,274 @@
+use crate::cartridge::CartridgeHeader;
+
+// Colors the cgb boot rom picks from, four to a palette
+const COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000, //
+    0x639F, 0x4279, 0x15B0, 0x04CB, //
+    0x7FFF, 0x6E31, 0x454A, 0x0000, //
+    0x7FFF, 0x1BEF, 0x0200, 0x0000, //
+    0x7FFF, 0x421F, 0x1CF2, 0x0000, //
+    0x7FFF, 0x5294, 0x294A, 0x0000, //
+    0x7FFF, 0x03FF, 0x012F, 0x0000, //
+    0x7FFF, 0x03EF, 0x01D6, 0x0000, //
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000, //
+    0x7E74, 0x03FF, 0x0180, 0x0000, //
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B, //
+    0x7ED6, 0x4BFF, 0x2175, 0x0000, //
+    0x53FF, 0x4A5F, 0x7E52, 0x0000, //
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0, //
+    0x03ED, 0x7FFF, 0x255F, 0x0000, //
+    0x036A, 0x021F, 0x03FF, 0x7FFF, //
+    0x7FFF, 0x01DF, 0x0112, 0x0000, //
+    0x231F, 0x035F, 0x00F2, 0x0009, //
+    0x7FFF, 0x03EA, 0x011F, 0x0000, //
+    0x299F, 0x001A, 0x000C, 0x0000, //
+    0x7FFF, 0x027F, 0x001F, 0x0000, //
+    0x7FFF, 0x03E0, 0x0206, 0x0120, //
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00, //
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F, //
+    0x7FFF, 0x03FF, 0x001F, 0x0000, //
+    0x03FF, 0x001F, 0x000C, 0x0000, //
+    0x7FFF, 0x033F, 0x0193, 0x0000, //
+    0x0000, 0x4200, 0x037F, 0x7FFF, //
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000, //
+    0x7FFF, 0x1BEF, 0x6180, 0x0000, //
+];
+
+// Offsets into the colors for the obj0, obj1 and background palettes. A few combinations
+// start partway through a palette the same way the boot rom's table does.
+const COMBINATIONS: [[usize; 3]; 51] = [
+    [16, 16, 116],
+    [72, 72, 72],
+    [80, 80, 80],
+    [96, 96, 96],
+    [36, 36, 36],
+    [0, 0, 0],
+    [108, 108, 108],
+    [20, 20, 20],
+    [48, 48, 48],
+    [104, 104, 104],
+    [64, 32, 32],
+    [16, 112, 112],
+    [16, 8, 8],
+    [12, 16, 16],
+    [16, 116, 116],
+    [112, 16, 112],
+    [8, 68, 8],
+    [64, 64, 32],
+    [16, 16, 28],
+    [16, 16, 72],
+    [16, 16, 80],
+    [76, 76, 36],
+    [15, 15, 44],
+    [68, 68, 8],
+    [16, 16, 8],
+    [16, 16, 12],
+    [112, 112, 0],
+    [12, 12, 0],
+    [0, 0, 4],
+    [72, 88, 72],
+    [80, 88, 80],
+    [96, 88, 96],
+    [64, 88, 32],
+    [68, 16, 52],
+    [111, 0, 56],
+    [111, 16, 60],
+    [76, 88, 36],
+    [64, 112, 40],
+    [16, 92, 112],
+    [68, 88, 8],
+    [16, 0, 8],
+    [16, 112, 12],
+    [112, 12, 0],
+    [12, 112, 16],
+    [84, 112, 16],
+    [12, 112, 0],
+    [100, 12, 112],
+    [0, 112, 32],
+    [16, 12, 112],
+    [112, 12, 24],
+    [16, 112, 116],
+];
+
+// Sums of the title bytes for the games the boot rom knows about
+const TITLE_CHECKSUMS: [u8; 79] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B, 0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+];
+// Checksums from here on are shared by several games, which are told apart by the fourth
+// letter of their title
+const FIRST_SHARED_CHECKSUM: usize = 65;
+const SHARED_CHECKSUMS: usize = TITLE_CHECKSUMS.len() - FIRST_SHARED_CHECKSUM;
+// One row per shared checksum, rows are SHARED_CHECKSUMS letters apart
+const FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// Combination for each checksum followed by one for each fourth letter
+const CHECKSUM_COMBINATIONS: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20, 5, 33, 13, 14, 5, 29,
+    5, 18, 9, 3, 2, 26, 25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34, 5, 42, 6,
+    5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0, 39, 36, 32, 25, 6, 22, 12, 36, 11, 39, 18, 39,
+    24, 31, 50, 17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+// What games outside the table and games from other publishers get
+const DEFAULT_COMBINATION: usize = 0;
+
+const NINTENDO_LICENSEE: u8 = 0x01;
+const USES_NEW_LICENSEE: u8 = 0x33;
+
+pub struct CompatPalettes {
+    pub background: [u16; 4],
+    pub object0: [u16; 4],
+    pub object1: [u16; 4],
+}
+
+impl CompatPalettes {
+    pub fn from_combination(combination: usize) -> Self {
+        let [object0, object1, background] = COMBINATIONS[combination];
+        CompatPalettes {
+            background: get_palette(background),
+            object0: get_palette(object0),
+            object1: get_palette(object1),
+        }
+    }
+}
+
+fn get_palette(offset: usize) -> [u16; 4] {
+    COLORS[offset..offset + 4].try_into().unwrap()
+}
+
+// Looks the cartridge up the same way the cgb boot rom does, only nintendo's own games are in the table
+pub fn get_combination_for_cartridge(header: &CartridgeHeader) -> usize {
+    let is_nintendo = match header.old_licensee {
+        NINTENDO_LICENSEE => true,
+        USES_NEW_LICENSEE => header.new_licensee == *b"01",
+        _ => false,
+    };
+    if !is_nintendo {
+        return DEFAULT_COMBINATION;
+    }
+
+    // The boot rom sums all of 0x0134-0x0143 exactly as stored, padding included
+    let checksum = header
+        .title_bytes
+        .iter()
+        .fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    let fourth_letter = header.title_bytes[3];
+    for (index, _) in TITLE_CHECKSUMS
+        .iter()
+        .enumerate()
+        .filter(|(_, &entry)| entry == checksum)
+    {
+        if index < FIRST_SHARED_CHECKSUM {
+            return CHECKSUM_COMBINATIONS[index] as usize;
+        }
+        let row_start = index - FIRST_SHARED_CHECKSUM;
+        for letter_index in (row_start..FOURTH_LETTERS.len()).step_by(SHARED_CHECKSUMS) {
+            if FOURTH_LETTERS[letter_index] == fourth_letter {
+                return CHECKSUM_COMBINATIONS[FIRST_SHARED_CHECKSUM + letter_index] as usize;
+            }
+        }
+    }
+    DEFAULT_COMBINATION
+}
+
+// Holding a direction with A, B or neither at startup overrides the table. The inputs are the
+// active low joypad nibbles with right/A in bit 0, left/B in bit 1, up in bit 2 and down in bit 3.
+pub fn get_combination_for_buttons(direction_input: u8, action_input: u8) -> Option<usize> {
+    let is_held = |input: u8, bit: u8| input & (1 << bit) == 0;
+    // Columns are no button, A and B
+    let combinations = if is_held(direction_input, 0) {
+        [1, 0, 6]
+    } else if is_held(direction_input, 1) {
+        [48, 40, 7]
+    } else if is_held(direction_input, 2) {
+        [5, 43, 28]
+    } else if is_held(direction_input, 3) {
+        [8, 3, 49]
+    } else {
+        return None;
+    };
+
+    let button = if is_held(action_input, 0) {
+        1
+    } else if is_held(action_input, 1) {
+        2
+    } else {
+        0
+    };
+    Some(combinations[button])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_header(title: &str, licensee: u8) -> CartridgeHeader {
+        let mut title_bytes = [0; 16];
+        title_bytes[..title.len()].copy_from_slice(title.as_bytes());
+        CartridgeHeader {
+            title: title.to_string(),
+            title_bytes,
+            manufacturer: None,
+            cgb_flag: 0x00,
+            sgb_flag: 0x00,
+            old_licensee: licensee,
+            new_licensee: [0, 0],
+            cartridge_type: 0x01,
+            rom_banks: 2,
+            ram_size: 0,
+            version: 0,
+            global_checksum: 0,
+        }
+    }
+
+    #[test]
+    fn known_title_gets_its_palettes() {
+        let header = get_test_header("TETRIS", NINTENDO_LICENSEE);
+        assert_eq!(get_combination_for_cartridge(&header), 3);
+        let palettes = CompatPalettes::from_combination(3);
+        assert_eq!(palettes.background, [0x7FFF, 0x03FF, 0x001F, 0x0000]);
+    }
+
+    #[test]
+    fn shared_checksums_are_told_apart_by_fourth_letter() {
+        let header = get_test_header("SUPER MARIOLAND", NINTENDO_LICENSEE);
+        assert_eq!(get_combination_for_cartridge(&header), 32);
+        let header = get_test_header("METROID2", NINTENDO_LICENSEE);
+        assert_eq!(get_combination_for_cartridge(&header), 46);
+    }
+
+    #[test]
+    fn bytes_after_the_end_of_the_title_are_summed() {
+        let mut header = get_test_header("TETRI", NINTENDO_LICENSEE);
+        header.title_bytes[6] = b'S';
+        assert_eq!(get_combination_for_cartridge(&header), 3);
+    }
+
+    #[test]
+    fn other_publishers_get_the_default() {
+        let header = get_test_header("TETRIS", 0x08);
+        assert_eq!(get_combination_for_cartridge(&header), DEFAULT_COMBINATION);
+
+        let mut header = get_test_header("TETRIS", USES_NEW_LICENSEE);
+        header.new_licensee = *b"01";
+        assert_eq!(get_combination_for_cartridge(&header), 3);
+    }
+
+    #[test]
+    fn buttons_select_palettes() {
+        assert_eq!(get_combination_for_buttons(0x0F, 0x0F), None);
+        // Right + A is the default
+        assert_eq!(
+            get_combination_for_buttons(0b1110, 0b1110),
+            Some(DEFAULT_COMBINATION)
+        );
+        // Left + B is greyscale
+        let combination = get_combination_for_buttons(0b1101, 0b1101).unwrap();
+        let palettes = CompatPalettes::from_combination(combination);
+        assert_eq!(palettes.object1, [0x7FFF, 0x5294, 0x294A, 0x0000]);
+    }
+}
@@ -6,9 +6,10 @@ use arrayvec::{self, ArrayVec};
 
 use crate::cpu::Operand::{Immediate, Indirect, Register};
 use crate::cpu::OperandU16::{ImmediateU16, RegisterPair};
-use crate::mem_manager::MemManager;
+use crate::mem_manager::{MemManager, Model};
 use crate::memory::Memory;
-use crate::registers::STAT_ADDRESS;
+use crate::registers::{DIV_ADDRESS, KEY1_ADDRESS, STAT_ADDRESS};
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 
 #[derive(Clone, Copy)]
 enum Operand {
@@ -64,18 +65,50 @@ pub struct CPU {
 }
 
 impl CPU {
+    // Starts with the registers the cgb boot rom leaves behind
     pub fn new(mem: Rc<RefCell<MemManager>>) -> Self {
+        let model = mem.borrow().get_model();
+        let mut cpu = CPU::new_at_reset(mem);
+        // Games tell the consoles apart by the value the boot rom leaves in A
+        match model {
+            Model::DMG => {
+                cpu.register_a = 0x01;
+                cpu.register_f = 0xB0;
+                cpu.register_c = 0x13;
+                cpu.register_e = 0xD8;
+                cpu.register_h = 0x01;
+                cpu.register_l = 0x4D;
+            }
+            Model::CGB => {
+                cpu.register_a = 0x11;
+                cpu.register_f = 0x80;
+                cpu.register_d = 0xFF;
+                cpu.register_e = 0x56;
+                cpu.register_l = 0x0D;
+            }
+        }
+        cpu.stack_pointer = 0xFFFE;
+        cpu.program_counter = 0x0100;
+
+        const IF_ADDRESS: u16 = 0xFF0F;
+        cpu.memory.borrow_mut().write(IF_ADDRESS, 0xE1);
+
+        cpu
+    }
+
+    // Starts from power on so a boot rom mapped at 0x0000 runs first
+    pub fn new_at_reset(mem: Rc<RefCell<MemManager>>) -> Self {
         let mut cpu = CPU {
-            register_a: 0x11,
-            register_f: 0x80,
+            register_a: 0x00,
+            register_f: 0x00,
             register_b: 0x00,
             register_c: 0x00,
-            register_d: 0xFF,
-            register_e: 0x56,
+            register_d: 0x00,
+            register_e: 0x00,
             register_h: 0x00,
-            register_l: 0x0D,
-            stack_pointer: 0xFFFE,
-            program_counter: 0x0100,
+            register_l: 0x00,
+            stack_pointer: 0x0000,
+            program_counter: 0x0000,
             memory: mem,
             instructions: ArrayVec::new(),
             halted: false,
@@ -90,9 +123,6 @@ impl CPU {
                 .push(Instruction::new(1, init_inst.clone()));
         }
 
-        const IF_ADDRESS: u16 = 0xFF0F;
-        cpu.memory.borrow_mut().write(IF_ADDRESS, 0xE1);
-
         map_instructions(&mut cpu);
 
         cpu
@@ -176,6 +206,50 @@ impl CPU {
         0
     }
 
+    pub fn get_program_counter(&self) -> u16 {
+        self.program_counter
+    }
+
+    pub fn get_stack_pointer(&self) -> u16 {
+        self.stack_pointer
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn print_registers(&self) {
+        println!(
+            "AF: {:02X}{:02X} BC: {:02X}{:02X} DE: {:02X}{:02X} HL: {:02X}{:02X} SP: {:04X} PC: {:04X}",
+            self.register_a,
+            self.register_f,
+            self.register_b,
+            self.register_c,
+            self.register_d,
+            self.register_e,
+            self.register_h,
+            self.register_l,
+            self.stack_pointer,
+            self.program_counter,
+        );
+        let flag = |bit: u8, name: char| {
+            if self.register_f & (1 << bit) != 0 {
+                name
+            } else {
+                '-'
+            }
+        };
+        println!(
+            "Flags: {}{}{}{} IME: {} Halted: {}",
+            flag(7, 'Z'),
+            flag(6, 'N'),
+            flag(5, 'H'),
+            flag(4, 'C'),
+            self.ime,
+            self.halted,
+        );
+    }
+
     fn new_standalone() -> Self {
         CPU::new(Rc::new(RefCell::new(MemManager::new())))
     }
@@ -599,11 +673,6 @@ impl Memory for CPU {
         let oam_locked = mode > 1; // Timing issue with these. Fix later
         let vram_locked = false; //mode > 2;
 
-        //For debugging: remove later
-        if address == 0xFF02 && data == 0x81 {
-            print!("{}", self.read(0xFF01) as char);
-        }
-        //
         match address {
             0x8000..=0x9FFF if vram_locked => (),
             0xFF68..=0xFF6B if vram_locked => (),
@@ -613,6 +682,67 @@ impl Memory for CPU {
     }
 }
 
+// The instruction table is rebuilt by CPU::new so only the registers and interrupt state are saved
+impl SaveState for CPU {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&[
+            self.register_a,
+            self.register_f,
+            self.register_b,
+            self.register_c,
+            self.register_d,
+            self.register_e,
+            self.register_h,
+            self.register_l,
+        ]);
+        state.write_u16(self.stack_pointer);
+        state.write_u16(self.program_counter);
+        state.write_bool(self.halted);
+        state.write_bool(self.ime);
+        state.write_u8(self.ei_queue.len() as u8);
+        for queued in &self.ei_queue {
+            let encoded = match queued {
+                None => 0,
+                Some(false) => 1,
+                Some(true) => 2,
+            };
+            state.write_u8(encoded);
+        }
+        state.write_option_u8(self.changed_cycles);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut registers = [0; 8];
+        state.read_bytes(&mut registers)?;
+        [
+            self.register_a,
+            self.register_f,
+            self.register_b,
+            self.register_c,
+            self.register_d,
+            self.register_e,
+            self.register_h,
+            self.register_l,
+        ] = registers;
+        self.stack_pointer = state.read_u16()?;
+        self.program_counter = state.read_u16()?;
+        self.halted = state.read_bool()?;
+        self.ime = state.read_bool()?;
+        self.ei_queue.clear();
+        for _ in 0..state.read_u8()? {
+            let queued = match state.read_u8()? {
+                0 => None,
+                1 => Some(false),
+                2 => Some(true),
+                _ => return Err(StateError::Invalid("ei queue entry")),
+            };
+            self.ei_queue.push_back(queued);
+        }
+        self.changed_cycles = state.read_option_u8()?;
+        Ok(())
+    }
+}
+
 fn map_instructions(cpu: &mut CPU) {
     // 8-bit LD instructions
     // LD r, r'  (1 M-cycles)
@@ -1668,8 +1798,23 @@ fn map_instructions(cpu: &mut CPU) {
         }),
     );
 
-    // STOP  (N M-cycles)
-    // todo!("stop");
+    // STOP  (1 M-cycles)
+    // Only performs the cgb speed switch, low power mode is not implemented
+    cpu.instructions[0x10] = Instruction::new(
+        1,
+        Rc::new(move |cpu: &mut CPU| {
+            // The byte after STOP is ignored
+            cpu.program_counter += 1;
+            let key1 = cpu.read(KEY1_ADDRESS);
+            let is_cgb = cpu.memory.borrow().get_model() == Model::CGB;
+            if is_cgb && key1 & 0b00000001 != 0 {
+                // Flip the current speed and clear the prepare bit
+                let new_speed = (key1 ^ 0b10000000) & 0b10000000;
+                cpu.memory.borrow_mut().force_write(KEY1_ADDRESS, new_speed);
+                cpu.write(DIV_ADDRESS, 0);
+            }
+        }),
+    );
 
     // DI (1 M-cycles)
     cpu.instructions[0xF3] = Instruction::new(
@@ -3153,6 +3298,36 @@ mod tests {
         assert_eq!(cpu.ime, false);
     }
 
+    #[test]
+    fn stop_switches_speed_when_prepared() {
+        let mut cpu = CPU::new_standalone();
+        cpu.write(KEY1_ADDRESS, 0x01);
+        cpu.run_test(vec![0x10, 0x00]);
+        assert_eq!(cpu.read(KEY1_ADDRESS), 0xFE);
+        assert_eq!(cpu.program_counter, 0x0102);
+        cpu.write(KEY1_ADDRESS, 0x01);
+        cpu.run_test(vec![0x10, 0x00]);
+        assert_eq!(cpu.read(KEY1_ADDRESS), 0x7E);
+    }
+
+    #[test]
+    fn dmg_starts_with_dmg_registers_and_never_switches_speed() {
+        let mem = Rc::new(RefCell::new(MemManager::new()));
+        mem.borrow_mut().set_model(Model::DMG);
+        let mut cpu = CPU::new(mem.clone());
+        assert_eq!(cpu.register_a, 0x01);
+        assert_eq!(CPU::combine_bytes(cpu.register_h, cpu.register_l), 0x014D);
+        cpu.run_test(vec![0x10, 0x00]);
+        assert!(!mem.borrow().is_double_speed());
+    }
+
+    #[test]
+    fn stop_keeps_speed_when_not_prepared() {
+        let mut cpu = CPU::new_standalone();
+        cpu.run_test(vec![0x10, 0x00]);
+        assert_eq!(cpu.read(KEY1_ADDRESS), 0x7E);
+    }
+
     #[test]
     fn halt_ends_after_interrupt() {
         // TODO: Test halt more when interrupts are full implemented
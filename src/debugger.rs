@@ -0,0 +1,429 @@
+use std::io::{self, BufRead, Write};
+
+use crate::cpu::CPU;
+use crate::disasm::disassemble;
+use crate::mem_manager::MemManager;
+use crate::memory::Memory;
+use crate::registers::IO_REGISTERS;
+
+// Where the cpu is about to execute from
+pub struct Location {
+    pub bank: usize,
+    pub pc: u16,
+    pub sp: u16,
+    pub opcode: u8,
+    pub halted: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Breakpoint {
+    // Breakpoints without a bank stop in whichever bank is mapped
+    bank: Option<usize>,
+    address: u16,
+}
+
+impl Breakpoint {
+    fn matches(&self, location: &Location) -> bool {
+        self.address == location.pc && self.bank.is_none_or(|bank| bank == location.bank)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Mode {
+    Paused,
+    Running,
+    // Stops before the next instruction
+    Step,
+    // Stops once the instruction after a call is reached from the same stack frame
+    StepOver { pc: u16, sp: u16 },
+    // Stops after a return that leaves the current stack frame. Holds where the previous
+    // instruction would have continued if it was a return, a conditional one that isn't taken
+    // lands there without leaving.
+    StepOut { sp: u16, fall_through: Option<u16> },
+    Frames(u32),
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Break(Option<Breakpoint>),
+    Delete(Breakpoint),
+    Step,
+    Next,
+    Out,
+    Continue,
+    Frame(u32),
+    Registers,
+    Memory(u16, u16),
+    List(Option<u16>, u16),
+    IO,
+    Palettes,
+    Quit,
+    Help,
+}
+
+const HELP: &str = "\
+break [bank:]address   stop at an address, lists breakpoints without one
+delete [bank:]address  remove a breakpoint
+step                   run one instruction
+next                   run one instruction, stepping over calls
+out                    run until the current function returns
+continue               run until a breakpoint
+frame [count]          run until the end of the frame, or several
+regs                   show the cpu registers and flags
+mem start [end]        dump memory
+list [address] [count] disassemble from an address, or from pc
+io                     show the io registers
+palettes               show the cgb palettes
+quit                   close the emulator";
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    mode: Mode,
+}
+
+impl Debugger {
+    // Starts paused so breakpoints can be set before the game runs
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: Vec::new(),
+            mode: Mode::Paused,
+        }
+    }
+
+    pub fn request_break(&mut self) {
+        self.mode = Mode::Paused;
+    }
+
+    pub fn on_frame(&mut self) {
+        if let Mode::Frames(frames) = self.mode {
+            self.mode = if frames > 1 {
+                Mode::Frames(frames - 1)
+            } else {
+                Mode::Paused
+            };
+        }
+    }
+
+    // Checked before every instruction
+    pub fn should_break(&mut self, location: &Location) -> bool {
+        let should_break = match self.mode {
+            Mode::Paused | Mode::Step => true,
+            Mode::StepOver { pc, sp } => location.pc == pc && location.sp >= sp,
+            Mode::StepOut { sp, fall_through } => {
+                fall_through.is_some_and(|pc| pc != location.pc) && location.sp > sp
+            }
+            Mode::Running | Mode::Frames(_) => false,
+        };
+        if let Mode::StepOut { fall_through, .. } = &mut self.mode {
+            *fall_through = get_return_fall_through(location);
+        }
+        // A halted cpu sits on the same address, only stop there once
+        let at_breakpoint = !location.halted
+            && self
+                .breakpoints
+                .iter()
+                .any(|breakpoint| breakpoint.matches(location));
+        if should_break || at_breakpoint {
+            self.mode = Mode::Paused;
+        }
+        self.mode == Mode::Paused
+    }
+
+    // Reads commands until one resumes the game, returns false if the emulator should close
+    pub fn prompt(&mut self, location: &Location, cpu: &CPU, memory: &MemManager) -> bool {
+        println!(
+            "Stopped at {:02X}:{:04X}: {}",
+            location.bank,
+            location.pc,
+            disassemble(memory, location.pc).text
+        );
+        let stdin = io::stdin();
+        loop {
+            print!("> ");
+            io::stdout().flush().ok();
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => return false,
+                Ok(_) => (),
+            }
+            let command = match parse_command(&line) {
+                Ok(Some(command)) => command,
+                Ok(None) => continue,
+                Err(e) => {
+                    println!("{e}");
+                    continue;
+                }
+            };
+            match command {
+                Command::Break(Some(breakpoint)) => {
+                    if !self.breakpoints.contains(&breakpoint) {
+                        self.breakpoints.push(breakpoint);
+                    }
+                }
+                Command::Break(None) => {
+                    for breakpoint in &self.breakpoints {
+                        println!("{}", format_breakpoint(breakpoint));
+                    }
+                }
+                Command::Delete(breakpoint) => {
+                    self.breakpoints.retain(|other| *other != breakpoint);
+                }
+                Command::Step => {
+                    self.mode = Mode::Step;
+                    return true;
+                }
+                Command::Next => {
+                    self.mode = match get_call_length(location.opcode) {
+                        Some(length) => Mode::StepOver {
+                            pc: location.pc.wrapping_add(length),
+                            sp: location.sp,
+                        },
+                        None => Mode::Step,
+                    };
+                    return true;
+                }
+                Command::Out => {
+                    // Stopped on the return itself, the next instruction is already out
+                    self.mode = Mode::StepOut {
+                        sp: location.sp,
+                        fall_through: get_return_fall_through(location),
+                    };
+                    return true;
+                }
+                Command::Continue => {
+                    self.mode = Mode::Running;
+                    return true;
+                }
+                Command::Frame(frames) => {
+                    self.mode = Mode::Frames(frames);
+                    return true;
+                }
+                Command::Registers => cpu.print_registers(),
+                Command::Memory(start, end) => memory.print_memory(start, end),
+                Command::List(start, count) => {
+                    print_disassembly(memory, start.unwrap_or(location.pc), count)
+                }
+                Command::IO => {
+                    for (name, address) in IO_REGISTERS {
+                        let value = memory.read(address);
+                        println!("{name:<5} {address:04X}: {value:02X} {value:08b}");
+                    }
+                }
+                Command::Palettes => memory.print_palettes(),
+                Command::Quit => return false,
+                Command::Help => println!("{HELP}"),
+            }
+        }
+    }
+}
+
+fn print_disassembly(memory: &MemManager, start: u16, count: u16) {
+    let mut address = start;
+    for _ in 0..count {
+        let instruction = disassemble(memory, address);
+        let bytes: Vec<String> = (0..instruction.length as u16)
+            .map(|i| format!("{:02X}", memory.read(address.wrapping_add(i))))
+            .collect();
+        let cycles = match instruction.branch_cycles {
+            Some(branch_cycles) => format!("{}/{branch_cycles}", instruction.cycles),
+            None => instruction.cycles.to_string(),
+        };
+        println!(
+            "{address:04X}: {:<8} {:<20} ; {cycles}",
+            bytes.join(" "),
+            instruction.text
+        );
+        address = address.wrapping_add(instruction.length as u16);
+    }
+}
+
+fn is_return(opcode: u8) -> bool {
+    matches!(opcode, 0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9)
+}
+
+// Where execution goes next if a return at the location isn't taken
+fn get_return_fall_through(location: &Location) -> Option<u16> {
+    is_return(location.opcode).then(|| location.pc.wrapping_add(1))
+}
+
+// Calls and restarts are the instructions next steps over
+fn get_call_length(opcode: u8) -> Option<u16> {
+    match opcode {
+        0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC => Some(3),
+        0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => Some(1),
+        _ => None,
+    }
+}
+
+fn format_breakpoint(breakpoint: &Breakpoint) -> String {
+    match breakpoint.bank {
+        Some(bank) => format!("{bank:02X}:{:04X}", breakpoint.address),
+        None => format!("{:04X}", breakpoint.address),
+    }
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    let digits = text.trim_start_matches("0x").trim_start_matches('$');
+    u16::from_str_radix(digits, 16).map_err(|_| format!("{text} isn't a hex address"))
+}
+
+fn parse_breakpoint(text: &str) -> Result<Breakpoint, String> {
+    match text.split_once(':') {
+        Some((bank, address)) => Ok(Breakpoint {
+            bank: Some(
+                usize::from_str_radix(bank, 16).map_err(|_| format!("{bank} isn't a hex bank"))?,
+            ),
+            address: parse_address(address)?,
+        }),
+        None => Ok(Breakpoint {
+            bank: None,
+            address: parse_address(text)?,
+        }),
+    }
+}
+
+fn parse_command(line: &str) -> Result<Option<Command>, String> {
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else {
+        return Ok(None);
+    };
+    let arguments: Vec<&str> = words.collect();
+    let command = match (name, arguments.as_slice()) {
+        ("b" | "break", []) => Command::Break(None),
+        ("b" | "break", [breakpoint]) => Command::Break(Some(parse_breakpoint(breakpoint)?)),
+        ("d" | "delete", [breakpoint]) => Command::Delete(parse_breakpoint(breakpoint)?),
+        ("s" | "step", []) => Command::Step,
+        ("n" | "next", []) => Command::Next,
+        ("o" | "out", []) => Command::Out,
+        ("c" | "continue", []) => Command::Continue,
+        ("f" | "frame", []) => Command::Frame(1),
+        ("f" | "frame", [frames]) => Command::Frame(
+            frames
+                .parse()
+                .map_err(|_| format!("{frames} isn't a frame count"))?,
+        ),
+        ("r" | "regs", []) => Command::Registers,
+        ("m" | "mem", [start]) => {
+            let start = parse_address(start)?;
+            Command::Memory(start, start.saturating_add(0x0F))
+        }
+        ("m" | "mem", [start, end]) => Command::Memory(parse_address(start)?, parse_address(end)?),
+        ("l" | "list", []) => Command::List(None, 10),
+        ("l" | "list", [start]) => Command::List(Some(parse_address(start)?), 10),
+        ("l" | "list", [start, count]) => Command::List(
+            Some(parse_address(start)?),
+            count
+                .parse()
+                .map_err(|_| format!("{count} isn't an instruction count"))?,
+        ),
+        ("io", []) => Command::IO,
+        ("p" | "palettes", []) => Command::Palettes,
+        ("q" | "quit", []) => Command::Quit,
+        ("h" | "help", []) => Command::Help,
+        _ => return Err(format!("Unknown command {}, try help", line.trim())),
+    };
+    Ok(Some(command))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(pc: u16, sp: u16, opcode: u8) -> Location {
+        Location {
+            bank: 1,
+            pc,
+            sp,
+            opcode,
+            halted: false,
+        }
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(
+            parse_command("break 01:4A00\n"),
+            Ok(Some(Command::Break(Some(Breakpoint {
+                bank: Some(1),
+                address: 0x4A00,
+            }))))
+        );
+        assert_eq!(
+            parse_command("mem c000 c0ff"),
+            Ok(Some(Command::Memory(0xC000, 0xC0FF)))
+        );
+        assert_eq!(parse_command("f 3"), Ok(Some(Command::Frame(3))));
+        assert_eq!(parse_command("   "), Ok(None));
+        assert!(parse_command("break zz").is_err());
+        assert!(parse_command("step 2").is_err());
+    }
+
+    #[test]
+    fn breakpoints_check_the_bank() {
+        let mut debugger = Debugger::new();
+        debugger.mode = Mode::Running;
+        debugger.breakpoints.push(Breakpoint {
+            bank: Some(2),
+            address: 0x4000,
+        });
+        debugger.breakpoints.push(Breakpoint {
+            bank: None,
+            address: 0x0150,
+        });
+        assert!(!debugger.should_break(&at(0x4000, 0xFFFE, 0x00)));
+        assert!(debugger.should_break(&Location {
+            bank: 2,
+            ..at(0x4000, 0xFFFE, 0x00)
+        }));
+        debugger.mode = Mode::Running;
+        assert!(debugger.should_break(&at(0x0150, 0xFFFE, 0x00)));
+    }
+
+    #[test]
+    fn step_over_waits_for_the_call_to_return() {
+        let mut debugger = Debugger::new();
+        debugger.mode = Mode::StepOver {
+            pc: 0x0203,
+            sp: 0xFFFE,
+        };
+        // A recursive call passing the same address deeper in the stack doesn't count
+        assert!(!debugger.should_break(&at(0x0203, 0xFFF8, 0x00)));
+        assert!(debugger.should_break(&at(0x0203, 0xFFFE, 0x00)));
+    }
+
+    #[test]
+    fn step_out_stops_after_returning() {
+        let mut debugger = Debugger::new();
+        debugger.mode = Mode::StepOut {
+            sp: 0xFFEC,
+            fall_through: None,
+        };
+        // Popping registers leaves the stack above where it started without returning
+        assert!(!debugger.should_break(&at(0x1000, 0xFFEC, 0xC1)));
+        // A ret nz that isn't taken stays in the function
+        assert!(!debugger.should_break(&at(0x1001, 0xFFEE, 0xC0)));
+        assert!(!debugger.should_break(&at(0x1002, 0xFFEE, 0xC9)));
+        assert!(debugger.should_break(&at(0x0203, 0xFFF0, 0x00)));
+    }
+
+    #[test]
+    fn step_out_from_a_return_stops_after_it() {
+        let mut debugger = Debugger::new();
+        let location = at(0x1001, 0xFFEE, 0xC9);
+        debugger.mode = Mode::StepOut {
+            sp: location.sp,
+            fall_through: get_return_fall_through(&location),
+        };
+        assert!(debugger.should_break(&at(0x0203, 0xFFF0, 0x00)));
+    }
+
+    #[test]
+    fn frames_count_down() {
+        let mut debugger = Debugger::new();
+        debugger.mode = Mode::Frames(2);
+        debugger.on_frame();
+        assert!(!debugger.should_break(&at(0x0100, 0xFFFE, 0x00)));
+        debugger.on_frame();
+        assert!(debugger.should_break(&at(0x0100, 0xFFFE, 0x00)));
+    }
+}
@@ -0,0 +1,352 @@
+use crate::memory::Memory;
+
+pub mod rom;
+
+// Operand names indexed by the fields of an opcode. Opcodes split into x (bits 6-7), y (bits 3-5)
+// and z (bits 0-2), with y split again into p (bits 4-5) and q (bit 3).
+const REGISTERS: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const REGISTER_PAIRS: [&str; 4] = ["bc", "de", "hl", "sp"];
+const STACK_REGISTER_PAIRS: [&str; 4] = ["bc", "de", "hl", "af"];
+const INDIRECT_REGISTER_PAIRS: [&str; 4] = ["[bc]", "[de]", "[hl+]", "[hl-]"];
+const CONDITIONS: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU_OPERATIONS: [&str; 8] = ["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"];
+const ACCUMULATOR_OPERATIONS: [&str; 8] =
+    ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+const ROTATE_OPERATIONS: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+const HL_INDIRECT: u8 = 6;
+// Opcodes the cpu doesn't have, running one locks it up
+const INVALID_OPCODES: [u8; 11] = [
+    0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+];
+
+// One instruction in RGBDS syntax. Cycles are machine cycles, conditional instructions take
+// the branch cycles instead when the condition holds.
+#[derive(Debug, PartialEq)]
+pub struct DecodedInstruction {
+    pub text: String,
+    pub length: u8,
+    pub cycles: u8,
+    pub branch_cycles: Option<u8>,
+}
+
+impl DecodedInstruction {
+    fn new(text: String, length: u8, cycles: u8) -> Self {
+        DecodedInstruction {
+            text,
+            length,
+            cycles,
+            branch_cycles: None,
+        }
+    }
+
+    fn branch(text: String, length: u8, cycles: u8, branch_cycles: u8) -> Self {
+        DecodedInstruction {
+            branch_cycles: Some(branch_cycles),
+            ..DecodedInstruction::new(text, length, cycles)
+        }
+    }
+}
+
+// How an instruction hands control on, for following code through a rom
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flow {
+    Next,
+    // Calls and conditional jumps continue with the next instruction as well as the target
+    Branch(u16),
+    Jump(u16),
+    // Returns, jumps through hl and opcodes that lock up the cpu
+    End,
+}
+
+pub fn disassemble(memory: &impl Memory, address: u16) -> DecodedInstruction {
+    let bytes = [
+        memory.read(address),
+        memory.read(address.wrapping_add(1)),
+        memory.read(address.wrapping_add(2)),
+    ];
+    decode(bytes, address)
+}
+
+// Decodes the instruction starting with the first byte, the address is needed to resolve
+// relative jumps. Opcodes the cpu doesn't have come out as data.
+pub fn decode(bytes: [u8; 3], address: u16) -> DecodedInstruction {
+    let opcode = bytes[0];
+    let n8 = bytes[1];
+    let n16 = u16::from_le_bytes([bytes[1], bytes[2]]);
+    let e8 = bytes[1] as i8;
+    let relative_target = address.wrapping_add(2).wrapping_add(e8 as u16);
+
+    let x = opcode >> 6;
+    let y = (opcode >> 3) & 0b111;
+    let z = opcode & 0b111;
+    let p = (y >> 1) as usize;
+    let q = y & 1;
+    let r = |code: u8| REGISTERS[code as usize];
+    // Anything that goes through [hl] takes an extra memory access
+    let hl_cycles = |code: u8| if code == HL_INDIRECT { 1 } else { 0 };
+
+    match (x, z) {
+        (0, 0) => match y {
+            0 => DecodedInstruction::new("nop".to_string(), 1, 1),
+            1 => DecodedInstruction::new(format!("ld [${n16:04X}], sp"), 3, 5),
+            2 => DecodedInstruction::new("stop".to_string(), 2, 1),
+            3 => DecodedInstruction::new(format!("jr ${relative_target:04X}"), 2, 3),
+            _ => DecodedInstruction::branch(
+                format!(
+                    "jr {}, ${relative_target:04X}",
+                    CONDITIONS[(y - 4) as usize]
+                ),
+                2,
+                2,
+                3,
+            ),
+        },
+        (0, 1) if q == 0 => {
+            DecodedInstruction::new(format!("ld {}, ${n16:04X}", REGISTER_PAIRS[p]), 3, 3)
+        }
+        (0, 1) => DecodedInstruction::new(format!("add hl, {}", REGISTER_PAIRS[p]), 1, 2),
+        (0, 2) if q == 0 => {
+            DecodedInstruction::new(format!("ld {}, a", INDIRECT_REGISTER_PAIRS[p]), 1, 2)
+        }
+        (0, 2) => DecodedInstruction::new(format!("ld a, {}", INDIRECT_REGISTER_PAIRS[p]), 1, 2),
+        (0, 3) if q == 0 => DecodedInstruction::new(format!("inc {}", REGISTER_PAIRS[p]), 1, 2),
+        (0, 3) => DecodedInstruction::new(format!("dec {}", REGISTER_PAIRS[p]), 1, 2),
+        (0, 4) => DecodedInstruction::new(format!("inc {}", r(y)), 1, 1 + hl_cycles(y) * 2),
+        (0, 5) => DecodedInstruction::new(format!("dec {}", r(y)), 1, 1 + hl_cycles(y) * 2),
+        (0, 6) => DecodedInstruction::new(format!("ld {}, ${n8:02X}", r(y)), 2, 2 + hl_cycles(y)),
+        (0, 7) => DecodedInstruction::new(ACCUMULATOR_OPERATIONS[y as usize].to_string(), 1, 1),
+        (1, _) if y == HL_INDIRECT && z == HL_INDIRECT => {
+            DecodedInstruction::new("halt".to_string(), 1, 1)
+        }
+        (1, _) => DecodedInstruction::new(
+            format!("ld {}, {}", r(y), r(z)),
+            1,
+            1 + hl_cycles(y) + hl_cycles(z),
+        ),
+        (2, _) => DecodedInstruction::new(
+            format!("{} a, {}", ALU_OPERATIONS[y as usize], r(z)),
+            1,
+            1 + hl_cycles(z),
+        ),
+        (3, 0) => match y {
+            0..=3 => DecodedInstruction::branch(format!("ret {}", CONDITIONS[y as usize]), 1, 2, 5),
+            4 => DecodedInstruction::new(format!("ldh [${:04X}], a", 0xFF00 | n8 as u16), 2, 3),
+            5 => DecodedInstruction::new(format!("add sp, {}", format_offset(e8)), 2, 4),
+            6 => DecodedInstruction::new(format!("ldh a, [${:04X}]", 0xFF00 | n8 as u16), 2, 3),
+            _ => {
+                let sign = if e8 < 0 { '-' } else { '+' };
+                let offset = e8.unsigned_abs();
+                DecodedInstruction::new(format!("ld hl, sp {sign} ${offset:02X}"), 2, 3)
+            }
+        },
+        (3, 1) if q == 0 => {
+            DecodedInstruction::new(format!("pop {}", STACK_REGISTER_PAIRS[p]), 1, 3)
+        }
+        (3, 1) => match p {
+            0 => DecodedInstruction::new("ret".to_string(), 1, 4),
+            1 => DecodedInstruction::new("reti".to_string(), 1, 4),
+            2 => DecodedInstruction::new("jp hl".to_string(), 1, 1),
+            _ => DecodedInstruction::new("ld sp, hl".to_string(), 1, 2),
+        },
+        (3, 2) => match y {
+            0..=3 => DecodedInstruction::branch(
+                format!("jp {}, ${n16:04X}", CONDITIONS[y as usize]),
+                3,
+                3,
+                4,
+            ),
+            4 => DecodedInstruction::new("ldh [c], a".to_string(), 1, 2),
+            5 => DecodedInstruction::new(format!("ld [${n16:04X}], a"), 3, 4),
+            6 => DecodedInstruction::new("ldh a, [c]".to_string(), 1, 2),
+            _ => DecodedInstruction::new(format!("ld a, [${n16:04X}]"), 3, 4),
+        },
+        (3, 3) => match y {
+            0 => DecodedInstruction::new(format!("jp ${n16:04X}"), 3, 4),
+            1 => decode_prefixed(bytes[1]),
+            6 => DecodedInstruction::new("di".to_string(), 1, 1),
+            7 => DecodedInstruction::new("ei".to_string(), 1, 1),
+            _ => decode_invalid(opcode),
+        },
+        (3, 4) if y < 4 => DecodedInstruction::branch(
+            format!("call {}, ${n16:04X}", CONDITIONS[y as usize]),
+            3,
+            3,
+            6,
+        ),
+        (3, 5) if q == 0 => {
+            DecodedInstruction::new(format!("push {}", STACK_REGISTER_PAIRS[p]), 1, 4)
+        }
+        (3, 5) if p == 0 => DecodedInstruction::new(format!("call ${n16:04X}"), 3, 6),
+        (3, 6) => {
+            DecodedInstruction::new(format!("{} a, ${n8:02X}", ALU_OPERATIONS[y as usize]), 2, 2)
+        }
+        (3, 7) => DecodedInstruction::new(format!("rst ${:02X}", y * 8), 1, 4),
+        _ => decode_invalid(opcode),
+    }
+}
+
+pub fn get_flow(bytes: [u8; 3], address: u16) -> Flow {
+    let n16 = u16::from_le_bytes([bytes[1], bytes[2]]);
+    let relative_target = address.wrapping_add(2).wrapping_add(bytes[1] as i8 as u16);
+    match bytes[0] {
+        0x18 => Flow::Jump(relative_target),
+        0x20 | 0x28 | 0x30 | 0x38 => Flow::Branch(relative_target),
+        0xC3 => Flow::Jump(n16),
+        0xC2 | 0xCA | 0xD2 | 0xDA | 0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC => Flow::Branch(n16),
+        rst @ (0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF) => {
+            Flow::Branch((rst & 0b00111000) as u16)
+        }
+        0xC9 | 0xD9 | 0xE9 => Flow::End,
+        opcode if is_invalid_opcode(opcode) => Flow::End,
+        _ => Flow::Next,
+    }
+}
+
+pub fn is_invalid_opcode(opcode: u8) -> bool {
+    INVALID_OPCODES.contains(&opcode)
+}
+
+fn decode_prefixed(opcode: u8) -> DecodedInstruction {
+    let x = opcode >> 6;
+    let y = (opcode >> 3) & 0b111;
+    let z = opcode & 0b111;
+    let register = REGISTERS[z as usize];
+    // Bit only reads [hl] while the others write it back as well
+    let (text, hl_cycles) = match x {
+        0 => (format!("{} {register}", ROTATE_OPERATIONS[y as usize]), 2),
+        1 => (format!("bit {y}, {register}"), 1),
+        2 => (format!("res {y}, {register}"), 2),
+        _ => (format!("set {y}, {register}"), 2),
+    };
+    let cycles = if z == HL_INDIRECT { 2 + hl_cycles } else { 2 };
+    DecodedInstruction::new(text, 2, cycles)
+}
+
+fn decode_invalid(opcode: u8) -> DecodedInstruction {
+    DecodedInstruction::new(format!("db ${opcode:02X}"), 1, 1)
+}
+
+fn format_offset(offset: i8) -> String {
+    if offset < 0 {
+        format!("-${:02X}", offset.unsigned_abs())
+    } else {
+        format!("${offset:02X}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::cpu::CPU;
+    use crate::mem_manager::MemManager;
+
+    fn decode_text(bytes: &[u8], address: u16) -> String {
+        let mut padded = [0; 3];
+        padded[..bytes.len()].copy_from_slice(bytes);
+        decode(padded, address).text
+    }
+
+    #[test]
+    fn operands_are_written_in_rgbds_syntax() {
+        assert_eq!(decode_text(&[0x01, 0x34, 0x12], 0), "ld bc, $1234");
+        assert_eq!(decode_text(&[0x2A], 0), "ld a, [hl+]");
+        assert_eq!(decode_text(&[0x36, 0x0F], 0), "ld [hl], $0F");
+        assert_eq!(decode_text(&[0x78], 0), "ld a, b");
+        assert_eq!(decode_text(&[0x96], 0), "sub a, [hl]");
+        assert_eq!(decode_text(&[0xE0, 0x44], 0), "ldh [$FF44], a");
+        assert_eq!(decode_text(&[0xF2], 0), "ldh a, [c]");
+        assert_eq!(decode_text(&[0xE8, 0xFE], 0), "add sp, -$02");
+        assert_eq!(decode_text(&[0xF8, 0x05], 0), "ld hl, sp + $05");
+        assert_eq!(decode_text(&[0xF5], 0), "push af");
+        assert_eq!(decode_text(&[0xFF], 0), "rst $38");
+        assert_eq!(decode_text(&[0xD3], 0), "db $D3");
+    }
+
+    #[test]
+    fn relative_jumps_resolve_their_target() {
+        assert_eq!(decode_text(&[0x18, 0xFE], 0x0150), "jr $0150");
+        assert_eq!(decode_text(&[0x20, 0x10], 0x0150), "jr nz, $0162");
+        assert_eq!(decode_text(&[0xC2, 0x00, 0x40], 0), "jp nz, $4000");
+    }
+
+    #[test]
+    fn prefixed_opcodes_are_decoded() {
+        assert_eq!(decode_text(&[0xCB, 0x37], 0), "swap a");
+        assert_eq!(decode_text(&[0xCB, 0x7E], 0), "bit 7, [hl]");
+        assert_eq!(decode_text(&[0xCB, 0xC1], 0), "set 0, c");
+        assert_eq!(decode([0xCB, 0x7E, 0], 0).cycles, 3);
+        assert_eq!(decode([0xCB, 0x86, 0], 0).cycles, 4);
+        assert_eq!(decode([0xCB, 0x00, 0], 0).length, 2);
+    }
+
+    #[test]
+    fn flow_follows_jumps_and_calls() {
+        assert_eq!(get_flow([0x18, 0xFE, 0], 0x0150), Flow::Jump(0x0150));
+        assert_eq!(get_flow([0xCC, 0x00, 0x40], 0), Flow::Branch(0x4000));
+        assert_eq!(get_flow([0xEF, 0, 0], 0), Flow::Branch(0x0028));
+        assert_eq!(get_flow([0xD9, 0, 0], 0), Flow::End);
+        assert_eq!(get_flow([0xC8, 0, 0], 0), Flow::Next);
+    }
+
+    // Runs the instruction once and checks the cpu moved past as many bytes and took as many
+    // cycles as were decoded
+    fn check_against_cpu(bytes: [u8; 3]) {
+        let memory = Rc::new(RefCell::new(MemManager::new()));
+        let mut cpu = CPU::new(memory.clone());
+        // Moves the stack down to work ram first so returns and pops have somewhere to read from
+        let program = [[0x31, 0xF0, 0xDF], bytes].concat();
+        let start = cpu.get_program_counter();
+        for (i, &byte) in program.iter().enumerate() {
+            memory.borrow_mut().write(start + i as u16, byte);
+        }
+        cpu.execute();
+        let address = cpu.get_program_counter();
+        let decoded = decode(bytes, address);
+        let clocks = cpu.execute();
+        let advanced = cpu.get_program_counter().wrapping_sub(address);
+        let fell_through = advanced == decoded.length as u16;
+        let text = &decoded.text;
+        match (get_flow(bytes, address), decoded.branch_cycles) {
+            (_, Some(branch_cycles)) => assert!(
+                (fell_through && clocks == decoded.cycles as u32 * 4)
+                    || clocks == branch_cycles as u32 * 4,
+                "{text} took {clocks} clocks"
+            ),
+            (Flow::Next, None) => {
+                assert!(fell_through, "{text} moved {advanced} bytes");
+                assert_eq!(clocks, decoded.cycles as u32 * 4, "{text}");
+            }
+            // Jumps and returns go somewhere else, only their timing can be compared
+            _ => assert_eq!(clocks, decoded.cycles as u32 * 4, "{text}"),
+        }
+    }
+
+    #[test]
+    fn lengths_and_cycles_match_the_cpu() {
+        for opcode in (0x00..=0xFF).filter(|&opcode| opcode != 0xCB) {
+            check_against_cpu([opcode, 0x10, 0xC0]);
+        }
+        for opcode in 0x00..=0xFF {
+            check_against_cpu([0xCB, opcode, 0x00]);
+        }
+    }
+
+    #[test]
+    fn lengths_and_cycles_are_decoded() {
+        assert_eq!(
+            decode([0xCD, 0x00, 0x40], 0),
+            DecodedInstruction::new("call $4000".to_string(), 3, 6)
+        );
+        assert_eq!(
+            decode([0xC8, 0, 0], 0),
+            DecodedInstruction::branch("ret z".to_string(), 1, 2, 5)
+        );
+        assert_eq!(decode([0x34, 0, 0], 0).cycles, 3);
+        assert_eq!(decode([0x70, 0, 0], 0).cycles, 2);
+        assert_eq!(decode([0x08, 0, 0], 0).cycles, 5);
+        assert_eq!(decode([0x10, 0, 0], 0).length, 2);
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::disasm::{decode, get_flow, is_invalid_opcode, Flow};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+// The logo and header are data even though the entry point jumps over them
+const HEADER_START: u16 = 0x0104;
+const HEADER_END: u16 = 0x0150;
+const BYTES_PER_DATA_LINE: usize = 16;
+// Older versions add a nop after halt and assemble ld to or from $FFxx as ldh, changing the rom
+const MIN_RGBASM_VERSION: &str = "0.7.0";
+// Where the cpu starts running code on its own
+const ENTRY_POINTS: [(u16, &str); 6] = [
+    (0x0040, "VBlankInterrupt"),
+    (0x0048, "StatInterrupt"),
+    (0x0050, "TimerInterrupt"),
+    (0x0058, "SerialInterrupt"),
+    (0x0060, "JoypadInterrupt"),
+    (0x0100, "Entry"),
+];
+
+// What following the code found in one bank
+struct Bank {
+    // Length of the instruction starting at each offset, 0 where none does
+    instruction_lengths: Vec<u8>,
+    covered: Vec<bool>,
+    // The bank and address each jump or call lands on
+    targets: BTreeMap<u16, (usize, u16)>,
+    labels: BTreeMap<u16, String>,
+}
+
+// Bank 0 is always at 0x0000-0x3FFF and every other bank is switched into 0x4000-0x7FFF
+fn get_base_address(bank: usize) -> u16 {
+    if bank == 0 {
+        0x0000
+    } else {
+        0x4000
+    }
+}
+
+fn get_offset(bank: usize, address: u16) -> Option<usize> {
+    let base = get_base_address(bank);
+    let offset = address.checked_sub(base)? as usize;
+    (offset < ROM_BANK_SIZE).then_some(offset)
+}
+
+// Splits a rom into code and data by following every path the code can take from the entry
+// points, so each bank can be written back out as RGBDS source that builds the same rom
+pub struct RomDisassembly<'a> {
+    rom: &'a [u8],
+    banks: Vec<Bank>,
+}
+
+impl<'a> RomDisassembly<'a> {
+    pub fn new(rom: &'a [u8]) -> io::Result<Self> {
+        if rom.len() < ROM_BANK_SIZE * 2 || !rom.len().is_multiple_of(ROM_BANK_SIZE) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "rom size should be a multiple of 16 KB and at least 32 KB",
+            ));
+        }
+        let banks = (0..rom.len() / ROM_BANK_SIZE)
+            .map(|_| Bank {
+                instruction_lengths: vec![0; ROM_BANK_SIZE],
+                covered: vec![false; ROM_BANK_SIZE],
+                targets: BTreeMap::new(),
+                labels: BTreeMap::new(),
+            })
+            .collect();
+        let mut disassembly = RomDisassembly { rom, banks };
+
+        disassembly.banks[0].covered[HEADER_START as usize..HEADER_END as usize].fill(true);
+        for (address, _) in ENTRY_POINTS {
+            disassembly.follow_code(0, address);
+        }
+        disassembly.add_labels();
+        Ok(disassembly)
+    }
+
+    pub fn get_bank_count(&self) -> usize {
+        self.banks.len()
+    }
+
+    fn get_bank_data(&self, bank: usize) -> &'a [u8] {
+        &self.rom[bank * ROM_BANK_SIZE..(bank + 1) * ROM_BANK_SIZE]
+    }
+
+    // Jumps into 0x4000-0x7FFF land in whichever bank was last switched in, which is only
+    // known when the code selected it right before
+    fn resolve_bank(&self, bank: usize, target: u16, mapped_bank: Option<usize>) -> Option<usize> {
+        match target {
+            0x0000..=0x3FFF => Some(0),
+            0x4000..=0x7FFF => {
+                let mapped_bank = mapped_bank.or((bank != 0).then_some(bank))?;
+                Some(mapped_bank.max(1) % self.banks.len())
+            }
+            _ => None,
+        }
+    }
+
+    fn follow_code(&mut self, start_bank: usize, start: u16) {
+        let mut queue = vec![(start_bank, start, None)];
+        while let Some((bank, mut address, mut mapped_bank)) = queue.pop() {
+            let data = self.get_bank_data(bank);
+            let mut immediate_a = None;
+            while let Some(offset) = get_offset(bank, address) {
+                let mut bytes = [0; 3];
+                let available = (ROM_BANK_SIZE - offset).min(bytes.len());
+                bytes[..available].copy_from_slice(&data[offset..offset + available]);
+                let length = decode(bytes, address).length as usize;
+
+                // Stop where the code runs into something already decoded or out of the bank.
+                // Stop takes a second byte that rgbasm always writes as 0.
+                let analysis = &mut self.banks[bank];
+                if offset + length > ROM_BANK_SIZE
+                    || analysis.covered[offset..offset + length].contains(&true)
+                    || is_invalid_opcode(bytes[0])
+                    || (bytes[0] == 0x10 && bytes[1] != 0x00)
+                {
+                    break;
+                }
+                analysis.instruction_lengths[offset] = length as u8;
+                analysis.covered[offset..offset + length].fill(true);
+
+                // Games usually switch banks with ld a, bank followed by ld [$2000], a
+                let n16 = u16::from_le_bytes([bytes[1], bytes[2]]);
+                match bytes[0] {
+                    0x3E => immediate_a = Some(bytes[1]),
+                    0xEA if (0x2000..=0x3FFF).contains(&n16) => {
+                        mapped_bank = immediate_a.map(|a| a as usize).or(mapped_bank);
+                    }
+                    0xEA => (),
+                    _ => immediate_a = None,
+                }
+
+                let flow = get_flow(bytes, address);
+                let target = match flow {
+                    Flow::Next => None,
+                    Flow::Branch(target) | Flow::Jump(target) => Some(target),
+                    Flow::End => break,
+                };
+                if let Some(target) = target {
+                    if let Some(target_bank) = self.resolve_bank(bank, target, mapped_bank) {
+                        self.banks[bank]
+                            .targets
+                            .insert(address, (target_bank, target));
+                        queue.push((target_bank, target, mapped_bank));
+                    }
+                }
+                if let Flow::Jump(_) = flow {
+                    break;
+                }
+                address = address.wrapping_add(length as u16);
+            }
+        }
+    }
+
+    // Only targets that were decoded as the start of an instruction can be labelled
+    fn add_labels(&mut self) {
+        for (address, name) in ENTRY_POINTS {
+            if self.banks[0].instruction_lengths[address as usize] > 0 {
+                self.banks[0].labels.insert(address, name.to_string());
+            }
+        }
+        let targets: Vec<(usize, u16)> = self
+            .banks
+            .iter()
+            .flat_map(|bank| bank.targets.values().copied())
+            .collect();
+        for (bank, address) in targets {
+            let Some(offset) = get_offset(bank, address) else {
+                continue;
+            };
+            if self.banks[bank].instruction_lengths[offset] > 0 {
+                self.banks[bank]
+                    .labels
+                    .entry(address)
+                    .or_insert_with(|| format!("Label_{bank:02X}_{address:04X}"));
+            }
+        }
+    }
+
+    pub fn render_bank(&self, bank: usize, rom_name: &str) -> String {
+        let base = get_base_address(bank);
+        let data = self.get_bank_data(bank);
+        let analysis = &self.banks[bank];
+        let mut text = String::new();
+        writeln!(text, "; Bank ${bank:02X} of {rom_name}").unwrap();
+        writeln!(
+            text,
+            "; Builds back into the same rom with rgbasm {MIN_RGBASM_VERSION} or newer"
+        )
+        .unwrap();
+        writeln!(text).unwrap();
+        if bank == 0 {
+            writeln!(text, "SECTION \"ROM Bank $00\", ROM0[$0000]").unwrap();
+        } else {
+            writeln!(
+                text,
+                "SECTION \"ROM Bank ${bank:02X}\", ROMX[$4000], BANK[${bank:02X}]"
+            )
+            .unwrap();
+        }
+
+        let mut data_line = Vec::new();
+        let mut offset = 0;
+        while offset < ROM_BANK_SIZE {
+            let address = base + offset as u16;
+            let starts_block = bank == 0 && (address == HEADER_START || address == HEADER_END);
+            let label = analysis.labels.get(&address);
+            let length = analysis.instruction_lengths[offset] as usize;
+            if starts_block || label.is_some() || length > 0 {
+                write_data_line(&mut text, &mut data_line);
+            }
+            if bank == 0 && address == HEADER_START {
+                writeln!(text, "\n; Cartridge header").unwrap();
+            } else if starts_block {
+                writeln!(text).unwrap();
+            }
+            if let Some(label) = label {
+                writeln!(text, "\n{label}::").unwrap();
+            }
+
+            if length == 0 {
+                data_line.push(data[offset]);
+                if data_line.len() == BYTES_PER_DATA_LINE {
+                    write_data_line(&mut text, &mut data_line);
+                }
+                offset += 1;
+                continue;
+            }
+            let mut bytes = [0; 3];
+            bytes[..length].copy_from_slice(&data[offset..offset + length]);
+            let mut instruction = decode(bytes, address).text;
+            if let Some(&(target_bank, target)) = analysis.targets.get(&address) {
+                if let Some(label) = self.banks[target_bank].labels.get(&target) {
+                    instruction = instruction.replace(&format!("${target:04X}"), label);
+                }
+            }
+            writeln!(text, "    {instruction}").unwrap();
+            offset += length;
+        }
+        write_data_line(&mut text, &mut data_line);
+        text
+    }
+
+    // Writes bank_00.asm, bank_01.asm and so on into the output directory
+    pub fn write_files(&self, output: &Path, rom_name: &str) -> io::Result<()> {
+        fs::create_dir_all(output)?;
+        for bank in 0..self.banks.len() {
+            let path = output.join(format!("bank_{bank:02X}.asm"));
+            fs::write(path, self.render_bank(bank, rom_name))?;
+        }
+        Ok(())
+    }
+}
+
+fn write_data_line(text: &mut String, data_line: &mut Vec<u8>) {
+    if data_line.is_empty() {
+        return;
+    }
+    let bytes: Vec<String> = data_line
+        .iter()
+        .map(|byte| format!("${byte:02X}"))
+        .collect();
+    writeln!(text, "    db {}", bytes.join(", ")).unwrap();
+    data_line.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_rom() -> Vec<u8> {
+        let mut rom = vec![0xFF; ROM_BANK_SIZE * 4];
+        let mut write = |address: usize, bytes: &[u8]| {
+            rom[address..address + bytes.len()].copy_from_slice(bytes);
+        };
+        // reti at every interrupt vector
+        for (address, _) in &ENTRY_POINTS[..5] {
+            write(*address as usize, &[0xD9]);
+        }
+        // nop, jp $0150
+        write(0x0100, &[0x00, 0xC3, 0x50, 0x01]);
+        // ld a, 2; ld [$2000], a; call $4000; jr -2
+        write(
+            0x0150,
+            &[0x3E, 0x02, 0xEA, 0x00, 0x20, 0xCD, 0x00, 0x40, 0x18, 0xFE],
+        );
+        // Bank 2: jr nz, +1; db $D3; ret
+        write(ROM_BANK_SIZE * 2, &[0x20, 0x01, 0xD3, 0xC9]);
+        rom
+    }
+
+    #[test]
+    fn code_is_followed_into_switched_banks() {
+        let rom = get_test_rom();
+        let disassembly = RomDisassembly::new(&rom).unwrap();
+        let bank_zero = &disassembly.banks[0];
+        assert_eq!(
+            bank_zero.instruction_lengths[0x0100..0x0105],
+            [1, 3, 0, 0, 0]
+        );
+        assert_eq!(bank_zero.instruction_lengths[0x0155], 3);
+        assert_eq!(bank_zero.labels[&0x0150], "Label_00_0150");
+
+        let bank_two = &disassembly.banks[2];
+        assert_eq!(bank_two.instruction_lengths[..4], [2, 0, 0, 1]);
+        assert_eq!(bank_two.labels[&0x4000], "Label_02_4000");
+        assert!(disassembly.banks[1].labels.is_empty());
+    }
+
+    #[test]
+    fn banks_are_written_with_labels_and_data() {
+        let rom = get_test_rom();
+        let disassembly = RomDisassembly::new(&rom).unwrap();
+        let bank_zero = disassembly.render_bank(0, "test.gb");
+        assert!(bank_zero.contains("SECTION \"ROM Bank $00\", ROM0[$0000]"));
+        assert!(bank_zero.contains("\nEntry::\n    nop\n    jp Label_00_0150\n"));
+        assert!(
+            bank_zero.contains("    call Label_02_4000\n\nLabel_00_0158::\n    jr Label_00_0158\n")
+        );
+        assert!(bank_zero.contains("; Cartridge header\n    db $FF, $FF"));
+
+        let bank_two = disassembly.render_bank(2, "test.gb");
+        assert!(bank_two.contains("SECTION \"ROM Bank $02\", ROMX[$4000], BANK[$02]"));
+        assert!(bank_two.contains("\nLabel_02_4000::\n    jr nz, Label_02_4003\n    db $D3\n"));
+        assert!(bank_two.contains("\nLabel_02_4003::\n    ret\n"));
+    }
+
+    #[test]
+    fn instructions_older_rgbasm_changes_are_written_as_decoded() {
+        let mut rom = get_test_rom();
+        // halt; ld [$FF44], a; ld a, [$FF44]; jr -2
+        let code = [0x76, 0xEA, 0x44, 0xFF, 0xFA, 0x44, 0xFF, 0x18, 0xFE];
+        rom[0x0150..0x0150 + code.len()].copy_from_slice(&code);
+        let disassembly = RomDisassembly::new(&rom).unwrap();
+        let bank_zero = disassembly.render_bank(0, "test.gb");
+        assert!(bank_zero.starts_with(
+            "; Bank $00 of test.gb\n; Builds back into the same rom with rgbasm 0.7.0 or newer\n"
+        ));
+        assert!(bank_zero.contains(
+            "\nLabel_00_0150::\n    halt\n    ld [$FF44], a\n    ld a, [$FF44]\n\nLabel_00_0157::\n"
+        ));
+    }
+
+    #[test]
+    fn every_byte_is_written_once() {
+        let rom = get_test_rom();
+        let disassembly = RomDisassembly::new(&rom).unwrap();
+        for bank in &disassembly.banks {
+            let mut offset = 0;
+            while offset < ROM_BANK_SIZE {
+                let length = bank.instruction_lengths[offset].max(1) as usize;
+                offset += length;
+            }
+            assert_eq!(offset, ROM_BANK_SIZE);
+        }
+        assert!(RomDisassembly::new(&rom[..ROM_BANK_SIZE]).is_err());
+    }
+}
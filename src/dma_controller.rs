@@ -2,18 +2,30 @@ use std::{cell::RefCell, rc::Rc};
 
 use crate::mem_manager::MemManager;
 use crate::memory::Memory;
+use crate::registers::{HDMA5_ADDRESS, STAT_ADDRESS};
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 
 const OAM_DMA_SRC_ADDRESS: u16 = 0xFF46;
 const OAM_START: u16 = 0xFE00;
 const OAM_SIZE: u16 = 160;
 const OAM_SRC_SENTINEL: u8 = 0xFF;
 const OAM_DMA_TRANSFER_CYCLES: u32 = 640;
+const VRAM_DMA_BLOCK_SIZE: u16 = 0x10;
+// Time the cpu is stopped for each block at normal speed, it takes twice as many cpu cycles at double speed
+const VRAM_DMA_BLOCK_CYCLES: u32 = 32;
+const VRAM_DMA_INACTIVE: u8 = 0xFF;
 
 // Todo: Lock cpu memory access during OAM dma
 pub struct DMAController {
     memory: Rc<RefCell<MemManager>>,
     oam_dma_is_active: bool,
     oam_dma_cycles_passed: u32,
+    hblank_dma_is_active: bool,
+    vram_dma_source: u16,
+    vram_dma_destination: u16,
+    vram_dma_blocks_left: u8,
+    vram_dma_cycles_left: u32,
+    last_ppu_mode: u8,
 }
 
 impl DMAController {
@@ -21,23 +33,34 @@ impl DMAController {
         memory
             .borrow_mut()
             .write(OAM_DMA_SRC_ADDRESS, OAM_SRC_SENTINEL);
+        memory
+            .borrow_mut()
+            .force_write(HDMA5_ADDRESS, VRAM_DMA_INACTIVE);
         Self {
             memory,
             oam_dma_is_active: false,
             oam_dma_cycles_passed: 0,
+            hblank_dma_is_active: false,
+            vram_dma_source: 0,
+            vram_dma_destination: 0,
+            vram_dma_blocks_left: 0,
+            vram_dma_cycles_left: 0,
+            last_ppu_mode: 0,
         }
     }
 
     pub fn update(&mut self, cycles: u32) {
         self.handle_oam_dma(cycles);
+        self.handle_vram_dma(cycles);
     }
 
     pub fn oam_dma_is_active(&self) -> bool {
         self.oam_dma_is_active
     }
 
+    // The cpu is stopped while a block of vram dma is being copied
     pub fn vram_dma_is_active(&self) -> bool {
-        false
+        self.vram_dma_cycles_left > 0
     }
 
     fn handle_oam_dma(&mut self, cycles: u32) {
@@ -68,11 +91,118 @@ impl DMAController {
         }
     }
 
-    // fn handle_vram_dma() {}
+    fn handle_vram_dma(&mut self, cycles: u32) {
+        self.vram_dma_cycles_left = self.vram_dma_cycles_left.saturating_sub(cycles);
+
+        // Hblank dma copies a block each time the ppu enters hblank
+        let ppu_mode = self.memory.borrow().read(STAT_ADDRESS) & 0b00000011;
+        let entered_hblank = ppu_mode == 0 && self.last_ppu_mode != 0;
+        self.last_ppu_mode = ppu_mode;
+        if self.hblank_dma_is_active && entered_hblank {
+            self.copy_vram_dma_block();
+        }
+
+        let hdma5_write = self.memory.borrow_mut().take_hdma5_write();
+        if let Some(value) = hdma5_write {
+            self.start_vram_dma(value);
+        }
+    }
+
+    fn start_vram_dma(&mut self, value: u8) {
+        let hblank_mode = value & 0b10000000 != 0;
+        if self.hblank_dma_is_active && !hblank_mode {
+            // Writing with bit 7 cleared cancels an hblank dma and leaves the remaining length readable
+            self.hblank_dma_is_active = false;
+            let remaining = self.vram_dma_blocks_left - 1;
+            self.memory
+                .borrow_mut()
+                .force_write(HDMA5_ADDRESS, 0b10000000 | remaining);
+            return;
+        }
+
+        let memory = self.memory.borrow();
+        self.vram_dma_source = memory.get_vram_dma_source();
+        self.vram_dma_destination = memory.get_vram_dma_destination();
+        drop(memory);
+        self.vram_dma_blocks_left = (value & 0b01111111) + 1;
+
+        if hblank_mode {
+            self.hblank_dma_is_active = true;
+            self.memory
+                .borrow_mut()
+                .force_write(HDMA5_ADDRESS, value & 0b01111111);
+            // A block is copied right away if the ppu is already in hblank
+            let ppu_mode = self.memory.borrow().read(STAT_ADDRESS) & 0b00000011;
+            if ppu_mode == 0 {
+                self.copy_vram_dma_block();
+            }
+        } else {
+            // General purpose dma copies everything at once
+            while self.vram_dma_blocks_left > 0 {
+                self.copy_vram_dma_block();
+            }
+        }
+    }
+
+    fn copy_vram_dma_block(&mut self) {
+        let mut mem = self.memory.borrow_mut();
+        for i in 0..VRAM_DMA_BLOCK_SIZE {
+            let data = mem.read(self.vram_dma_source.wrapping_add(i));
+            // The destination always stays inside the vram bank selected by VBK
+            let destination = 0x8000 | ((self.vram_dma_destination + i) & 0x1FFF);
+            mem.write(destination, data);
+        }
+        self.vram_dma_source = self.vram_dma_source.wrapping_add(VRAM_DMA_BLOCK_SIZE);
+        self.vram_dma_destination =
+            0x8000 | ((self.vram_dma_destination + VRAM_DMA_BLOCK_SIZE) & 0x1FFF);
+        self.vram_dma_blocks_left -= 1;
+
+        self.vram_dma_cycles_left += if mem.is_double_speed() {
+            VRAM_DMA_BLOCK_CYCLES * 2
+        } else {
+            VRAM_DMA_BLOCK_CYCLES
+        };
+
+        if self.vram_dma_blocks_left == 0 {
+            self.hblank_dma_is_active = false;
+            mem.force_write(HDMA5_ADDRESS, VRAM_DMA_INACTIVE);
+        } else if self.hblank_dma_is_active {
+            mem.force_write(HDMA5_ADDRESS, self.vram_dma_blocks_left - 1);
+        }
+    }
+}
+
+impl SaveState for DMAController {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.oam_dma_is_active);
+        state.write_u32(self.oam_dma_cycles_passed);
+        state.write_bool(self.hblank_dma_is_active);
+        state.write_u16(self.vram_dma_source);
+        state.write_u16(self.vram_dma_destination);
+        state.write_u8(self.vram_dma_blocks_left);
+        state.write_u32(self.vram_dma_cycles_left);
+        state.write_u8(self.last_ppu_mode);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.oam_dma_is_active = state.read_bool()?;
+        self.oam_dma_cycles_passed = state.read_u32()?;
+        self.hblank_dma_is_active = state.read_bool()?;
+        self.vram_dma_source = state.read_u16()?;
+        self.vram_dma_destination = state.read_u16()?;
+        self.vram_dma_blocks_left = state.read_u8()?;
+        self.vram_dma_cycles_left = state.read_u32()?;
+        self.last_ppu_mode = state.read_u8()?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::registers::{
+        HDMA1_ADDRESS, HDMA2_ADDRESS, HDMA3_ADDRESS, HDMA4_ADDRESS, VBK_ADDRESS,
+    };
+
     use super::*;
 
     fn get_test_dma_controller() -> DMAController {
@@ -80,6 +210,97 @@ mod tests {
         DMAController::new(mem.clone())
     }
 
+    fn setup_vram_dma(dma: &mut DMAController, source: u16, destination: u16) {
+        let mut mem = dma.memory.borrow_mut();
+        for i in 0..0x100 {
+            mem.write(source + i, i as u8);
+        }
+        mem.write(HDMA1_ADDRESS, (source >> 8) as u8);
+        mem.write(HDMA2_ADDRESS, source as u8);
+        mem.write(HDMA3_ADDRESS, (destination >> 8) as u8);
+        mem.write(HDMA4_ADDRESS, destination as u8);
+    }
+
+    fn set_ppu_mode(dma: &mut DMAController, mode: u8) {
+        dma.memory.borrow_mut().write(STAT_ADDRESS, mode);
+        dma.update(4);
+    }
+
+    #[test]
+    fn general_purpose_dma_copies_everything() {
+        let mut dma = get_test_dma_controller();
+        setup_vram_dma(&mut dma, 0xC000, 0x8800);
+        dma.memory.borrow_mut().write(HDMA5_ADDRESS, 0x03);
+        dma.update(0);
+        for i in 0..0x40 {
+            assert_eq!(dma.memory.borrow().read(0x8800 + i), i as u8);
+        }
+        assert_eq!(dma.memory.borrow().read(0x8840), 0x00);
+        assert_eq!(dma.memory.borrow().read(HDMA5_ADDRESS), 0xFF);
+        assert!(dma.vram_dma_is_active());
+    }
+
+    #[test]
+    fn general_purpose_dma_stops_cpu_for_each_block() {
+        let mut dma = get_test_dma_controller();
+        setup_vram_dma(&mut dma, 0xC000, 0x8000);
+        dma.memory.borrow_mut().write(HDMA5_ADDRESS, 0x01);
+        dma.update(0);
+        dma.update(VRAM_DMA_BLOCK_CYCLES * 2 - 4);
+        assert!(dma.vram_dma_is_active());
+        dma.update(4);
+        assert!(!dma.vram_dma_is_active());
+    }
+
+    #[test]
+    fn hblank_dma_copies_one_block_per_hblank() {
+        let mut dma = get_test_dma_controller();
+        set_ppu_mode(&mut dma, 3);
+        setup_vram_dma(&mut dma, 0xC000, 0x9000);
+        dma.memory.borrow_mut().write(HDMA5_ADDRESS, 0x81);
+        dma.update(0);
+        assert_eq!(dma.memory.borrow().read(0x9000), 0x00);
+        assert_eq!(dma.memory.borrow().read(HDMA5_ADDRESS), 0x01);
+
+        set_ppu_mode(&mut dma, 0);
+        assert_eq!(dma.memory.borrow().read(0x900F), 0x0F);
+        assert_eq!(dma.memory.borrow().read(0x9010), 0x00);
+        assert_eq!(dma.memory.borrow().read(HDMA5_ADDRESS), 0x00);
+
+        set_ppu_mode(&mut dma, 2);
+        set_ppu_mode(&mut dma, 0);
+        assert_eq!(dma.memory.borrow().read(0x901F), 0x1F);
+        assert_eq!(dma.memory.borrow().read(HDMA5_ADDRESS), 0xFF);
+    }
+
+    #[test]
+    fn hblank_dma_can_be_cancelled() {
+        let mut dma = get_test_dma_controller();
+        set_ppu_mode(&mut dma, 3);
+        setup_vram_dma(&mut dma, 0xC000, 0x9000);
+        dma.memory.borrow_mut().write(HDMA5_ADDRESS, 0x82);
+        set_ppu_mode(&mut dma, 0);
+        dma.memory.borrow_mut().write(HDMA5_ADDRESS, 0x00);
+        dma.update(0);
+        assert_eq!(dma.memory.borrow().read(HDMA5_ADDRESS), 0x81);
+
+        set_ppu_mode(&mut dma, 2);
+        set_ppu_mode(&mut dma, 0);
+        assert_eq!(dma.memory.borrow().read(0x9010), 0x00);
+    }
+
+    #[test]
+    fn vram_dma_writes_to_selected_bank() {
+        let mut dma = get_test_dma_controller();
+        setup_vram_dma(&mut dma, 0xC000, 0x8000);
+        dma.memory.borrow_mut().write(VBK_ADDRESS, 1);
+        dma.memory.borrow_mut().write(HDMA5_ADDRESS, 0x00);
+        dma.update(0);
+        assert_eq!(dma.memory.borrow().read(0x8001), 0x01);
+        dma.memory.borrow_mut().write(VBK_ADDRESS, 0);
+        assert_eq!(dma.memory.borrow().read(0x8001), 0x00);
+    }
+
     #[test]
     fn oam_dma_transfers_correctly() {
         let mut dma = get_test_dma_controller();
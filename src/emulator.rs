@@ -1,33 +1,62 @@
 use std::cell::RefCell;
 use std::fs;
 use std::hint::spin_loop;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::time::Duration;
 
-use sdl2::pixels::PixelFormatEnum;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::pixels::{Color, PixelFormatEnum};
 use spin_sleep::SpinSleeper;
 
+use crate::apu::SAMPLE_RATE;
+use crate::cartridge::{get_mmm01_menu_header, is_mbc1_multicart, CartridgeHeader, LoadError};
+use crate::cheats::Cheats;
+use crate::compat_palettes::{
+    get_combination_for_buttons, get_combination_for_cartridge, CompatPalettes,
+};
 use crate::cpu::CPU;
+use crate::debugger::{Debugger, Location};
 use crate::dma_controller::DMAController;
-use crate::input_handler::InputHandler;
+use crate::input_handler::{InputHandler, StateRequest};
+use crate::mbc::camera::{Camera, ImageSource};
+use crate::mbc::huc1::HuC1;
+use crate::mbc::huc3::HuC3;
 use crate::mbc::mbc1::MBC1;
+use crate::mbc::mbc2::MBC2;
 use crate::mbc::mbc3::MBC3;
 use crate::mbc::mbc5::MBC5;
-use crate::mbc::MBC;
-use crate::mem_manager::MemManager;
+use crate::mbc::mbc7::MBC7;
+use crate::mbc::mmm01::MMM01;
+use crate::mbc::rom_ram::RomRam;
+use crate::mbc::{has_battery, MBC};
+use crate::mem_manager::{MemManager, Model};
 use crate::memory::Memory;
-use crate::ppu::PPU;
+use crate::ppu::{DMGPalette, PPU};
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+use crate::serial::{ConsoleOutput, SerialPeer};
 use crate::timer::Timer;
 
-use crate::registers::{BCPD_ADDRESS, BCPS_ADDRESS, OCPD_ADDRESS, OCPS_ADDRESS};
+use crate::registers::{
+    BCPD_ADDRESS, BCPS_ADDRESS, IF_ADDRESS, KEY0_ADDRESS, OCPD_ADDRESS, OCPS_ADDRESS,
+};
 
 const DOTS_PER_FRAME: u32 = 70224;
 const SCREEN_WIDTH: u32 = 160;
 const SCREEN_HEIGHT: u32 = 144;
 const HORIZONTAL_SCALE: u32 = 5;
 const VERTICAL_SCALE: u32 = 5;
+const AUDIO_DEVICE_SAMPLES: u16 = 1024;
+// Emulation waits whenever more than this much audio is queued (in seconds)
+const AUDIO_QUEUE_LATENCY: f64 = 0.05;
+// Longest wait for the queue to drain before assuming the audio device stalled (in seconds)
+const AUDIO_QUEUE_TIMEOUT: f64 = 0.05;
+// How often battery backed ram gets written to disk while running
+const SAVE_INTERVAL_FRAMES: u32 = 60 * 10;
+// Longest a dmg game is held back while a palette is picked, about as long as the cgb logo shows
+const PALETTE_SELECTION_FRAMES: u32 = 60 * 2;
 
-// Todo: Implement cgb double speed mode
 pub struct Emulator {
     memory: Rc<RefCell<MemManager>>,
     cpu: CPU,
@@ -35,40 +64,128 @@ pub struct Emulator {
     timer: Timer,
     dma: DMAController,
     input: InputHandler,
+    rom_path: PathBuf,
+    // Shown in the window title next to the emulator's name
+    game_title: String,
+    save_path: Option<PathBuf>,
+    saved_ram: Vec<u8>,
+    image_source: Option<Box<dyn ImageSource>>,
+    debugger: Option<Debugger>,
 }
 
 impl Emulator {
-    pub fn new() -> Self {
+    pub fn new(model: Model) -> Self {
+        let mem = Emulator::new_memory(model);
+        Emulator::with_components(
+            mem.clone(),
+            CPU::new(mem.clone()),
+            PPU::new(mem.clone()),
+            Timer::new(mem.clone()),
+        )
+    }
+
+    // Starts from power on with the boot rom mapped in instead of skipping to the state it leaves behind
+    pub fn with_boot_rom(model: Model, boot_rom: Vec<u8>) -> Self {
+        let mem = Emulator::new_memory(model);
+        mem.borrow_mut().set_boot_rom(Some(boot_rom));
+        Emulator::with_components(
+            mem.clone(),
+            CPU::new_at_reset(mem.clone()),
+            PPU::new_at_reset(mem.clone()),
+            Timer::new_at_reset(mem.clone()),
+        )
+    }
+
+    fn new_memory(model: Model) -> Rc<RefCell<MemManager>> {
         let mem = Rc::new(RefCell::new(MemManager::new()));
+        mem.borrow_mut().set_model(model);
+        // Test roms report their results over serial
+        mem.borrow_mut()
+            .set_serial_peer(Some(Box::new(ConsoleOutput)));
+        mem
+    }
+
+    fn with_components(mem: Rc<RefCell<MemManager>>, cpu: CPU, ppu: PPU, timer: Timer) -> Self {
         Emulator {
             memory: mem.clone(),
-            cpu: CPU::new(mem.clone()),
-            ppu: PPU::new(mem.clone()),
-            timer: Timer::new(mem.clone()),
+            cpu,
+            ppu,
+            timer,
             dma: DMAController::new(mem.clone()),
             input: InputHandler::new(mem.clone()),
+            rom_path: PathBuf::new(),
+            game_title: String::new(),
+            save_path: None,
+            saved_ram: Vec::new(),
+            image_source: None,
+            debugger: None,
         }
     }
 
-    pub fn load_rom(&mut self, rom_path: &str) -> std::io::Result<()> {
+    // Shades used for the screen when emulating a dmg
+    pub fn set_dmg_palette(&mut self, palette: DMGPalette) {
+        self.ppu.set_dmg_palette(palette);
+    }
+
+    // Replaces what is plugged into the link port
+    pub fn set_serial_peer(&mut self, peer: Box<dyn SerialPeer>) {
+        self.memory.borrow_mut().set_serial_peer(Some(peer));
+    }
+
+    // Pictures shown to a camera cartridge, has to be set before the rom is loaded
+    pub fn set_image_source(&mut self, source: Box<dyn ImageSource>) {
+        self.image_source = Some(source);
+    }
+
+    // Pauses before the first instruction and takes debugger commands from stdin
+    pub fn enable_debugger(&mut self) {
+        self.debugger = Some(Debugger::new());
+    }
+
+    pub fn load_rom(&mut self, rom_path: &str) -> Result<(), LoadError> {
         let program = fs::read(rom_path)?;
+        let header = match get_mmm01_menu_header(&program) {
+            Some(menu_header) => menu_header,
+            None => CartridgeHeader::parse(&program)?,
+        };
+        if let Err(e) = header.verify_global_checksum(&program) {
+            println!("Warning: {e}");
+        }
+        header.verify_rom_size(&program)?;
+        let mut mbc = get_mbc(&header, &program)?;
+        println!("Loaded {header}");
+
+        self.rom_path = PathBuf::from(rom_path);
+        self.game_title = header.title.clone();
         // Preload cartridge header to to get data for setup
-        let header_range = 0..0x014F;
+        let header_range = 0..0x0150;
         for i in header_range {
             self.memory.borrow_mut().write(i as u16, program[i]);
         }
 
-        self.setup_dmg_compat();
-
-        // MBC setup
-        let rom_banks = self.get_number_of_rom_banks();
-        let ram_banks = self.get_number_of_ram_banks();
-        let mut mbc = self.get_mbc(rom_banks, ram_banks);
+        // The cgb boot rom sets up the compatibility palettes itself
+        let is_cgb = self.memory.borrow().get_model() == Model::CGB;
+        if is_cgb && !self.memory.borrow().is_boot_rom_mapped() {
+            self.setup_dmg_compat(&header);
+        }
 
         // Load rom into memory
         let rom_bank_size: usize = 0x4000;
         if let Some(ref mut mbc) = mbc {
             mbc.init(&program);
+            mbc.set_tilt_source(self.input.get_tilt_source());
+            if let Some(source) = self.image_source.take() {
+                mbc.set_image_source(source);
+            }
+
+            if has_battery(header.cartridge_type) {
+                let save_path = Path::new(rom_path).with_extension("sav");
+                if let Ok(data) = fs::read(&save_path) {
+                    mbc.load_ram(&data);
+                }
+                self.saved_ram = mbc.dump_ram();
+                self.save_path = Some(save_path);
+            }
         } else {
             for i in 0..rom_bank_size * 2 {
                 self.memory
@@ -77,15 +194,34 @@ impl Emulator {
             }
         }
         self.memory.borrow_mut().set_mbc(mbc);
+        self.load_cheats();
         Ok(())
     }
 
+    // Cheats are picked up from a text file next to the rom, a bad one is skipped entirely
+    fn load_cheats(&mut self) {
+        let cheats_path = self.rom_path.with_extension("cht");
+        let Ok(text) = fs::read_to_string(&cheats_path) else {
+            return;
+        };
+        match Cheats::parse(&text) {
+            Ok(cheats) if !cheats.is_empty() => self.memory.borrow_mut().set_cheats(cheats),
+            Ok(_) => (),
+            Err(e) => println!("Couldn't load cheats {}: {e}", cheats_path.display()),
+        }
+    }
+
     pub fn run(&mut self) {
         let sdl_context = sdl2::init().unwrap();
         let video_subsystem = sdl_context.video().unwrap();
+        let window_title = if self.game_title.is_empty() {
+            String::from("Gam Bam Color")
+        } else {
+            format!("Gam Bam Color - {}", self.game_title)
+        };
         let window = video_subsystem
             .window(
-                "Gam Bam Color",
+                &window_title,
                 SCREEN_WIDTH * HORIZONTAL_SCALE,
                 SCREEN_HEIGHT * VERTICAL_SCALE,
             )
@@ -101,18 +237,99 @@ impl Emulator {
             .create_texture_target(PixelFormatEnum::BGR555, SCREEN_WIDTH, SCREEN_HEIGHT)
             .unwrap();
 
+        let audio_subsystem = sdl_context.audio().unwrap();
+        let desired_spec = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE as i32),
+            channels: Some(2),
+            samples: Some(AUDIO_DEVICE_SAMPLES),
+        };
+        // Fall back to pacing with the frame timer if no audio device is available
+        let audio_queue: Option<AudioQueue<f32>> =
+            match audio_subsystem.open_queue(None, &desired_spec) {
+                Ok(queue) => {
+                    let device_rate = queue.spec().freq as u32;
+                    self.memory
+                        .borrow_mut()
+                        .apu_mut()
+                        .set_sample_rate(device_rate);
+                    queue.resume();
+                    Some(queue)
+                }
+                Err(e) => {
+                    println!("Couldn't open audio device: {e}");
+                    None
+                }
+            };
+
         let frame_time: std::time::Duration = std::time::Duration::from_secs_f64(1.0 / 59.7);
         let mut dots = 0;
         let sleeper = spin_sleep::SpinSleeper::new(100_000_0)
             .with_spin_strategy(spin_sleep::SpinStrategy::SpinLoopHint);
         let mut start = std::time::Instant::now();
+        let mut frames_since_save = 0;
+        let mut audio_stalled = false;
+
+        if self.memory.borrow().is_dmg_compat() {
+            canvas.set_draw_color(Color::WHITE);
+            let still_running = self.wait_for_palette_selection(|input| {
+                for e in event_pump.poll_iter() {
+                    input.update_joypad(e);
+                }
+                canvas.clear();
+                canvas.present();
+                sleeper.sleep(frame_time);
+            });
+            if !still_running {
+                return;
+            }
+            start = std::time::Instant::now();
+        }
+
         loop {
+            // Nothing runs while the other emulator catches up, but the window stays responsive
+            if self.memory.borrow().is_serial_waiting() {
+                for e in event_pump.poll_iter() {
+                    self.input.update_joypad(e);
+                }
+                if self.input.is_quit_requested() {
+                    self.save_cartridge_ram();
+                    return;
+                }
+                self.memory.borrow_mut().update(0);
+                continue;
+            }
             if dots >= DOTS_PER_FRAME {
                 for e in event_pump.poll_iter() {
                     self.input.update_joypad(e);
                 }
+                if self.input.is_quit_requested() {
+                    self.save_cartridge_ram();
+                    return;
+                }
+                if let Some(request) = self.input.take_state_request() {
+                    self.handle_state_request(request);
+                }
+                if self.input.take_cheats_toggle_request() {
+                    let enabled = self.memory.borrow_mut().toggle_cheats();
+                    println!("Cheats {}", if enabled { "on" } else { "off" });
+                }
+                self.memory.borrow_mut().apply_ram_cheats();
+                if self.input.take_debug_break_request() {
+                    self.debugger
+                        .get_or_insert_with(Debugger::new)
+                        .request_break();
+                }
+                if let Some(ref mut debugger) = self.debugger {
+                    debugger.on_frame();
+                }
                 dots -= DOTS_PER_FRAME;
 
+                frames_since_save += 1;
+                if frames_since_save >= SAVE_INTERVAL_FRAMES {
+                    self.save_cartridge_ram();
+                    frames_since_save = 0;
+                }
+
                 let frame = self.ppu.get_frame();
                 texture
                     .update(None, &frame, (SCREEN_WIDTH * 2) as usize)
@@ -120,93 +337,516 @@ impl Emulator {
                 canvas.copy(&texture, None, None).unwrap();
                 canvas.present();
 
-                let elapsed = start.elapsed();
-                let remainder = frame_time.saturating_sub(elapsed);
+                let samples = self.memory.borrow_mut().apu_mut().take_samples();
+                let paced_by_audio = match audio_queue {
+                    Some(ref queue) => {
+                        self.queue_audio(queue, &samples, &sleeper, &mut audio_stalled)
+                    }
+                    None => false,
+                };
+                if !paced_by_audio {
+                    let elapsed = start.elapsed();
+                    let remainder = frame_time.saturating_sub(elapsed);
 
-                if remainder != Duration::ZERO && !self.input.is_throttled() {
-                    sleeper.sleep(remainder);
+                    if remainder != Duration::ZERO && !self.input.is_throttled() {
+                        sleeper.sleep(remainder);
+                    }
+                    start = std::time::Instant::now();
                 }
-                start = std::time::Instant::now();
             }
             self.input.update();
-            let curr_clocks = self.cpu.execute();
+            let curr_clocks = if self.dma.vram_dma_is_active() {
+                // The cpu is stopped while vram dma copies a block
+                4
+            } else {
+                if !self.check_debugger() {
+                    self.save_cartridge_ram();
+                    return;
+                }
+                self.cpu.execute()
+            };
             self.timer.update(curr_clocks);
-            self.ppu.update(curr_clocks);
             self.dma.update(curr_clocks);
-            dots += curr_clocks;
+            // The ppu, apu and frame timing stay at normal speed while the cpu runs at double speed
+            let curr_dots = if self.memory.borrow().is_double_speed() {
+                curr_clocks / 2
+            } else {
+                curr_clocks
+            };
+            self.ppu.update(curr_dots);
+            self.memory.borrow_mut().update(curr_dots);
+            dots += curr_dots;
         }
     }
 
-    pub fn load_and_run(&mut self, rom_path: &str) {
-        let status = self.load_rom(rom_path);
-        if let Ok(_) = status {
-            self.run();
-        } else {
-            println!("Couldn't load rom");
+    // Hands control to the debugger if it wants to stop before the next instruction, returns
+    // false if it asked to quit
+    fn check_debugger(&mut self) -> bool {
+        let Some(ref mut debugger) = self.debugger else {
+            return true;
+        };
+        let memory = self.memory.borrow();
+        let pc = self.cpu.get_program_counter();
+        let location = Location {
+            bank: memory.get_rom_bank(pc),
+            pc,
+            sp: self.cpu.get_stack_pointer(),
+            opcode: memory.read(pc),
+            halted: self.cpu.is_halted(),
+        };
+        if !debugger.should_break(&location) {
+            return true;
         }
+        debugger.prompt(&location, &self.cpu, &memory)
     }
 
-    fn get_number_of_rom_banks(&self) -> u8 {
-        2 << self.memory.borrow().read(0x0148)
+    // Queues a frame's worth of audio and waits for the device to play it down to the target
+    // latency, which keeps the emulation running at the speed the audio is consumed
+    // Returns false when the frame still has to be paced with the frame timer, which is the case
+    // from the moment the device stops draining the queue until it has played it down again
+    fn queue_audio(
+        &self,
+        queue: &AudioQueue<f32>,
+        samples: &[f32],
+        sleeper: &SpinSleeper,
+        stalled: &mut bool,
+    ) -> bool {
+        if self.input.is_throttled() {
+            // Mute while unthrottled so the queue doesn't grow without bound
+            queue.clear();
+            *stalled = false;
+            return true;
+        }
+        let bytes_per_second = queue.spec().freq as f64 * 2.0 * size_of::<f32>() as f64;
+        let max_queued_bytes = (bytes_per_second * AUDIO_QUEUE_LATENCY) as u32;
+        if *stalled {
+            if queue.size() > max_queued_bytes {
+                // Nothing is added meanwhile so the queue stays bounded
+                return false;
+            }
+            *stalled = false;
+        }
+
+        if let Err(e) = queue.queue_audio(samples) {
+            println!("Couldn't queue audio: {e}");
+        }
+        let start = std::time::Instant::now();
+        while queue.size() > max_queued_bytes {
+            if start.elapsed().as_secs_f64() > AUDIO_QUEUE_TIMEOUT {
+                // A paused or unplugged device never drains
+                *stalled = true;
+                return false;
+            }
+            sleeper.sleep(Duration::from_micros(500));
+        }
+        true
     }
 
-    fn get_number_of_ram_banks(&self) -> u8 {
-        let header_value = self.memory.borrow().read(0x0149);
-        match header_value {
-            0 => 0,
-            2 => 1,
-            3 => 4,
-            4 => 16,
-            5 => 8,
-            _ => 0,
+    // Writes battery backed ram to the save file if it changed since the last save
+    fn save_cartridge_ram(&mut self) {
+        let Some(ref save_path) = self.save_path else {
+            return;
+        };
+        let Some(ram) = self.memory.borrow().dump_cartridge_ram() else {
+            return;
+        };
+        // Clock footers end with the time they were dumped at, which changes every second
+        let compared = ram
+            .len()
+            .saturating_sub(self.memory.borrow().get_cartridge_timestamp_size());
+        if ram.len() == self.saved_ram.len() && ram[..compared] == self.saved_ram[..compared] {
+            return;
+        }
+        match write_atomically(save_path, &ram) {
+            Ok(()) => self.saved_ram = ram,
+            Err(e) => println!("Couldn't write save file {}: {e}", save_path.display()),
         }
     }
 
-    fn get_mbc(&self, rom_banks: u8, ram_banks: u8) -> Option<Box<dyn MBC>> {
-        let header_value = self.memory.borrow().read(0x0147);
-        match header_value {
-            0 => None,
-            0x01..=0x03 => Some(Box::new(MBC1::new(rom_banks, ram_banks))),
-            0x0f..=0x013 => Some(Box::new(MBC3::new(rom_banks, ram_banks))),
-            0x19..=0x1E => Some(Box::new(MBC5::new(rom_banks, ram_banks))),
-            _ => None,
+    // Snapshots the whole machine into the versioned save state format
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.write_header(self.get_rom_checksum());
+        self.cpu.save_state(&mut state);
+        self.memory.borrow().save_state(&mut state);
+        self.ppu.save_state(&mut state);
+        self.timer.save_state(&mut state);
+        self.dma.save_state(&mut state);
+        state.into_bytes()
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data);
+        state.read_header(self.get_rom_checksum())?;
+        // Keep the current machine around so a damaged state can't leave it half loaded
+        let backup = self.save_state();
+        if let Err(e) = self.load_components(&mut state) {
+            let mut backup_state = StateReader::new(&backup);
+            backup_state.read_header(self.get_rom_checksum())?;
+            self.load_components(&mut backup_state)?;
+            return Err(e);
         }
+        Ok(())
     }
 
-    fn setup_dmg_compat(&self) {
-        // Check for original gb game
-        let compat_value = self.memory.borrow().read(0x0143);
-        let is_dmg_game = compat_value != 0x80 && compat_value != 0xC0;
-        if is_dmg_game {
-            // Todo: Implement compatibility palettes
-            // Just set palettes to monochrome for now
-            let black = (0x00, 0x00);
-            let dark_gray = (0x4a, 0x29);
-            let light_gray = (0x9c, 0x73);
-            let white = (0xFF, 0x7f);
-            let colors = [white, light_gray, dark_gray, black];
-            // Auto-increment
-            self.memory.borrow_mut().write(BCPS_ADDRESS, 0b10000000);
-            self.memory.borrow_mut().write(OCPS_ADDRESS, 0b10000000);
+    fn load_components(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.cpu.load_state(state)?;
+        self.memory.borrow_mut().load_state(state)?;
+        self.ppu.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.dma.load_state(state)?;
+        if !state.is_finished() {
+            return Err(StateError::Invalid("unexpected data at the end"));
+        }
+        Ok(())
+    }
 
-            // Initialize background palettes
-            for _ in 0..8 {
-                for color in colors.iter() {
-                    self.memory.borrow_mut().write(BCPD_ADDRESS, color.0);
-                    self.memory.borrow_mut().write(BCPD_ADDRESS, color.1);
+    fn handle_state_request(&mut self, request: StateRequest) {
+        match request {
+            StateRequest::Save(slot) => {
+                let path = self.get_state_path(slot);
+                if let Err(e) = write_atomically(&path, &self.save_state()) {
+                    println!("Couldn't write save state {}: {e}", path.display());
                 }
             }
-
-            // Initialize object palettes
-            for _ in 0..8 {
-                for color in colors.iter() {
-                    self.memory.borrow_mut().write(OCPD_ADDRESS, color.0);
-                    self.memory.borrow_mut().write(OCPD_ADDRESS, color.1);
+            StateRequest::Load(slot) => {
+                let path = self.get_state_path(slot);
+                let result = match fs::read(&path) {
+                    Ok(data) => self.load_state(&data).map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+                if let Err(e) = result {
+                    println!("Couldn't load save state {}: {e}", path.display());
                 }
             }
+        }
+    }
+
+    fn get_state_path(&self, slot: u8) -> PathBuf {
+        self.rom_path.with_extension(format!("ss{slot}"))
+    }
+
+    // The global checksum from the header keeps states from being loaded into other games
+    fn get_rom_checksum(&self) -> u16 {
+        let memory = self.memory.borrow();
+        u16::from_be_bytes([memory.read(0x014E), memory.read(0x014F)])
+    }
+
+    pub fn load_and_run(&mut self, rom_path: &str) {
+        match self.load_rom(rom_path) {
+            Ok(()) => self.run(),
+            Err(e) => println!("Couldn't load rom: {e}"),
+        }
+    }
+
+    fn setup_dmg_compat(&self, header: &CartridgeHeader) {
+        if !header.is_cgb_game() {
+            // Leave the same mode and palettes behind that the cgb boot rom would
+            self.memory.borrow_mut().force_write(KEY0_ADDRESS, 0x04);
+            let combination = get_combination_for_cartridge(header);
+            self.load_compat_palettes(combination);
+        }
+    }
+
+    // The cgb picks a palette while its logo shows, before the game runs, so buttons the game
+    // itself reads right after starting can't change the colors. The game only waits while
+    // something is held when it loads. Returns false if quitting was requested meanwhile.
+    fn wait_for_palette_selection(
+        &mut self,
+        mut next_frame: impl FnMut(&mut InputHandler),
+    ) -> bool {
+        let interrupt_flags = self.memory.borrow().read(IF_ADDRESS);
+        next_frame(&mut self.input);
+        for _ in 0..PALETTE_SELECTION_FRAMES {
+            if self.input.is_quit_requested() {
+                return false;
+            }
+            self.check_palette_selection();
+            if self.input.get_held_buttons() == (0x0F, 0x0F) {
+                break;
+            }
+            next_frame(&mut self.input);
+        }
+        // Button presses while waiting don't reach the game as joypad interrupts
+        self.memory.borrow_mut().write(IF_ADDRESS, interrupt_flags);
+        true
+    }
+
+    // Lets the player pick a palette with the same buttons as on a cgb
+    fn check_palette_selection(&self) {
+        if !self.memory.borrow().is_dmg_compat() {
+            return;
+        }
+        let (direction_input, action_input) = self.input.get_held_buttons();
+        if let Some(combination) = get_combination_for_buttons(direction_input, action_input) {
+            self.load_compat_palettes(combination);
+        }
+    }
+
+    fn load_compat_palettes(&self, combination: usize) {
+        let palettes = CompatPalettes::from_combination(combination);
+        let mut memory = self.memory.borrow_mut();
+        // Auto-increment
+        memory.write(BCPS_ADDRESS, 0b10000000);
+        memory.write(OCPS_ADDRESS, 0b10000000);
+
+        for color in palettes.background {
+            memory.write(BCPD_ADDRESS, color as u8);
+            memory.write(BCPD_ADDRESS, (color >> 8) as u8);
+        }
+        for color in palettes.object0.iter().chain(palettes.object1.iter()) {
+            memory.write(OCPD_ADDRESS, *color as u8);
+            memory.write(OCPD_ADDRESS, (*color >> 8) as u8);
+        }
+
+        memory.write(BCPS_ADDRESS, 0);
+        memory.write(OCPS_ADDRESS, 0);
+    }
+}
+
+// Writes to a temporary file first and renames it over the target so that a crash midway
+// never leaves a partially written file behind
+fn write_atomically(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    let mut file = fs::File::create(&temp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+fn get_mbc(header: &CartridgeHeader, program: &[u8]) -> Result<Option<Box<dyn MBC>>, LoadError> {
+    let rom_banks = header.rom_banks;
+    let ram_size = header.ram_size;
+    let cartridge_type = header.cartridge_type;
+    let mbc: Box<dyn MBC> = match cartridge_type {
+        0 => return Ok(None),
+        0x01..=0x03 if is_mbc1_multicart(program) => {
+            Box::new(MBC1::new_multicart(rom_banks, ram_size))
+        }
+        0x01..=0x03 => Box::new(MBC1::new(rom_banks, ram_size)),
+        0x05..=0x06 => Box::new(MBC2::new(rom_banks)),
+        0x08..=0x09 => Box::new(RomRam::new(rom_banks, ram_size)),
+        0x0B..=0x0D => Box::new(MMM01::new(rom_banks, ram_size)),
+        0x0F..=0x13 => {
+            let has_rtc = cartridge_type == 0x0F || cartridge_type == 0x10;
+            // Only the MBC30 can address more than 2 MB of rom or 32 KB of ram
+            if rom_banks > 128 || ram_size > 0x8000 {
+                Box::new(MBC3::new_mbc30(rom_banks, ram_size, has_rtc))
+            } else {
+                Box::new(MBC3::new(rom_banks, ram_size, has_rtc))
+            }
+        }
+        0x19..=0x1E => Box::new(MBC5::new(rom_banks, ram_size)),
+        0x22 => Box::new(MBC7::new(rom_banks)),
+        0xFC => Box::new(Camera::new(rom_banks)),
+        0xFE => Box::new(HuC3::new(rom_banks, ram_size)),
+        0xFF => Box::new(HuC1::new(rom_banks, ram_size)),
+        _ => return Err(LoadError::UnsupportedCartridgeType(cartridge_type)),
+    };
+    Ok(Some(mbc))
+}
+
+#[cfg(test)]
+mod tests {
+    use sdl2::event::Event;
+    use sdl2::keyboard::{Keycode, Mod};
+
+    use super::*;
+
+    fn get_temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gam_bam_color_{}_{name}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn atomic_write_replaces_existing_file() {
+        let dir = get_temp_dir("atomic_write");
+        let path = dir.join("game.sav");
+        fs::write(&path, [0xAA; 16]).unwrap();
+
+        write_atomically(&path, &[0x55; 8]).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [0x55; 8]);
+        assert!(!dir.join("game.sav.tmp").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Writes a rom with 8 KB of ram and the given cartridge type to the directory
+    fn write_test_rom(dir: &Path, cartridge_type: u8) -> PathBuf {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0147] = cartridge_type;
+        rom[0x0149] = 0x02;
+        rom[0x014D] = rom[0x0134..=0x014C]
+            .iter()
+            .fold(0u8, |sum, &byte| sum.wrapping_sub(byte).wrapping_sub(1));
+        let rom_path = dir.join("game.gb");
+        fs::write(&rom_path, &rom).unwrap();
+        rom_path
+    }
+
+    #[test]
+    fn battery_ram_is_loaded_from_and_saved_to_sav_file() {
+        let dir = get_temp_dir("battery_ram");
+        // MBC1 with battery backed ram
+        let rom_path = write_test_rom(&dir, 0x03);
+        let ram: Vec<u8> = (0..0x2000).map(|i| i as u8).collect();
+        fs::write(dir.join("game.sav"), &ram).unwrap();
+
+        let mut emulator = Emulator::new(Model::CGB);
+        emulator.load_rom(rom_path.to_str().unwrap()).unwrap();
+        emulator.memory.borrow_mut().write(0x0000, 0x0A);
+        assert_eq!(emulator.memory.borrow().read(0xA001), 0x01);
+
+        emulator.memory.borrow_mut().write(0xA001, 0x42);
+        emulator.save_cartridge_ram();
+        let saved = fs::read(dir.join("game.sav")).unwrap();
+        assert_eq!(saved[0x0001], 0x42);
+        assert_eq!(saved[0x1FFF], ram[0x1FFF]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn clock_timestamp_alone_does_not_rewrite_save() {
+        let dir = get_temp_dir("clock_timestamp");
+        // MBC3 with a clock and battery backed ram
+        let rom_path = write_test_rom(&dir, 0x10);
+        let mut emulator = Emulator::new(Model::CGB);
+        emulator.load_rom(rom_path.to_str().unwrap()).unwrap();
+
+        // As if the last save was dumped a while ago
+        let timestamp_start = emulator.saved_ram.len() - 8;
+        emulator.saved_ram[timestamp_start..].fill(0);
+        emulator.save_cartridge_ram();
+        assert!(!dir.join("game.sav").exists());
+
+        emulator.memory.borrow_mut().write(0x0000, 0x0A);
+        emulator.memory.borrow_mut().write(0xA000, 0x42);
+        emulator.save_cartridge_ram();
+        assert!(dir.join("game.sav").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn loading_a_state_restores_the_machine() {
+        let mut emulator = Emulator::new(Model::CGB);
+        emulator.memory.borrow_mut().write(0xC000, 0x3C);
+        for _ in 0..1000 {
+            let clocks = emulator.cpu.execute();
+            emulator.ppu.update(clocks);
+        }
+        let state = emulator.save_state();
+
+        emulator.memory.borrow_mut().write(0xC000, 0x00);
+        for _ in 0..1000 {
+            let clocks = emulator.cpu.execute();
+            emulator.ppu.update(clocks);
+        }
+        emulator.load_state(&state).unwrap();
+
+        assert_eq!(emulator.memory.borrow().read(0xC000), 0x3C);
+        assert_eq!(emulator.save_state(), state);
+    }
+
+    #[test]
+    fn boot_rom_runs_from_reset() {
+        // LD A, $01 followed by a write of A to BANK
+        let boot_rom = vec![0x3E, 0x01, 0xE0, 0x50];
+        let mut emulator = Emulator::with_boot_rom(Model::CGB, [boot_rom, vec![0; 0xFC]].concat());
+        emulator.cpu.execute();
+        assert!(emulator.memory.borrow().is_boot_rom_mapped());
+        emulator.cpu.execute();
+        assert!(!emulator.memory.borrow().is_boot_rom_mapped());
+    }
+
+    #[test]
+    fn truncated_state_leaves_machine_untouched() {
+        let mut emulator = Emulator::new(Model::CGB);
+        let state = emulator.save_state();
+        emulator.memory.borrow_mut().write(0xC000, 0x3C);
+        let before = emulator.save_state();
+
+        let result = emulator.load_state(&state[..state.len() - 1]);
+        assert_eq!(result, Err(StateError::Truncated));
+        assert_eq!(emulator.save_state(), before);
+    }
+
+    fn get_key_event(keycode: Keycode, pressed: bool) -> Event {
+        if pressed {
+            Event::KeyDown {
+                timestamp: 0,
+                window_id: 0,
+                keycode: Some(keycode),
+                scancode: None,
+                keymod: Mod::NOMOD,
+                repeat: false,
+            }
+        } else {
+            Event::KeyUp {
+                timestamp: 0,
+                window_id: 0,
+                keycode: Some(keycode),
+                scancode: None,
+                keymod: Mod::NOMOD,
+                repeat: false,
+            }
+        }
+    }
+
+    // Second color of the second object palette, which the compat palettes mostly set differently
+    fn get_object1_color(emulator: &Emulator) -> u16 {
+        let mut memory = emulator.memory.borrow_mut();
+        memory.write(OCPS_ADDRESS, 10);
+        let low = memory.read(OCPD_ADDRESS);
+        memory.write(OCPS_ADDRESS, 11);
+        let high = memory.read(OCPD_ADDRESS);
+        memory.write(OCPS_ADDRESS, 0);
+        u16::from_le_bytes([low, high])
+    }
+
+    fn load_dmg_game(name: &str) -> (Emulator, PathBuf) {
+        let dir = get_temp_dir(name);
+        let rom_path = write_test_rom(&dir, 0x01);
+        let mut emulator = Emulator::new(Model::CGB);
+        emulator.load_rom(rom_path.to_str().unwrap()).unwrap();
+        assert!(emulator.memory.borrow().is_dmg_compat());
+        (emulator, dir)
+    }
+
+    #[test]
+    fn game_starts_right_away_when_nothing_is_held() {
+        let (mut emulator, dir) = load_dmg_game("palette_no_wait");
+        let default_color = get_object1_color(&emulator);
+        let mut frames = 0;
+        assert!(emulator.wait_for_palette_selection(|_| frames += 1));
+        assert_eq!(frames, 1);
+        assert_eq!(get_object1_color(&emulator), default_color);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn palette_picked_while_waiting_stays_loaded() {
+        let (mut emulator, dir) = load_dmg_game("palette_picked");
+        // Left + B is greyscale, held for a few frames and then let go
+        let mut frames = 0;
+        assert_ne!(get_object1_color(&emulator), 0x5294);
+        assert!(emulator.wait_for_palette_selection(|input| {
+            let held = frames < 5;
+            input.update_joypad(get_key_event(Keycode::Left, held));
+            input.update_joypad(get_key_event(Keycode::Z, held));
+            frames += 1;
+        }));
+        assert_eq!(frames, 6);
+        assert_eq!(get_object1_color(&emulator), 0x5294);
+        assert_eq!(emulator.memory.borrow().read(IF_ADDRESS) & 0b00010000, 0);
 
-            self.memory.borrow_mut().write(BCPS_ADDRESS, 0);
-            self.memory.borrow_mut().write(OCPS_ADDRESS, 0);
+        for _ in 0..1000 {
+            let clocks = emulator.cpu.execute();
+            emulator.ppu.update(clocks);
         }
+        assert_eq!(get_object1_color(&emulator), 0x5294);
+        fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -6,10 +6,13 @@ use crate::ppu::{BackgroundPixel, ObjectPixel, PPU};
 
 use crate::fetcher::FetcherStage::{DataHigh, DataLow, GetTile, Push};
 
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+
 use crate::registers::{
     LCDC_ADDRESS, SCX_ADDRESS, SCY_ADDRESS, VBK_ADDRESS, WX_ADDRESS, WY_ADDRESS,
 };
 
+#[derive(Clone, Copy)]
 enum FetcherStage {
     GetTile,
     DataLow,
@@ -17,6 +20,18 @@ enum FetcherStage {
     Push,
 }
 
+impl FetcherStage {
+    fn from_u8(value: u8) -> Result<Self, StateError> {
+        match value {
+            0 => Ok(GetTile),
+            1 => Ok(DataLow),
+            2 => Ok(DataHigh),
+            3 => Ok(Push),
+            _ => Err(StateError::Invalid("fetcher stage")),
+        }
+    }
+}
+
 pub struct BackgroundFetcher {
     tilemap_col: u8,
     current_dots: u32,
@@ -159,6 +174,10 @@ impl BackgroundFetcher {
     }
 
     pub(crate) fn get_bg_tile_attributes(&mut self, ppu: &PPU) -> u8 {
+        // Dmg games never set up the attribute map
+        if ppu.memory.borrow().is_dmg_mode() {
+            return 0;
+        }
         let tile_address = self.get_tile_address(ppu);
 
         let initial = ppu.memory.borrow().read(VBK_ADDRESS);
@@ -243,6 +262,7 @@ pub(crate) struct SpriteFetcher {
     stage: FetcherStage,
     current_dots: u8,
     current_sprite: Option<u16>,
+    pending_sprites: VecDeque<u16>,
     tile_index: Option<u8>,
     tile_data_low: Option<u8>,
     tile_data_high: Option<u8>,
@@ -254,6 +274,7 @@ impl SpriteFetcher {
             stage: GetTile,
             current_dots: 0,
             current_sprite: None,
+            pending_sprites: VecDeque::new(),
             tile_index: None,
             tile_data_low: None,
             tile_data_high: None,
@@ -261,6 +282,11 @@ impl SpriteFetcher {
     }
 
     pub(crate) fn start_fetch(&mut self, sprite_address: u16) {
+        // Objects at the same position wait for the one being fetched
+        if self.current_sprite.is_some() {
+            self.pending_sprites.push_back(sprite_address);
+            return;
+        }
         self.reset();
         self.current_sprite = Some(sprite_address);
     }
@@ -294,9 +320,9 @@ impl SpriteFetcher {
                     self.tile_data_low.unwrap(),
                     self.tile_data_high.unwrap(),
                 );
-                ppu.object_pixel_queue.clear();
                 self.push_object_pixels(ppu, pixels);
                 self.reset();
+                self.current_sprite = self.pending_sprites.pop_front();
             }
         }
     }
@@ -318,6 +344,7 @@ impl SpriteFetcher {
             let sprite_address = self.current_sprite.unwrap();
             ppu.memory.borrow().read(sprite_address + 3)
         };
+        let is_dmg_mode = ppu.memory.borrow().is_dmg_mode();
 
         let using_large_objects = lcdc & 0b00000100 != 0;
         let height = if using_large_objects { 16 } else { 8 };
@@ -336,7 +363,7 @@ impl SpriteFetcher {
 
         let initial = ppu.memory.borrow().read(VBK_ADDRESS);
 
-        let uses_vram_bank_one = attrs & 0b00001000 != 0;
+        let uses_vram_bank_one = attrs & 0b00001000 != 0 && !is_dmg_mode;
         if uses_vram_bank_one {
             ppu.memory.borrow_mut().write(VBK_ADDRESS, 0x01);
         } else {
@@ -374,17 +401,36 @@ impl SpriteFetcher {
             }
         };
 
-        let palette = attrs & 0b00000111;
+        // Dmg games pick between OBP0 and OBP1 instead of the cgb palettes
+        let is_dmg_mode = ppu.memory.borrow().is_dmg_mode();
+        let palette = if is_dmg_mode {
+            (attrs & 0b00010000) >> 4
+        } else {
+            attrs & 0b00000111
+        };
         let bg_prio = if attrs & 0b10000000 != 0 { true } else { false };
         let sprite_prio = ((sprite_address - 0xFE00) / 4) as u8;
-        for _ in 0..8 {
-            let color = pop_pixel().unwrap();
-            ppu.object_pixel_queue.push_back(ObjectPixel {
-                color,
+        for i in 0..8 {
+            let pixel = ObjectPixel {
+                color: pop_pixel().unwrap(),
                 palette,
                 sprite_prio,
                 bg_prio,
-            })
+            };
+            match ppu.object_pixel_queue.get_mut(i) {
+                // Objects are fetched from left to right so on a dmg the one already queued
+                // always wins, on a cgb the one first in oam wins unless it's transparent there
+                Some(queued) => {
+                    let replaces_queued = queued.color == 0
+                        || (pixel.color != 0
+                            && !is_dmg_mode
+                            && pixel.sprite_prio < queued.sprite_prio);
+                    if replaces_queued {
+                        *queued = pixel;
+                    }
+                }
+                None => ppu.object_pixel_queue.push_back(pixel),
+            }
         }
     }
 
@@ -393,11 +439,66 @@ impl SpriteFetcher {
     }
 }
 
+impl SaveState for BackgroundFetcher {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.tilemap_col);
+        state.write_u32(self.current_dots);
+        state.write_u8(self.stage as u8);
+        state.write_option_u8(self.tile_index);
+        state.write_option_u8(self.tile_attrs);
+        state.write_option_u8(self.tile_data_low);
+        state.write_option_u8(self.tile_data_high);
+        state.write_bool(self.in_window);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.tilemap_col = state.read_u8()?;
+        self.current_dots = state.read_u32()?;
+        self.stage = FetcherStage::from_u8(state.read_u8()?)?;
+        self.tile_index = state.read_option_u8()?;
+        self.tile_attrs = state.read_option_u8()?;
+        self.tile_data_low = state.read_option_u8()?;
+        self.tile_data_high = state.read_option_u8()?;
+        self.in_window = state.read_bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for SpriteFetcher {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.stage as u8);
+        state.write_u8(self.current_dots);
+        state.write_option_u16(self.current_sprite);
+        state.write_u8(self.pending_sprites.len() as u8);
+        for sprite_address in &self.pending_sprites {
+            state.write_u16(*sprite_address);
+        }
+        state.write_option_u8(self.tile_index);
+        state.write_option_u8(self.tile_data_low);
+        state.write_option_u8(self.tile_data_high);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.stage = FetcherStage::from_u8(state.read_u8()?)?;
+        self.current_dots = state.read_u8()?;
+        self.current_sprite = state.read_option_u16()?;
+        self.pending_sprites.clear();
+        for _ in 0..state.read_u8()? {
+            self.pending_sprites.push_back(state.read_u16()?);
+        }
+        self.tile_index = state.read_option_u8()?;
+        self.tile_data_low = state.read_option_u8()?;
+        self.tile_data_high = state.read_option_u8()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::{cell::RefCell, rc::Rc};
 
-    use crate::{mem_manager::MemManager, registers::LY_ADDRESS};
+    use crate::mem_manager::{MemManager, Model};
+    use crate::registers::LY_ADDRESS;
 
     use super::*;
 
@@ -749,4 +850,66 @@ mod tests {
     //         assert_ne!(draw.bg_fetcher.sprites_to_fetch[i], 0xFE00);
     //     }
     // }
+
+    fn fetch_object_at(ppu: &mut PPU, fetcher: &mut SpriteFetcher, obj_index: u16, color: u8) {
+        let address = 0xFE00 + obj_index * 4;
+        ppu.memory.borrow_mut().write(address, 16);
+        ppu.memory.borrow_mut().write(address + 2, obj_index as u8);
+        // Every row of the tile uses the same color
+        let tile = 0x8000 + obj_index * 16;
+        for row in 0..8 {
+            ppu.memory
+                .borrow_mut()
+                .write(tile + row * 2, 0xFF * (color & 1));
+            ppu.memory
+                .borrow_mut()
+                .write(tile + row * 2 + 1, 0xFF * (color >> 1));
+        }
+        fetcher.start_fetch(address);
+        while fetcher.has_sprite_queued() {
+            fetcher.tick(ppu);
+        }
+    }
+
+    #[test]
+    fn first_object_in_oam_wins_on_cgb() {
+        let mut ppu = get_test_ppu();
+        let mut fetcher = SpriteFetcher::new();
+        fetch_object_at(&mut ppu, &mut fetcher, 1, 1);
+        fetch_object_at(&mut ppu, &mut fetcher, 0, 2);
+        assert!(ppu.object_pixel_queue.iter().all(|pixel| pixel.color == 2));
+    }
+
+    #[test]
+    fn transparent_object_first_in_oam_shows_the_one_behind_on_cgb() {
+        let mut ppu = get_test_ppu();
+        let mut fetcher = SpriteFetcher::new();
+        fetch_object_at(&mut ppu, &mut fetcher, 1, 1);
+        fetch_object_at(&mut ppu, &mut fetcher, 0, 0);
+        assert!(ppu.object_pixel_queue.iter().all(|pixel| pixel.color == 1));
+    }
+
+    #[test]
+    fn object_queued_first_wins_on_dmg() {
+        let mut ppu = get_test_ppu();
+        ppu.memory.borrow_mut().set_model(Model::DMG);
+        let mut fetcher = SpriteFetcher::new();
+        fetch_object_at(&mut ppu, &mut fetcher, 1, 1);
+        fetch_object_at(&mut ppu, &mut fetcher, 0, 2);
+        assert!(ppu.object_pixel_queue.iter().all(|pixel| pixel.color == 1));
+    }
+
+    #[test]
+    fn objects_at_same_position_are_all_fetched() {
+        let mut ppu = get_test_ppu();
+        let mut fetcher = SpriteFetcher::new();
+        fetcher.start_fetch(0xFE00);
+        fetcher.start_fetch(0xFE04);
+        let mut dots = 0;
+        while fetcher.has_sprite_queued() {
+            fetcher.tick(&mut ppu);
+            dots += 1;
+        }
+        assert_eq!(dots, 14);
+    }
 }
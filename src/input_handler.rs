@@ -1,13 +1,30 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 
+use crate::mbc::mbc7::TiltSource;
 use crate::mem_manager::MemManager;
 use crate::memory::Memory;
 use crate::registers::JOYP_ADDRESS;
 
+pub enum StateRequest {
+    Save(u8),
+    Load(u8),
+}
+
+// Tips the cartridge all the way towards whichever arrow keys are held
+struct KeyboardTilt {
+    tilt: Rc<Cell<(f32, f32)>>,
+}
+
+impl TiltSource for KeyboardTilt {
+    fn get_tilt(&self) -> (f32, f32) {
+        self.tilt.get()
+    }
+}
+
 pub struct InputHandler {
     memory: Rc<RefCell<MemManager>>,
     action_selected: bool,
@@ -15,6 +32,12 @@ pub struct InputHandler {
     action_input: u8,
     direction_input: u8,
     throttled: bool,
+    quit_requested: bool,
+    state_slot: u8,
+    state_request: Option<StateRequest>,
+    cheats_toggle_requested: bool,
+    debug_break_requested: bool,
+    tilt: Rc<Cell<(f32, f32)>>,
 }
 
 impl InputHandler {
@@ -26,6 +49,12 @@ impl InputHandler {
             action_input: 0x0F,
             direction_input: 0x0F,
             throttled: false,
+            quit_requested: false,
+            state_slot: 1,
+            state_request: None,
+            cheats_toggle_requested: false,
+            debug_break_requested: false,
+            tilt: Rc::new(Cell::new((0.0, 0.0))),
         };
         input.memory.borrow_mut().force_write(JOYP_ADDRESS, 0xFF);
         input
@@ -40,6 +69,41 @@ impl InputHandler {
         self.throttled
     }
 
+    pub fn is_quit_requested(&self) -> bool {
+        self.quit_requested
+    }
+
+    // Active low direction and action nibbles in the same layout the joypad register uses
+    pub fn get_held_buttons(&self) -> (u8, u8) {
+        (self.direction_input, self.action_input)
+    }
+
+    // Hands out the most recent save state hotkey press, if there is one
+    pub fn take_state_request(&mut self) -> Option<StateRequest> {
+        self.state_request.take()
+    }
+
+    pub fn take_cheats_toggle_request(&mut self) -> bool {
+        std::mem::take(&mut self.cheats_toggle_requested)
+    }
+
+    pub fn take_debug_break_request(&mut self) -> bool {
+        std::mem::take(&mut self.debug_break_requested)
+    }
+
+    pub fn get_tilt_source(&self) -> Box<dyn TiltSource> {
+        Box::new(KeyboardTilt {
+            tilt: self.tilt.clone(),
+        })
+    }
+
+    fn update_tilt(&mut self) {
+        let is_held = |bit: u8| (self.direction_input & (1 << bit) == 0) as i8 as f32;
+        let x = is_held(0) - is_held(1);
+        let y = is_held(3) - is_held(2);
+        self.tilt.set((x, y));
+    }
+
     fn check_action_or_dir(&mut self) {
         let joyp = self.memory.borrow().read(JOYP_ADDRESS);
         self.action_selected = joyp & 0b00100000 == 0;
@@ -66,11 +130,12 @@ impl InputHandler {
             | Event::KeyDown {
                 keycode: Some(Keycode::Escape),
                 ..
-            } => std::process::exit(0),
+            } => self.quit_requested = true,
             Event::KeyDown {
                 keycode: Some(k), ..
             } => {
                 self.handle_keydown(k);
+                self.update_tilt();
                 // Queue interrupt
                 let if_address = 0xFF0F;
                 let if_value = self.memory.borrow().read(if_address);
@@ -82,6 +147,7 @@ impl InputHandler {
                 keycode: Some(k), ..
             } => {
                 self.handle_keyup(k);
+                self.update_tilt();
             }
             _ => {}
         }
@@ -98,6 +164,19 @@ impl InputHandler {
             Keycode::Backspace => self.action_input &= 0b11111011,
             Keycode::Up => self.direction_input &= 0b11111011,
             Keycode::Space => self.throttled = !self.throttled,
+            Keycode::F5 => self.state_request = Some(StateRequest::Save(self.state_slot)),
+            Keycode::F9 => self.state_request = Some(StateRequest::Load(self.state_slot)),
+            Keycode::F2 => self.cheats_toggle_requested = true,
+            Keycode::F12 => self.debug_break_requested = true,
+            Keycode::Num1 => self.state_slot = 1,
+            Keycode::Num2 => self.state_slot = 2,
+            Keycode::Num3 => self.state_slot = 3,
+            Keycode::Num4 => self.state_slot = 4,
+            Keycode::Num5 => self.state_slot = 5,
+            Keycode::Num6 => self.state_slot = 6,
+            Keycode::Num7 => self.state_slot = 7,
+            Keycode::Num8 => self.state_slot = 8,
+            Keycode::Num9 => self.state_slot = 9,
             _ => (),
         }
     }
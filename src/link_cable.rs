@@ -0,0 +1,386 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use crate::serial::SerialPeer;
+
+const TRANSFER_MESSAGE: u8 = 0;
+const REPLY_MESSAGE: u8 = 1;
+const SYNC_MESSAGE: u8 = 2;
+// Kind, sequence, data and the dots the sender had run
+const MESSAGE_SIZE: usize = 11;
+// How long the clocking side waits for the other emulator before giving up on a byte
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+// What the clocking side reads when the other side doesn't answer
+const NO_DATA: u8 = 0xFF;
+// Each side sends how far it has run four times a frame and waits for the other once it gets
+// more than a frame ahead
+const DOTS_PER_FRAME: u64 = 70224;
+const SYNC_INTERVAL: u64 = DOTS_PER_FRAME / 4;
+const MAX_LEAD: u64 = DOTS_PER_FRAME;
+// How long the side that got ahead waits at a time before handing control back to the frontend
+const WAIT_INTERVAL: Duration = Duration::from_millis(10);
+
+// Every transfer is numbered and its reply repeats the number, so a reply that shows up after
+// its transfer timed out isn't mistaken for the reply to the next one. Every message carries
+// the dots its sender had run when it was sent.
+enum Message {
+    Transfer { sequence: u8, data: u8, time: u64 },
+    Reply { sequence: u8, data: u8, time: u64 },
+    Sync { time: u64 },
+}
+
+// Connects the serial port to another emulator over tcp
+//
+// Both emulators count the dots they have run and keep within a frame of each other, the one
+// that gets ahead waits until it hears the other has caught up. Pausing one of them holds up
+// the other as well.
+//
+// The side driving the clock stamps its byte with its dot count and waits for the other side's
+// byte before its transfer starts shifting. The other side answers with its serial register once
+// it has run up to that count, or straight away if it's already past it, which is at most a frame
+// late. If it has a transfer waiting on the external clock that transfer completes too.
+pub struct LinkCable {
+    stream: TcpStream,
+    incoming: Receiver<Message>,
+    connected: bool,
+    sequence: u8,
+    time: u64,
+    peer_time: u64,
+    next_sync: u64,
+    // The other side's last transfer until this side has run up to when it was sent
+    pending: Option<(u8, u8, u64)>,
+}
+
+impl LinkCable {
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind(("0.0.0.0", port))?;
+        println!("Waiting for link cable connection on port {port}");
+        let (stream, address) = listener.accept()?;
+        println!("Link cable connected to {address}");
+        LinkCable::from_stream(stream)
+    }
+
+    pub fn connect(address: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        println!("Link cable connected to {address}");
+        LinkCable::from_stream(stream)
+    }
+
+    fn from_stream(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        // Messages are read on their own thread so polling never has to touch the socket
+        let reader = stream.try_clone()?;
+        let (sender, incoming) = mpsc::channel();
+        thread::spawn(move || read_messages(reader, sender));
+        Ok(LinkCable {
+            stream,
+            incoming,
+            connected: true,
+            sequence: 0,
+            time: 0,
+            peer_time: 0,
+            next_sync: 0,
+            pending: None,
+        })
+    }
+
+    fn send(&mut self, message: Message) {
+        if !self.connected {
+            return;
+        }
+        let (kind, sequence, data, time) = match message {
+            Message::Transfer {
+                sequence,
+                data,
+                time,
+            } => (TRANSFER_MESSAGE, sequence, data, time),
+            Message::Reply {
+                sequence,
+                data,
+                time,
+            } => (REPLY_MESSAGE, sequence, data, time),
+            Message::Sync { time } => (SYNC_MESSAGE, 0, 0, time),
+        };
+        let mut bytes = [0; MESSAGE_SIZE];
+        bytes[..3].copy_from_slice(&[kind, sequence, data]);
+        bytes[3..].copy_from_slice(&time.to_le_bytes());
+        if let Err(e) = self.stream.write_all(&bytes) {
+            println!("Link cable disconnected: {e}");
+            self.connected = false;
+        }
+    }
+
+    fn disconnect(&mut self) {
+        if self.connected {
+            println!("Link cable disconnected");
+            self.connected = false;
+        }
+    }
+
+    // Transfers are held until this side has run up to when they were sent
+    fn receive(&mut self, message: Message) {
+        let time = match message {
+            Message::Transfer {
+                sequence,
+                data,
+                time,
+            } => {
+                self.pending = Some((sequence, data, time));
+                time
+            }
+            // Replies that arrive after a timeout are stale and get dropped
+            Message::Reply { time, .. } | Message::Sync { time } => time,
+        };
+        self.peer_time = self.peer_time.max(time);
+    }
+
+    // Runs this side's clock forward, answers the other side's transfer once it's due and waits a
+    // little while this side is too far ahead. Returns the other side's byte if this side was
+    // listening.
+    fn exchange(&mut self, data: u8, dots: u32, listening: bool) -> Option<u8> {
+        self.time += dots as u64;
+        if self.time >= self.next_sync {
+            self.send(Message::Sync { time: self.time });
+            self.next_sync = self.time + SYNC_INTERVAL;
+        }
+        loop {
+            if let Some((sequence, incoming, sent_at)) = self.pending {
+                if sent_at <= self.time {
+                    self.pending = None;
+                    self.send(Message::Reply {
+                        sequence,
+                        data,
+                        time: self.time,
+                    });
+                    if listening {
+                        return Some(incoming);
+                    }
+                }
+            }
+
+            let message = if self.is_waiting() {
+                match self.incoming.recv_timeout(WAIT_INTERVAL) {
+                    Ok(message) => Ok(message),
+                    // The frontend keeps handling events and tries again
+                    Err(RecvTimeoutError::Timeout) => return None,
+                    Err(RecvTimeoutError::Disconnected) => Err(TryRecvError::Disconnected),
+                }
+            } else {
+                self.incoming.try_recv()
+            };
+            match message {
+                Ok(message) => self.receive(message),
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => {
+                    self.disconnect();
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for LinkCable {
+    // Wakes up the reader thread and lets the other side know the cable was unplugged
+    fn drop(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+fn read_messages(mut stream: TcpStream, sender: Sender<Message>) {
+    let mut buffer = [0; MESSAGE_SIZE];
+    while stream.read_exact(&mut buffer).is_ok() {
+        let (sequence, data) = (buffer[1], buffer[2]);
+        let time = u64::from_le_bytes(buffer[3..].try_into().unwrap());
+        let message = match buffer[0] {
+            TRANSFER_MESSAGE => Message::Transfer {
+                sequence,
+                data,
+                time,
+            },
+            REPLY_MESSAGE => Message::Reply {
+                sequence,
+                data,
+                time,
+            },
+            SYNC_MESSAGE => Message::Sync { time },
+            _ => break,
+        };
+        if sender.send(message).is_err() {
+            break;
+        }
+    }
+}
+
+impl SerialPeer for LinkCable {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        // The other side is waiting on a transfer it clocked too, so neither one is listening
+        if let Some((sequence, _, _)) = self.pending.take() {
+            self.send(Message::Reply {
+                sequence,
+                data: NO_DATA,
+                time: self.time,
+            });
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        let sequence = self.sequence;
+        self.send(Message::Transfer {
+            sequence,
+            data: outgoing,
+            time: self.time,
+        });
+        while self.connected {
+            match self.incoming.recv_timeout(REPLY_TIMEOUT) {
+                Ok(Message::Reply {
+                    sequence: reply,
+                    data,
+                    time,
+                }) if reply == sequence => {
+                    self.peer_time = self.peer_time.max(time);
+                    return data;
+                }
+                Ok(Message::Transfer { sequence, time, .. }) => {
+                    self.peer_time = self.peer_time.max(time);
+                    self.send(Message::Reply {
+                        sequence,
+                        data: NO_DATA,
+                        time: self.time,
+                    });
+                }
+                Ok(message) => self.receive(message),
+                Err(RecvTimeoutError::Timeout) => return NO_DATA,
+                Err(RecvTimeoutError::Disconnected) => self.disconnect(),
+            }
+        }
+        NO_DATA
+    }
+
+    fn poll_external(&mut self, outgoing: u8, dots: u32) -> Option<u8> {
+        self.exchange(outgoing, dots, true)
+    }
+
+    fn idle(&mut self, data: u8, dots: u32) {
+        self.exchange(data, dots, false);
+    }
+
+    fn is_waiting(&self) -> bool {
+        self.connected && self.time > self.peer_time + MAX_LEAD
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    const DOTS_PER_POLL: u32 = 456;
+
+    fn get_connected_cables() -> (LinkCable, LinkCable) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = TcpStream::connect(address).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (
+            LinkCable::from_stream(server).unwrap(),
+            LinkCable::from_stream(client).unwrap(),
+        )
+    }
+
+    // Runs the listening side until a byte comes in, returns it with the dots that side had run
+    fn spawn_listener(mut slave: LinkCable, outgoing: u8) -> thread::JoinHandle<(u8, u64)> {
+        thread::spawn(move || loop {
+            if let Some(data) = slave.poll_external(outgoing, DOTS_PER_POLL) {
+                return (data, slave.time);
+            }
+            thread::sleep(Duration::from_micros(100));
+        })
+    }
+
+    #[test]
+    fn bytes_are_exchanged_with_listening_side() {
+        let (mut master, slave) = get_connected_cables();
+        let slave_thread = spawn_listener(slave, 0x34);
+        assert_eq!(master.transfer(0x12), 0x34);
+        assert_eq!(slave_thread.join().unwrap().0, 0x12);
+    }
+
+    #[test]
+    fn late_reply_is_not_taken_for_the_next_one() {
+        let (mut master, mut slave) = get_connected_cables();
+        slave.send(Message::Reply {
+            sequence: 0,
+            data: 0x56,
+            time: 0,
+        });
+        let slave_thread = spawn_listener(slave, 0x34);
+        assert_eq!(master.transfer(0x12), 0x34);
+        assert_eq!(slave_thread.join().unwrap().0, 0x12);
+    }
+
+    #[test]
+    fn transfer_is_answered_once_the_other_side_catches_up() {
+        let (mut master, slave) = get_connected_cables();
+        master.idle(0xFF, 20000);
+        let slave_thread = spawn_listener(slave, 0x34);
+        assert_eq!(master.transfer(0x12), 0x34);
+        let (data, slave_time) = slave_thread.join().unwrap();
+        assert_eq!(data, 0x12);
+        assert!(slave_time >= 20000);
+    }
+
+    #[test]
+    fn side_that_is_not_listening_answers_with_its_register() {
+        let (mut master, mut slave) = get_connected_cables();
+        let slave_thread = thread::spawn(move || {
+            // Stays within a frame of the master, which doesn't move while it waits
+            while slave.time < MAX_LEAD / 2 {
+                slave.idle(0x56, DOTS_PER_POLL);
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+        let start = Instant::now();
+        assert_eq!(master.transfer(0x12), 0x56);
+        assert!(start.elapsed() < REPLY_TIMEOUT);
+        slave_thread.join().unwrap();
+    }
+
+    #[test]
+    fn side_that_gets_ahead_waits_for_the_other() {
+        let (mut master, mut slave) = get_connected_cables();
+        let master_thread = thread::spawn(move || {
+            master.idle(0xFF, MAX_LEAD as u32 + 1000);
+            while master.is_waiting() {
+                master.idle(0xFF, 0);
+            }
+            master.time
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!master_thread.is_finished());
+        slave.idle(0xFF, MAX_LEAD as u32);
+        assert_eq!(master_thread.join().unwrap(), MAX_LEAD + 1000);
+    }
+
+    #[test]
+    fn waiting_side_returns_while_the_other_is_silent() {
+        let (mut master, _slave) = get_connected_cables();
+        let start = Instant::now();
+        assert_eq!(master.poll_external(0xFF, MAX_LEAD as u32 + 1000), None);
+        assert!(start.elapsed() < REPLY_TIMEOUT);
+        assert!(master.is_waiting());
+        master.idle(0xFF, 0);
+        assert!(master.is_waiting());
+    }
+
+    #[test]
+    fn transfer_after_disconnect_reads_no_data() {
+        let (mut master, slave) = get_connected_cables();
+        drop(slave);
+        assert_eq!(master.transfer(0x12), NO_DATA);
+        // Nothing is left to wait for
+        master.idle(0xFF, MAX_LEAD as u32 * 2);
+    }
+}
@@ -1,16 +1,33 @@
-use clap::Parser;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, Subcommand};
+use disasm::rom::RomDisassembly;
 use emulator::Emulator;
+use link_cable::LinkCable;
+use mbc::camera::ImageFiles;
+use mem_manager::Model;
+use ppu::DMGPalette;
 
+mod apu;
+mod cartridge;
+mod cheats;
+mod compat_palettes;
 mod cpu;
+mod debugger;
+mod disasm;
 mod dma_controller;
 mod emulator;
 mod fetcher;
 mod input_handler;
+mod link_cable;
 mod mbc;
 mod mem_manager;
 mod memory;
 mod ppu;
 mod registers;
+mod save_state;
+mod serial;
 mod timer;
 
 // const SPHL_PATH: &str = "src/test_roms/sphl.gb";
@@ -31,11 +48,109 @@ const CPU_ROM_PATH: &str = "src/test_roms/cpu_full.gb";
 struct Args {
     #[arg(short, long, default_value = CPU_ROM_PATH)]
     rom_path: String,
+    /// Console to emulate
+    #[arg(long, value_enum, default_value = "cgb")]
+    model: Model,
+    /// Shades the screen is drawn with when emulating a DMG
+    #[arg(long, value_enum, default_value = "grey")]
+    dmg_palette: DMGPalette,
+    /// Run a DMG (256 byte) or CGB (2304 byte) boot rom before the game
+    #[arg(long, value_name = "FILE")]
+    boot_rom: Option<String>,
+    /// Wait for another emulator to connect a link cable on this port
+    #[arg(long, value_name = "PORT", conflicts_with = "link_connect")]
+    link_listen: Option<u16>,
+    /// Connect a link cable to another emulator that is listening
+    #[arg(long, value_name = "HOST:PORT")]
+    link_connect: Option<String>,
+    /// Picture (PNG or PGM) the Game Boy Camera sees, repeat to cycle through several
+    #[arg(long, value_name = "FILE")]
+    camera_image: Vec<PathBuf>,
+    /// Start paused in the debugger, which reads commands from the terminal
+    #[arg(long)]
+    debug: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write a rom out as RGBDS source, one file per bank, instead of running it
+    Disasm {
+        rom: PathBuf,
+        /// Directory the bank_XX.asm files are written to
+        #[arg(short, long, value_name = "DIR")]
+        output: PathBuf,
+    },
+}
+
+fn disassemble_rom(rom_path: &Path, output: &Path) -> std::io::Result<usize> {
+    let rom = fs::read(rom_path)?;
+    let disassembly = RomDisassembly::new(&rom)?;
+    let rom_name = rom_path.file_name().unwrap_or_default().to_string_lossy();
+    disassembly.write_files(output, &rom_name)?;
+    Ok(disassembly.get_bank_count())
 }
 
 fn main() {
-    let mut emulator = Emulator::new();
     let args = Args::parse();
+    if let Some(Command::Disasm { rom, output }) = args.command {
+        match disassemble_rom(&rom, &output) {
+            Ok(banks) => println!("Wrote {banks} banks to {}", output.display()),
+            Err(e) => println!("Couldn't disassemble rom: {e}"),
+        }
+        return;
+    }
+
+    let mut emulator = match args.boot_rom {
+        Some(ref path) => match fs::read(path) {
+            Ok(boot_rom) if boot_rom.len() == 0x100 || boot_rom.len() == 0x900 => {
+                Emulator::with_boot_rom(args.model, boot_rom)
+            }
+            Ok(boot_rom) => {
+                println!(
+                    "Boot rom should be 256 or 2304 bytes, not {}",
+                    boot_rom.len()
+                );
+                return;
+            }
+            Err(e) => {
+                println!("Couldn't load boot rom: {e}");
+                return;
+            }
+        },
+        None => Emulator::new(args.model),
+    };
+
+    emulator.set_dmg_palette(args.dmg_palette);
+
+    let link_cable = if let Some(port) = args.link_listen {
+        Some(LinkCable::listen(port))
+    } else {
+        args.link_connect.as_deref().map(LinkCable::connect)
+    };
+    match link_cable {
+        Some(Ok(link_cable)) => emulator.set_serial_peer(Box::new(link_cable)),
+        Some(Err(e)) => {
+            println!("Couldn't connect link cable: {e}");
+            return;
+        }
+        None => (),
+    }
+
+    if !args.camera_image.is_empty() {
+        match ImageFiles::load(&args.camera_image) {
+            Ok(images) => emulator.set_image_source(Box::new(images)),
+            Err(e) => {
+                println!("Couldn't load camera image: {e}");
+                return;
+            }
+        }
+    }
+
+    if args.debug {
+        emulator.enable_debugger();
+    }
 
     emulator.load_and_run(&args.rom_path);
 }
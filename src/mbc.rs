@@ -1,10 +1,83 @@
+use crate::mbc::camera::ImageSource;
+use crate::mbc::mbc7::TiltSource;
 use crate::memory::Memory;
+use crate::save_state::{SaveState, StateError, StateReader};
 
+pub mod camera;
+pub mod huc1;
+pub mod huc3;
 pub mod mbc1;
+pub mod mbc2;
 pub mod mbc3;
 pub mod mbc5;
+pub mod mbc7;
+pub mod mmm01;
+pub mod rom_ram;
+
+const RAM_BANK_SIZE: usize = 0x2000;
 
 // Interface for Memory Bank Controllers
-pub trait MBC: Memory {
+// Save states cover the banking registers and ram but not the rom, which is reloaded from the file
+pub trait MBC: Memory + SaveState {
     fn init(&mut self, program: &Vec<u8>);
+    // Copies out the external ram so it can be persisted for battery backed cartridges
+    fn dump_ram(&self) -> Vec<u8>;
+    // Restores external ram from previously dumped data
+    fn load_ram(&mut self, data: &[u8]);
+    // Bank of the rom that is mapped where the address falls
+    fn get_mapped_rom_bank(&self, address: u16) -> usize;
+    // Bytes at the end of the dumped ram that only record when it was dumped, a save isn't
+    // rewritten just because they changed
+    fn get_timestamp_size(&self) -> usize {
+        0
+    }
+    // Advances hardware on the cartridge that runs off the clock
+    fn update(&mut self, _cycles: u32) {}
+    // Connects cartridges with an accelerometer to whatever drives it
+    fn set_tilt_source(&mut self, _source: Box<dyn TiltSource>) {}
+    // Connects cartridges with an image sensor to the pictures it should see
+    fn set_image_source(&mut self, _source: Box<dyn ImageSource>) {}
+    // Level of a speaker on the cartridge, mixed into the console's own audio
+    fn get_audio_output(&self) -> f32 {
+        0.0
+    }
+}
+
+// Checks the cartridge type in the header for a battery that keeps external ram alive
+pub fn has_battery(cartridge_type: u8) -> bool {
+    matches!(
+        cartridge_type,
+        0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFC | 0xFE | 0xFF
+    )
+}
+
+// Shared helpers for controllers that store the rom as a list of banks and ram as one block
+pub(crate) fn load_rom_banks<const N: usize>(banks: &mut [[u8; N]], program: &[u8]) {
+    for (bank, chunk) in banks.iter_mut().zip(program.chunks(N)) {
+        bank[..chunk.len()].copy_from_slice(chunk);
+    }
+}
+
+// Finds the byte an access to 0xA000-0xBFFF lands on. Bank numbers past the end of the chip
+// wrap around since the upper address lines aren't connected, and chips smaller than a bank
+// are mirrored.
+pub(crate) fn get_ram_offset(ram: &[u8], bank: usize, address: u16) -> Option<usize> {
+    if ram.is_empty() {
+        return None;
+    }
+    Some((bank * RAM_BANK_SIZE + (address - 0xA000) as usize) % ram.len())
+}
+
+pub(crate) fn load_ram_data(ram: &mut [u8], data: &[u8]) {
+    let length = data.len().min(ram.len());
+    ram[..length].copy_from_slice(&data[..length]);
+}
+
+pub(crate) fn load_ram_state(ram: &mut [u8], state: &mut StateReader) -> Result<(), StateError> {
+    let data = state.read_vec()?;
+    if data.len() != ram.len() {
+        return Err(StateError::Invalid("cartridge ram size"));
+    }
+    ram.copy_from_slice(&data);
+    Ok(())
 }
@@ -0,0 +1,504 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+use crate::mbc::{get_ram_offset, load_ram_data, load_ram_state, load_rom_banks, MBC};
+use crate::memory::Memory;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+// Every camera has the same 128 KB of ram whatever the header says, pictures are written to it
+const RAM_SIZE: usize = 0x20000;
+
+// Only the top 112 of the sensor's 128 rows end up in the picture
+pub const SENSOR_WIDTH: usize = 128;
+pub const SENSOR_HEIGHT: usize = 112;
+// Grey the sensor sees when nothing is feeding it images
+const EMPTY_SENSOR_VALUE: u8 = 0x80;
+
+// Selecting a ram bank with this bit set maps the sensor registers instead
+const REGISTER_BANK_BIT: u8 = 0b00010000;
+const REGISTER_COUNT: usize = 0x36;
+const SHOOT_REGISTER: usize = 0x00;
+const GAIN_REGISTER: usize = 0x01;
+const EXPOSURE_HIGH_REGISTER: usize = 0x02;
+const EXPOSURE_LOW_REGISTER: usize = 0x03;
+const EDGE_REGISTER: usize = 0x04;
+// Three thresholds for each position of a 4x4 matrix
+const DITHER_MATRIX_REGISTER: usize = 0x06;
+
+const SHOOT_BIT: u8 = 0b00000001;
+const N_BIT: u8 = 0b10000000;
+const INVERT_BIT: u8 = 0b00001000;
+
+// Exposure at which the sensor passes its input straight through
+const EXPOSURE_REFERENCE: u32 = 0x1000;
+// The picture is written as tiles to the start of ram bank 0 after a header
+const PICTURE_ADDRESS: usize = 0x0100;
+
+// Supplies what the sensor sees each time a picture is taken, as SENSOR_WIDTH * SENSOR_HEIGHT
+// greyscale values where 0 is black
+pub trait ImageSource {
+    fn capture(&mut self) -> Vec<u8>;
+}
+
+// Shows the camera one image after another and starts over once they run out
+pub struct ImageFiles {
+    images: Vec<Vec<u8>>,
+    next: usize,
+}
+
+impl ImageFiles {
+    pub fn load(paths: &[PathBuf]) -> io::Result<Self> {
+        let images = paths
+            .iter()
+            .map(|path| load_image(path))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(ImageFiles { images, next: 0 })
+    }
+}
+
+impl ImageSource for ImageFiles {
+    fn capture(&mut self) -> Vec<u8> {
+        if self.images.is_empty() {
+            return vec![EMPTY_SENSOR_VALUE; SENSOR_WIDTH * SENSOR_HEIGHT];
+        }
+        let image = self.images[self.next].clone();
+        self.next = (self.next + 1) % self.images.len();
+        image
+    }
+}
+
+fn invalid_data(message: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Loads a png or binary pgm and scales it to fill the sensor
+fn load_image(path: &Path) -> io::Result<Vec<u8>> {
+    let is_pgm = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("pgm"));
+    let (width, height, pixels) = if is_pgm {
+        decode_pgm(&fs::read(path)?)?
+    } else {
+        decode_png(path)?
+    };
+    if width == 0 || height == 0 {
+        return Err(invalid_data("image is empty"));
+    }
+
+    let mut scaled = Vec::with_capacity(SENSOR_WIDTH * SENSOR_HEIGHT);
+    for y in 0..SENSOR_HEIGHT {
+        for x in 0..SENSOR_WIDTH {
+            let source_x = x * width / SENSOR_WIDTH;
+            let source_y = y * height / SENSOR_HEIGHT;
+            scaled.push(pixels[source_y * width + source_x]);
+        }
+    }
+    Ok(scaled)
+}
+
+fn decode_png(path: &Path) -> io::Result<(usize, usize, Vec<u8>)> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(invalid_data)?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(invalid_data)?;
+
+    let channels = info.color_type.samples();
+    let pixels = buffer[..info.buffer_size()]
+        .chunks(channels)
+        .map(|pixel| match info.color_type {
+            png::ColorType::Rgb | png::ColorType::Rgba => {
+                let luma = 299 * pixel[0] as u32 + 587 * pixel[1] as u32 + 114 * pixel[2] as u32;
+                (luma / 1000) as u8
+            }
+            _ => pixel[0],
+        })
+        .collect();
+    Ok((info.width as usize, info.height as usize, pixels))
+}
+
+fn decode_pgm(data: &[u8]) -> io::Result<(usize, usize, Vec<u8>)> {
+    // Header fields are separated by whitespace and can have comments between them
+    let mut position = 0;
+    let mut fields = Vec::new();
+    while fields.len() < 4 {
+        while position < data.len() && data[position].is_ascii_whitespace() {
+            position += 1;
+        }
+        if position < data.len() && data[position] == b'#' {
+            while position < data.len() && data[position] != b'\n' {
+                position += 1;
+            }
+            continue;
+        }
+        let start = position;
+        while position < data.len() && !data[position].is_ascii_whitespace() {
+            position += 1;
+        }
+        if start == position {
+            return Err(invalid_data("pgm header is truncated"));
+        }
+        fields.push(String::from_utf8_lossy(&data[start..position]).into_owned());
+    }
+    // A single whitespace byte separates the header from the pixels
+    position += 1;
+
+    if fields[0] != "P5" {
+        return Err(invalid_data("only binary pgm files are supported"));
+    }
+    let parse = |field: &str| {
+        field
+            .parse::<usize>()
+            .map_err(|_| invalid_data("pgm header has a bad number"))
+    };
+    let width = parse(&fields[1])?;
+    let height = parse(&fields[2])?;
+    let max_value = parse(&fields[3])?;
+    if max_value == 0 || max_value > 255 {
+        return Err(invalid_data("only 8 bit pgm files are supported"));
+    }
+    let end = width
+        .checked_mul(height)
+        .and_then(|size| size.checked_add(position))
+        .ok_or_else(|| invalid_data("pgm is too large"))?;
+    if data.len() < end {
+        return Err(invalid_data("pgm is truncated"));
+    }
+    let pixels = data[position..end]
+        .iter()
+        .map(|&value| (value as usize * 255 / max_value) as u8)
+        .collect();
+    Ok((width, height, pixels))
+}
+
+pub struct Camera {
+    rom: Vec<[u8; ROM_BANK_SIZE]>,
+    ram: Vec<u8>,
+    // Ram can always be read, this only guards writes
+    ram_enabled: bool,
+    rom_bank_index: u8,
+    ram_bank_index: u8,
+    registers: [u8; REGISTER_COUNT],
+    capture_cycles_left: u32,
+    source: Option<Box<dyn ImageSource>>,
+}
+
+impl Camera {
+    pub fn new(rom_banks: u16) -> Self {
+        Camera {
+            rom: vec![[0; ROM_BANK_SIZE]; rom_banks as usize],
+            ram: vec![0; RAM_SIZE],
+            ram_enabled: false,
+            rom_bank_index: 1,
+            ram_bank_index: 0,
+            registers: [0; REGISTER_COUNT],
+            capture_cycles_left: 0,
+            source: None,
+        }
+    }
+
+    fn are_registers_mapped(&self) -> bool {
+        self.ram_bank_index & REGISTER_BANK_BIT != 0
+    }
+
+    fn get_exposure(&self) -> u32 {
+        u16::from_be_bytes([
+            self.registers[EXPOSURE_HIGH_REGISTER],
+            self.registers[EXPOSURE_LOW_REGISTER],
+        ]) as u32
+    }
+
+    // Longer exposures take longer, turning off the n bit adds a little extra
+    fn get_capture_cycles(&self) -> u32 {
+        let extra = if self.registers[GAIN_REGISTER] & N_BIT != 0 {
+            0
+        } else {
+            512
+        };
+        (32446 + extra + 16 * self.get_exposure()) * 4
+    }
+
+    fn write_register(&mut self, register: usize, data: u8) {
+        if register >= REGISTER_COUNT {
+            return;
+        }
+        if register == SHOOT_REGISTER {
+            let is_shooting = self.registers[SHOOT_REGISTER] & SHOOT_BIT != 0;
+            if data & SHOOT_BIT != 0 && !is_shooting {
+                self.capture_cycles_left = self.get_capture_cycles();
+            } else if data & SHOOT_BIT == 0 {
+                // Clearing the bit cancels the capture
+                self.capture_cycles_left = 0;
+            }
+            self.registers[SHOOT_REGISTER] = data & 0b00000111;
+            return;
+        }
+        self.registers[register] = data;
+    }
+
+    fn finish_capture(&mut self) {
+        self.registers[SHOOT_REGISTER] &= !SHOOT_BIT;
+        let image = match self.source.as_mut() {
+            Some(source) => source.capture(),
+            None => vec![EMPTY_SENSOR_VALUE; SENSOR_WIDTH * SENSOR_HEIGHT],
+        };
+        let exposure = self.get_exposure();
+        let invert = self.registers[EDGE_REGISTER] & INVERT_BIT != 0;
+
+        for y in 0..SENSOR_HEIGHT {
+            for x in 0..SENSOR_WIDTH {
+                let sensed = image[y * SENSOR_WIDTH + x] as u32 * exposure / EXPOSURE_REFERENCE;
+                let mut value = sensed.min(0xFF) as u8;
+                if invert {
+                    value = 0xFF - value;
+                }
+                let color = self.get_dithered_color(x, y, value);
+                self.write_picture_pixel(x, y, color);
+            }
+        }
+    }
+
+    // The thresholds for this position of the matrix split the brightness into four shades
+    fn get_dithered_color(&self, x: usize, y: usize, value: u8) -> u8 {
+        let matrix_position = (y % 4) * 4 + x % 4;
+        let thresholds = &self.registers[DITHER_MATRIX_REGISTER + matrix_position * 3..][..3];
+        if value < thresholds[0] {
+            3
+        } else if value < thresholds[1] {
+            2
+        } else if value < thresholds[2] {
+            1
+        } else {
+            0
+        }
+    }
+
+    fn write_picture_pixel(&mut self, x: usize, y: usize, color: u8) {
+        let tile = (y / 8) * (SENSOR_WIDTH / 8) + x / 8;
+        let address = PICTURE_ADDRESS + tile * 16 + (y % 8) * 2;
+        let bit = 7 - (x % 8);
+        for plane in 0..2 {
+            let byte = &mut self.ram[address + plane];
+            *byte &= !(1 << bit);
+            *byte |= ((color >> plane) & 1) << bit;
+        }
+    }
+
+    fn get_rom_bank(&self) -> usize {
+        self.rom_bank_index as usize % self.rom.len()
+    }
+}
+
+impl Memory for Camera {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            rom_bank_one_address @ 0x0000..=0x3FFF => self.rom[0][rom_bank_one_address as usize],
+            other_rom_banks_address @ 0x4000..=0x7FFF => {
+                self.rom[self.get_rom_bank()][(other_rom_banks_address - 0x4000) as usize]
+            }
+            // Only the shoot register can be read back, the rest read as zero
+            register_address @ 0xA000..=0xBFFF if self.are_registers_mapped() => {
+                if register_address & 0x7F == SHOOT_REGISTER as u16 {
+                    self.registers[SHOOT_REGISTER]
+                } else {
+                    0x00
+                }
+            }
+            external_ram_address @ 0xA000..=0xBFFF => {
+                let bank = (self.ram_bank_index & 0x0F) as usize;
+                match get_ram_offset(&self.ram, bank, external_ram_address) {
+                    Some(offset) => self.ram[offset],
+                    None => 0xFF,
+                }
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            // Ram write enable register
+            0x0000..=0x1FFF => {
+                self.ram_enabled = data & 0b00001111 == 0x0A;
+            }
+            // Rom bank select register, unlike most controllers bank 0 can be selected
+            0x2000..=0x3FFF => {
+                self.rom_bank_index = data & 0b00111111;
+            }
+            // Ram bank select register
+            0x4000..=0x5FFF => {
+                self.ram_bank_index = data & 0b00011111;
+            }
+            register_address @ 0xA000..=0xBFFF if self.are_registers_mapped() => {
+                self.write_register((register_address & 0x7F) as usize, data);
+            }
+            external_ram_address @ 0xA000..=0xBFFF if self.ram_enabled => {
+                let bank = (self.ram_bank_index & 0x0F) as usize;
+                if let Some(offset) = get_ram_offset(&self.ram, bank, external_ram_address) {
+                    self.ram[offset] = data;
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl MBC for Camera {
+    fn init(&mut self, program: &Vec<u8>) {
+        load_rom_banks(&mut self.rom, program);
+    }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        load_ram_data(&mut self.ram, data);
+    }
+
+    fn get_mapped_rom_bank(&self, address: u16) -> usize {
+        if address < 0x4000 {
+            0
+        } else {
+            self.get_rom_bank()
+        }
+    }
+
+    fn update(&mut self, cycles: u32) {
+        if self.capture_cycles_left == 0 {
+            return;
+        }
+        self.capture_cycles_left = self.capture_cycles_left.saturating_sub(cycles);
+        if self.capture_cycles_left == 0 {
+            self.finish_capture();
+        }
+    }
+
+    fn set_image_source(&mut self, source: Box<dyn ImageSource>) {
+        self.source = Some(source);
+    }
+}
+
+impl SaveState for Camera {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_vec(&self.ram);
+        state.write_bool(self.ram_enabled);
+        state.write_u8(self.rom_bank_index);
+        state.write_u8(self.ram_bank_index);
+        state.write_bytes(&self.registers);
+        state.write_u32(self.capture_cycles_left);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_ram_state(&mut self.ram, state)?;
+        self.ram_enabled = state.read_bool()?;
+        self.rom_bank_index = state.read_u8()?;
+        self.ram_bank_index = state.read_u8()?;
+        state.read_bytes(&mut self.registers)?;
+        self.capture_cycles_left = state.read_u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::RAM_BANK_SIZE;
+
+    struct Gradient;
+
+    // Columns get brighter from left to right
+    impl ImageSource for Gradient {
+        fn capture(&mut self) -> Vec<u8> {
+            (0..SENSOR_WIDTH * SENSOR_HEIGHT)
+                .map(|i| (i % SENSOR_WIDTH * 2) as u8)
+                .collect()
+        }
+    }
+
+    fn get_test_camera() -> Camera {
+        let mut camera = Camera::new(2);
+        camera.set_image_source(Box::new(Gradient));
+        camera.write(0x4000, REGISTER_BANK_BIT);
+        camera
+    }
+
+    fn read_pixel(camera: &Camera, x: usize, y: usize) -> u8 {
+        let tile = (y / 8) * (SENSOR_WIDTH / 8) + x / 8;
+        let address = PICTURE_ADDRESS + tile * 16 + (y % 8) * 2;
+        let bit = 7 - (x % 8);
+        let low = (camera.ram[address] >> bit) & 1;
+        let high = (camera.ram[address + 1] >> bit) & 1;
+        (high << 1) | low
+    }
+
+    #[test]
+    fn capture_is_busy_until_exposure_finishes() {
+        let mut camera = get_test_camera();
+        camera.write(0xA001, N_BIT);
+        camera.write(0xA003, 0x10);
+        camera.write(0xA000, SHOOT_BIT);
+        assert_eq!(camera.read(0xA000) & SHOOT_BIT, SHOOT_BIT);
+        assert_eq!(camera.read(0xA002), 0x00);
+
+        camera.update((32446 + 16 * 0x10) * 4 - 1);
+        assert_eq!(camera.read(0xA000) & SHOOT_BIT, SHOOT_BIT);
+        camera.update(1);
+        assert_eq!(camera.read(0xA000) & SHOOT_BIT, 0);
+    }
+
+    #[test]
+    fn picture_is_dithered_into_tiles() {
+        let mut camera = get_test_camera();
+        camera.write(0xA002, (EXPOSURE_REFERENCE >> 8) as u8);
+        for i in 0..16 {
+            camera.write(0xA006 + i * 3, 0x40);
+            camera.write(0xA007 + i * 3, 0x80);
+            camera.write(0xA008 + i * 3, 0xC0);
+        }
+        camera.write(0xA000, SHOOT_BIT);
+        camera.update(camera.capture_cycles_left);
+
+        assert_eq!(read_pixel(&camera, 0, 0), 3);
+        assert_eq!(read_pixel(&camera, 40, 5), 2);
+        assert_eq!(read_pixel(&camera, 80, 50), 1);
+        assert_eq!(read_pixel(&camera, 127, 111), 0);
+
+        // The picture is also visible through the normal ram banks
+        camera.write(0x4000, 0);
+        assert_eq!(camera.read(0xA100), 0xFF);
+    }
+
+    #[test]
+    fn ram_is_128_kb_whatever_the_header_says() {
+        let mut camera = Camera::new(2);
+        camera.write(0x0000, 0x0A);
+        camera.write(0x4000, 0x0F);
+        camera.write(0xBFFF, 0x42);
+        let ram = camera.dump_ram();
+        assert_eq!(ram.len(), RAM_SIZE);
+        assert_eq!(ram[RAM_BANK_SIZE * 16 - 1], 0x42);
+    }
+
+    #[test]
+    fn pgm_images_are_decoded() {
+        let mut pgm = b"P5\n# two pixels\n2 1\n255\n".to_vec();
+        pgm.extend_from_slice(&[0x00, 0xFF]);
+        let (width, height, pixels) = decode_pgm(&pgm).unwrap();
+        assert_eq!((width, height), (2, 1));
+        assert_eq!(pixels, vec![0x00, 0xFF]);
+        assert!(decode_pgm(b"P2\n2 1\n255\n0 0").is_err());
+    }
+
+    #[test]
+    fn oversized_pgm_is_rejected() {
+        // The pixel count overflows, and so does adding the header length to it
+        for size in [format!("{} 3", usize::MAX / 2), format!("{} 1", usize::MAX)] {
+            let pgm = format!("P5\n{size}\n255\n");
+            let error = decode_pgm(pgm.as_bytes()).unwrap_err();
+            assert_eq!(error.to_string(), "pgm is too large");
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use crate::mbc::{get_ram_offset, load_ram_data, load_ram_state, load_rom_banks, MBC};
+use crate::memory::Memory;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+// What the infrared receiver reads when it doesn't see any light, bit 0 is set when it does
+const IR_NO_LIGHT: u8 = 0xC0;
+const IR_MODE: u8 = 0x0E;
+
+pub struct HuC1 {
+    rom: Vec<[u8; ROM_BANK_SIZE]>,
+    ram: Vec<u8>,
+    // There is no ram enable, the same register swaps ram for the infrared port instead
+    ir_mode: bool,
+    led_on: bool,
+    rom_bank_index: u8,
+    ram_bank_index: u8,
+}
+
+impl HuC1 {
+    pub fn new(rom_banks: u16, ram_size: usize) -> Self {
+        HuC1 {
+            rom: vec![[0; ROM_BANK_SIZE]; rom_banks as usize],
+            ram: vec![0; ram_size],
+            ir_mode: false,
+            led_on: false,
+            rom_bank_index: 1,
+            ram_bank_index: 0,
+        }
+    }
+
+    fn get_rom_bank(&self) -> usize {
+        self.rom_bank_index as usize % self.rom.len()
+    }
+}
+
+impl Memory for HuC1 {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            rom_bank_one_address @ 0x0000..=0x3FFF => self.rom[0][rom_bank_one_address as usize],
+            other_rom_banks_address @ 0x4000..=0x7FFF => {
+                self.rom[self.get_rom_bank()][(other_rom_banks_address - 0x4000) as usize]
+            }
+            0xA000..=0xBFFF if self.ir_mode => IR_NO_LIGHT,
+            external_ram_address @ 0xA000..=0xBFFF => {
+                let bank = self.ram_bank_index as usize;
+                match get_ram_offset(&self.ram, bank, external_ram_address) {
+                    Some(offset) => self.ram[offset],
+                    None => 0xFF,
+                }
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            // Ram or infrared select register
+            0x0000..=0x1FFF => {
+                self.ir_mode = data & 0b00001111 == IR_MODE;
+            }
+            // Rom bank select register
+            0x2000..=0x3FFF => {
+                let data = data & 0b00111111;
+                self.rom_bank_index = if data == 0 { 1 } else { data };
+            }
+            // Ram bank select register
+            0x4000..=0x5FFF => {
+                self.ram_bank_index = data & 0b00000011;
+            }
+            0xA000..=0xBFFF if self.ir_mode => {
+                self.led_on = data & 0b00000001 != 0;
+            }
+            external_ram_address @ 0xA000..=0xBFFF => {
+                let bank = self.ram_bank_index as usize;
+                if let Some(offset) = get_ram_offset(&self.ram, bank, external_ram_address) {
+                    self.ram[offset] = data;
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl MBC for HuC1 {
+    fn init(&mut self, program: &Vec<u8>) {
+        load_rom_banks(&mut self.rom, program);
+    }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        load_ram_data(&mut self.ram, data);
+    }
+
+    fn get_mapped_rom_bank(&self, address: u16) -> usize {
+        if address < 0x4000 {
+            0
+        } else {
+            self.get_rom_bank()
+        }
+    }
+}
+
+impl SaveState for HuC1 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_vec(&self.ram);
+        state.write_bool(self.ir_mode);
+        state.write_bool(self.led_on);
+        state.write_u8(self.rom_bank_index);
+        state.write_u8(self.ram_bank_index);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_ram_state(&mut self.ram, state)?;
+        self.ir_mode = state.read_bool()?;
+        self.led_on = state.read_bool()?;
+        self.rom_bank_index = state.read_u8()?;
+        self.ram_bank_index = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::RAM_BANK_SIZE;
+
+    #[test]
+    fn ir_mode_replaces_ram() {
+        let mut mbc = HuC1::new(2, RAM_BANK_SIZE * 4);
+        mbc.write(0x4000, 2);
+        mbc.write(0xA000, 0x42);
+        assert_eq!(mbc.read(0xA000), 0x42);
+
+        mbc.write(0x0000, IR_MODE);
+        mbc.write(0xA000, 0x01);
+        assert!(mbc.led_on);
+        assert_eq!(mbc.read(0xA000), IR_NO_LIGHT);
+
+        mbc.write(0x0000, 0x0A);
+        assert_eq!(mbc.read(0xA000), 0x42);
+        mbc.write(0x4000, 0);
+        assert_eq!(mbc.read(0xA000), 0x00);
+    }
+}
@@ -0,0 +1,418 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::mbc::{get_ram_offset, load_ram_data, load_ram_state, load_rom_banks, MBC};
+use crate::memory::Memory;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+
+const CYCLES_PER_SECOND: u32 = 4194304;
+const CYCLES_PER_MINUTE: u32 = CYCLES_PER_SECOND * 60;
+const MINUTES_PER_DAY: u16 = 60 * 24;
+// The day counter is twelve bits wide
+const DAYS_WRAP: u16 = 0x1000;
+// Minutes and days as u32s followed by a u64 timestamp
+const RTC_FOOTER_SIZE: usize = 16;
+const TIMESTAMP_SIZE: usize = 8;
+
+// The low nibble of 0x0000-0x1FFF picks what 0xA000-0xBFFF is connected to
+const MODE_RAM_READ: u8 = 0x0;
+const MODE_RAM: u8 = 0xA;
+const MODE_COMMAND: u8 = 0xB;
+const MODE_RESPONSE: u8 = 0xC;
+const MODE_SEMAPHORE: u8 = 0xD;
+const MODE_IR: u8 = 0xE;
+
+const COMMAND_READ: u8 = 0x1;
+const COMMAND_WRITE: u8 = 0x3;
+const COMMAND_ADDRESS_LOW: u8 = 0x4;
+const COMMAND_ADDRESS_HIGH: u8 = 0x5;
+const COMMAND_EXTENDED: u8 = 0x6;
+
+const EXTENDED_LOAD_TIME: u8 = 0x0;
+const EXTENDED_STORE_TIME: u8 = 0x1;
+const EXTENDED_STATUS: u8 = 0x2;
+const EXTENDED_TONE: u8 = 0xE;
+
+// Nibbles in the clock chip's memory that the time is copied to and from
+const TIME_ADDRESS: usize = 0x00;
+const TONE_ENABLE_ADDRESS: usize = 0x26;
+const TONE_ADDRESS: usize = 0x27;
+const CLOCK_MEMORY_SIZE: usize = 0x100;
+
+// The pitches the cartridge speaker plays aren't documented, these are close enough for the
+// beeps to be recognisable
+const TONE_FREQUENCIES: [u32; 4] = [2048, 1024, 4096, 512];
+const TONE_CYCLES: u32 = CYCLES_PER_SECOND / 4;
+const TONE_VOLUME: f32 = 0.25;
+
+const IR_NO_LIGHT: u8 = 0xC0;
+
+pub struct HuC3 {
+    rom: Vec<[u8; ROM_BANK_SIZE]>,
+    ram: Vec<u8>,
+    mode: u8,
+    led_on: bool,
+    rom_bank_index: u8,
+    ram_bank_index: u8,
+    // The clock is a separate chip that is talked to one command nibble at a time
+    command: u8,
+    response: u8,
+    clock_address: u8,
+    clock_memory: [u8; CLOCK_MEMORY_SIZE],
+    minutes: u16,
+    days: u16,
+    cycles: u32,
+    tone_frequency: u32,
+    tone_cycles_left: u32,
+}
+
+impl HuC3 {
+    pub fn new(rom_banks: u16, ram_size: usize) -> Self {
+        HuC3 {
+            rom: vec![[0; ROM_BANK_SIZE]; rom_banks as usize],
+            ram: vec![0; ram_size],
+            mode: MODE_RAM_READ,
+            led_on: false,
+            rom_bank_index: 1,
+            ram_bank_index: 0,
+            command: 0,
+            response: 0,
+            clock_address: 0,
+            clock_memory: [0; CLOCK_MEMORY_SIZE],
+            minutes: 0,
+            days: 0,
+            cycles: 0,
+            tone_frequency: TONE_FREQUENCIES[0],
+            tone_cycles_left: 0,
+        }
+    }
+
+    fn advance_minutes(&mut self, minutes: u64) {
+        let total = self.minutes as u64 + minutes;
+        self.minutes = (total % MINUTES_PER_DAY as u64) as u16;
+        let days = self.days as u64 + total / MINUTES_PER_DAY as u64;
+        self.days = (days % DAYS_WRAP as u64) as u16;
+    }
+
+    fn run_command(&mut self) {
+        let command = (self.command >> 4) & 0b0111;
+        let argument = self.command & 0x0F;
+        let mut result = argument;
+        match command {
+            COMMAND_READ => {
+                result = self.clock_memory[self.clock_address as usize];
+                self.clock_address = self.clock_address.wrapping_add(1);
+            }
+            COMMAND_WRITE => {
+                self.clock_memory[self.clock_address as usize] = argument;
+                self.clock_address = self.clock_address.wrapping_add(1);
+            }
+            COMMAND_ADDRESS_LOW => {
+                self.clock_address = (self.clock_address & 0xF0) | argument;
+            }
+            COMMAND_ADDRESS_HIGH => {
+                self.clock_address = (self.clock_address & 0x0F) | (argument << 4);
+            }
+            COMMAND_EXTENDED => result = self.run_extended_command(argument),
+            _ => (),
+        }
+        self.response = (command << 4) | result;
+    }
+
+    fn run_extended_command(&mut self, argument: u8) -> u8 {
+        match argument {
+            // Minutes of the day and days are both stored as three little endian nibbles
+            EXTENDED_LOAD_TIME => {
+                for i in 0..3 {
+                    self.clock_memory[TIME_ADDRESS + i] = (self.minutes >> (i * 4)) as u8 & 0x0F;
+                    self.clock_memory[TIME_ADDRESS + 3 + i] = (self.days >> (i * 4)) as u8 & 0x0F;
+                }
+            }
+            EXTENDED_STORE_TIME => {
+                let mut minutes = 0;
+                let mut days = 0;
+                for i in 0..3 {
+                    minutes |= (self.clock_memory[TIME_ADDRESS + i] as u16) << (i * 4);
+                    days |= (self.clock_memory[TIME_ADDRESS + 3 + i] as u16) << (i * 4);
+                }
+                self.minutes = minutes % MINUTES_PER_DAY;
+                self.days = days;
+                self.cycles = 0;
+            }
+            // Games poll this until the clock says it is ready
+            EXTENDED_STATUS => return 0x1,
+            EXTENDED_TONE if self.clock_memory[TONE_ENABLE_ADDRESS] == 1 => {
+                let tone = self.clock_memory[TONE_ADDRESS] as usize % TONE_FREQUENCIES.len();
+                self.tone_frequency = TONE_FREQUENCIES[tone];
+                self.tone_cycles_left = TONE_CYCLES;
+            }
+            _ => (),
+        }
+        argument
+    }
+
+    fn dump_rtc(&self) -> Vec<u8> {
+        let mut footer = Vec::with_capacity(RTC_FOOTER_SIZE);
+        footer.extend_from_slice(&(self.minutes as u32).to_le_bytes());
+        footer.extend_from_slice(&(self.days as u32).to_le_bytes());
+        footer.extend_from_slice(&unix_time().to_le_bytes());
+        footer
+    }
+
+    fn load_rtc(&mut self, footer: &[u8]) {
+        let minutes = u32::from_le_bytes(footer[0..4].try_into().unwrap());
+        let days = u32::from_le_bytes(footer[4..8].try_into().unwrap());
+        self.minutes = (minutes % MINUTES_PER_DAY as u32) as u16;
+        self.days = (days % DAYS_WRAP as u32) as u16;
+        let timestamp = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+        self.advance_minutes(unix_time().saturating_sub(timestamp) / 60);
+    }
+
+    fn get_rom_bank(&self) -> usize {
+        self.rom_bank_index as usize % self.rom.len()
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+impl Memory for HuC3 {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            rom_bank_one_address @ 0x0000..=0x3FFF => self.rom[0][rom_bank_one_address as usize],
+            other_rom_banks_address @ 0x4000..=0x7FFF => {
+                self.rom[self.get_rom_bank()][(other_rom_banks_address - 0x4000) as usize]
+            }
+            external_ram_address @ 0xA000..=0xBFFF => match self.mode {
+                MODE_RAM_READ | MODE_RAM => {
+                    let bank = self.ram_bank_index as usize;
+                    match get_ram_offset(&self.ram, bank, external_ram_address) {
+                        Some(offset) => self.ram[offset],
+                        None => 0xFF,
+                    }
+                }
+                MODE_RESPONSE => self.response,
+                // Commands finish instantly so the clock is always ready
+                MODE_SEMAPHORE => 0xFF,
+                MODE_IR => IR_NO_LIGHT,
+                _ => 0xFF,
+            },
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            // Mode select register
+            0x0000..=0x1FFF => {
+                self.mode = data & 0b00001111;
+            }
+            // Rom bank select register
+            0x2000..=0x3FFF => {
+                self.rom_bank_index = data & 0b01111111;
+            }
+            // Ram bank select register
+            0x4000..=0x5FFF => {
+                self.ram_bank_index = data & 0b00001111;
+            }
+            external_ram_address @ 0xA000..=0xBFFF => match self.mode {
+                MODE_RAM => {
+                    let bank = self.ram_bank_index as usize;
+                    if let Some(offset) = get_ram_offset(&self.ram, bank, external_ram_address) {
+                        self.ram[offset] = data;
+                    }
+                }
+                MODE_COMMAND => self.command = data,
+                // Clearing bit 0 tells the clock to run the command
+                MODE_SEMAPHORE if data & 0b00000001 == 0 => self.run_command(),
+                MODE_IR => self.led_on = data & 0b00000001 != 0,
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+}
+
+impl MBC for HuC3 {
+    fn init(&mut self, program: &Vec<u8>) {
+        load_rom_banks(&mut self.rom, program);
+    }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        data.extend(self.dump_rtc());
+        data
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        load_ram_data(&mut self.ram, data);
+        let ram_size = self.ram.len();
+        if data.len() >= ram_size + RTC_FOOTER_SIZE {
+            self.load_rtc(&data[ram_size..ram_size + RTC_FOOTER_SIZE]);
+        }
+    }
+
+    fn get_timestamp_size(&self) -> usize {
+        TIMESTAMP_SIZE
+    }
+
+    fn get_mapped_rom_bank(&self, address: u16) -> usize {
+        if address < 0x4000 {
+            0
+        } else {
+            self.get_rom_bank()
+        }
+    }
+
+    fn update(&mut self, cycles: u32) {
+        self.cycles += cycles;
+        while self.cycles >= CYCLES_PER_MINUTE {
+            self.cycles -= CYCLES_PER_MINUTE;
+            self.advance_minutes(1);
+        }
+        self.tone_cycles_left = self.tone_cycles_left.saturating_sub(cycles);
+    }
+
+    // The speaker plays a square wave at the selected pitch
+    fn get_audio_output(&self) -> f32 {
+        if self.tone_cycles_left == 0 {
+            return 0.0;
+        }
+        let cycles_per_period = CYCLES_PER_SECOND / self.tone_frequency;
+        let phase = (TONE_CYCLES - self.tone_cycles_left) % cycles_per_period;
+        if phase < cycles_per_period / 2 {
+            TONE_VOLUME
+        } else {
+            -TONE_VOLUME
+        }
+    }
+}
+
+impl SaveState for HuC3 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_vec(&self.ram);
+        state.write_u8(self.mode);
+        state.write_bool(self.led_on);
+        state.write_u8(self.rom_bank_index);
+        state.write_u8(self.ram_bank_index);
+        state.write_u8(self.command);
+        state.write_u8(self.response);
+        state.write_u8(self.clock_address);
+        state.write_bytes(&self.clock_memory);
+        state.write_u16(self.minutes);
+        state.write_u16(self.days);
+        state.write_u32(self.cycles);
+        state.write_u32(self.tone_frequency);
+        state.write_u32(self.tone_cycles_left);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_ram_state(&mut self.ram, state)?;
+        self.mode = state.read_u8()?;
+        self.led_on = state.read_bool()?;
+        self.rom_bank_index = state.read_u8()?;
+        self.ram_bank_index = state.read_u8()?;
+        self.command = state.read_u8()?;
+        self.response = state.read_u8()?;
+        self.clock_address = state.read_u8()?;
+        state.read_bytes(&mut self.clock_memory)?;
+        self.minutes = state.read_u16()?;
+        self.days = state.read_u16()?;
+        self.cycles = state.read_u32()?;
+        self.tone_frequency = state.read_u32()?;
+        self.tone_cycles_left = state.read_u32()?;
+        if !TONE_FREQUENCIES.contains(&self.tone_frequency) {
+            return Err(StateError::Invalid("huc3 tone"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::RAM_BANK_SIZE;
+
+    fn send_command(mbc: &mut HuC3, command: u8, argument: u8) -> u8 {
+        mbc.write(0x0000, MODE_COMMAND);
+        mbc.write(0xA000, (command << 4) | argument);
+        mbc.write(0x0000, MODE_SEMAPHORE);
+        mbc.write(0xA000, 0xFE);
+        mbc.write(0x0000, MODE_RESPONSE);
+        mbc.read(0xA000) & 0x0F
+    }
+
+    fn set_clock_address(mbc: &mut HuC3, address: u8) {
+        send_command(mbc, COMMAND_ADDRESS_LOW, address & 0x0F);
+        send_command(mbc, COMMAND_ADDRESS_HIGH, address >> 4);
+    }
+
+    #[test]
+    fn clock_time_is_read_through_commands() {
+        let mut mbc = HuC3::new(2, RAM_BANK_SIZE);
+        for _ in 0..MINUTES_PER_DAY + 0x123 {
+            mbc.update(CYCLES_PER_MINUTE);
+        }
+        send_command(&mut mbc, COMMAND_EXTENDED, EXTENDED_LOAD_TIME);
+        set_clock_address(&mut mbc, TIME_ADDRESS as u8);
+        let nibbles: Vec<u8> = (0..6)
+            .map(|_| send_command(&mut mbc, COMMAND_READ, 0))
+            .collect();
+        assert_eq!(nibbles, vec![0x3, 0x2, 0x1, 0x1, 0x0, 0x0]);
+    }
+
+    #[test]
+    fn clock_time_is_written_through_commands() {
+        let mut mbc = HuC3::new(2, RAM_BANK_SIZE);
+        set_clock_address(&mut mbc, TIME_ADDRESS as u8);
+        for nibble in [0xE, 0x3, 0x0, 0x5, 0x0, 0x0] {
+            send_command(&mut mbc, COMMAND_WRITE, nibble);
+        }
+        send_command(&mut mbc, COMMAND_EXTENDED, EXTENDED_STORE_TIME);
+        assert_eq!(mbc.minutes, 0x3E);
+        assert_eq!(mbc.days, 5);
+        assert_eq!(send_command(&mut mbc, COMMAND_EXTENDED, EXTENDED_STATUS), 1);
+    }
+
+    #[test]
+    fn dumped_ram_ends_with_the_timestamp() {
+        let mbc = HuC3::new(2, RAM_BANK_SIZE);
+        let start = unix_time();
+        let ram = mbc.dump_ram();
+        let timestamp = &ram[ram.len() - mbc.get_timestamp_size()..];
+        let timestamp = u64::from_le_bytes(timestamp.try_into().unwrap());
+        assert!((start..=unix_time()).contains(&timestamp));
+    }
+
+    #[test]
+    fn ram_is_only_writable_in_ram_mode() {
+        let mut mbc = HuC3::new(2, RAM_BANK_SIZE);
+        mbc.write(0xA000, 0x42);
+        assert_eq!(mbc.read(0xA000), 0x00);
+        mbc.write(0x0000, MODE_RAM);
+        mbc.write(0xA000, 0x42);
+        mbc.write(0x0000, MODE_RAM_READ);
+        assert_eq!(mbc.read(0xA000), 0x42);
+        mbc.write(0x0000, MODE_IR);
+        assert_eq!(mbc.read(0xA000), IR_NO_LIGHT);
+    }
+
+    #[test]
+    fn tone_plays_for_a_while() {
+        let mut mbc = HuC3::new(2, RAM_BANK_SIZE);
+        set_clock_address(&mut mbc, TONE_ENABLE_ADDRESS as u8);
+        send_command(&mut mbc, COMMAND_WRITE, 1);
+        send_command(&mut mbc, COMMAND_WRITE, 1);
+        send_command(&mut mbc, COMMAND_EXTENDED, EXTENDED_TONE);
+        assert_eq!(mbc.tone_frequency, TONE_FREQUENCIES[1]);
+        assert_eq!(mbc.get_audio_output(), TONE_VOLUME);
+
+        mbc.update(TONE_CYCLES);
+        assert_eq!(mbc.get_audio_output(), 0.0);
+    }
+}
@@ -1,55 +1,77 @@
-use crate::{mbc::MBC, memory::Memory};
+use crate::mbc::{get_ram_offset, load_ram_data, load_ram_state, load_rom_banks, MBC};
+use crate::memory::Memory;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 
 const ROM_BANK_SIZE: usize = 0x4000;
-const RAM_BANK_SIZE: usize = 0x2000;
 pub struct MBC1 {
     rom: Vec<[u8; ROM_BANK_SIZE]>,
-    ram: Vec<[u8; RAM_BANK_SIZE]>,
+    ram: Vec<u8>,
     ram_enabled: bool,
     rom_bank_index: u8,
     ram_bank_index: u8,
     using_ram_banking: bool,
+    // Multicarts leave bit 4 of the rom bank register unconnected, so the upper bits select
+    // one of four 256 KB games instead
+    multicart: bool,
 }
 
 impl MBC1 {
-    pub fn new(rom_banks: u8, ram_banks: u8) -> Self {
-        let mut mbc = MBC1 {
-            rom: Vec::with_capacity(rom_banks as usize),
-            ram: Vec::with_capacity(ram_banks as usize),
+    pub fn new(rom_banks: u16, ram_size: usize) -> Self {
+        MBC1 {
+            rom: vec![[0; ROM_BANK_SIZE]; rom_banks as usize],
+            ram: vec![0; ram_size],
             ram_enabled: false,
             rom_bank_index: 0,
             ram_bank_index: 0,
             using_ram_banking: false,
+            multicart: false,
+        }
+    }
+
+    pub fn new_multicart(rom_banks: u16, ram_size: usize) -> Self {
+        MBC1 {
+            multicart: true,
+            ..MBC1::new(rom_banks, ram_size)
+        }
+    }
+
+    fn get_upper_bits_shift(&self) -> u8 {
+        if self.multicart {
+            4
+        } else {
+            5
+        }
+    }
+
+    // The ram bank register doubles as the upper two bits of the rom bank
+    fn get_upper_rom_bank(&self) -> usize {
+        let mut lower_bits = if self.rom_bank_index == 0 {
+            1
+        } else {
+            self.rom_bank_index
         };
-        // Initialize rom and ram_banks
-        for _ in 0..rom_banks {
-            mbc.rom.push([0; ROM_BANK_SIZE]);
+        if self.multicart {
+            // The zero check still sees all five bits, so 0x10 selects the first bank of a game
+            lower_bits &= 0b00001111;
         }
-        for _ in 0..ram_banks {
-            mbc.ram.push([0; RAM_BANK_SIZE]);
+        let upper_bits = self.ram_bank_index << self.get_upper_bits_shift();
+        (upper_bits | lower_bits) as usize % self.rom.len()
+    }
+
+    // Banks 0x20, 0x40 and 0x60 can only be reached in ram banking mode
+    fn get_lower_rom_bank(&self) -> usize {
+        if self.using_ram_banking {
+            (self.ram_bank_index << self.get_upper_bits_shift()) as usize % self.rom.len()
+        } else {
+            0
         }
-        mbc
     }
 
-    fn init_write(&mut self, address: u16, data: u8) {
-        match address {
-            rom_bank_one_address @ 0x0000..=0x3FFF => {
-                let extra_bits = 0b01100000 & self.rom_bank_index;
-                self.rom[0 | (extra_bits << 5) as usize][rom_bank_one_address as usize] = data;
-            }
-            other_rom_banks_address @ 0x4000..=0x7FFF => {
-                let bank_number = if self.rom_bank_index == 0 {
-                    1
-                } else {
-                    self.rom_bank_index
-                };
-                self.rom[bank_number as usize][(other_rom_banks_address - 0x4000) as usize] = data;
-            }
-            external_ram_address @ 0xA000..=0xBFFF if self.ram_enabled => {
-                self.ram[self.ram_bank_index as usize][(external_ram_address - 0xA000) as usize] =
-                    data;
-            }
-            _ => (),
+    fn get_ram_bank(&self) -> usize {
+        if self.using_ram_banking {
+            self.ram_bank_index as usize
+        } else {
+            0
         }
     }
 }
@@ -58,19 +80,16 @@ impl Memory for MBC1 {
     fn read(&self, address: u16) -> u8 {
         match address {
             rom_bank_one_address @ 0x0000..=0x3FFF => {
-                let extra_bits = 0b01100000 & self.rom_bank_index; // Will always be zero unless banks 0x20, 0x40, 0x60 are possible to access
-                self.rom[0 | (extra_bits << 5) as usize][rom_bank_one_address as usize]
+                self.rom[self.get_lower_rom_bank()][rom_bank_one_address as usize]
             }
             other_rom_banks_address @ 0x4000..=0x7FFF => {
-                let bank_number = if self.rom_bank_index == 0 {
-                    1
-                } else {
-                    self.rom_bank_index
-                };
-                self.rom[bank_number as usize][(other_rom_banks_address - 0x4000) as usize]
+                self.rom[self.get_upper_rom_bank()][(other_rom_banks_address - 0x4000) as usize]
             }
             external_ram_address @ 0xA000..=0xBFFF if self.ram_enabled => {
-                self.ram[self.ram_bank_index as usize][(external_ram_address - 0xA000) as usize]
+                match get_ram_offset(&self.ram, self.get_ram_bank(), external_ram_address) {
+                    Some(offset) => self.ram[offset],
+                    None => 0xFF,
+                }
             }
             _ => 0xFF,
         }
@@ -85,44 +104,22 @@ impl Memory for MBC1 {
             }
             // Rom bank select register
             0x2000..=0x3FFF => {
-                let mask = if data as usize > self.rom.len() {
-                    // Cut off bits if the rom bank would be too high for the cartridge
-                    self.rom.len() as u8 - 1
-                } else {
-                    // Else use the bottom five bits
-                    0b00011111
-                };
-                let data = mask & data;
-
-                let extra_bits = if self.using_ram_banking && self.rom.len() > 32 {
-                    self.ram_bank_index
-                } else {
-                    0
-                };
-
-                self.rom_bank_index = data | (extra_bits << 5);
+                self.rom_bank_index = data & 0b00011111;
             }
             // Ram bank select register
             0x4000..=0x5FFF => {
-                if self.using_ram_banking {
-                    if self.ram.len() > 1 || self.rom.len() >= 64 {
-                        self.ram_bank_index = data;
-                    }
-                }
+                self.ram_bank_index = data & 0b00000011;
             }
             // Banking mode select register
             0x6000..=0x7FFF => {
-                if data == 1 {
-                    self.using_ram_banking = true;
-                } else if data == 0 {
-                    self.using_ram_banking = false;
-                }
+                self.using_ram_banking = data & 0b00000001 != 0;
             }
-            external_ram_address @ 0xA000..=0xBFFF
-                if self.ram_enabled && self.using_ram_banking =>
-            {
-                self.ram[self.ram_bank_index as usize][(external_ram_address - 0xA000) as usize] =
-                    data;
+            external_ram_address @ 0xA000..=0xBFFF if self.ram_enabled => {
+                if let Some(offset) =
+                    get_ram_offset(&self.ram, self.get_ram_bank(), external_ram_address)
+                {
+                    self.ram[offset] = data;
+                }
             }
             _ => (),
         }
@@ -131,34 +128,63 @@ impl Memory for MBC1 {
 
 impl MBC for MBC1 {
     fn init(&mut self, program: &Vec<u8>) {
-        let rom_select_address = 0x2000;
-        for i in 0..self.rom.len() {
-            self.write(rom_select_address, i as u8);
-            // Figure out whether the data should be written to first or second area of rom
-            let bank_offset = if i == 0 || i == 0x20 || i == 0x40 || i == 0x60 {
-                0
-            } else {
-                0x4000
-            };
-            for j in 0..ROM_BANK_SIZE {
-                self.init_write(
-                    bank_offset + j as u16,
-                    program[ROM_BANK_SIZE * i as usize + j],
-                )
-            }
+        load_rom_banks(&mut self.rom, program);
+    }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        load_ram_data(&mut self.ram, data);
+    }
+
+    fn get_mapped_rom_bank(&self, address: u16) -> usize {
+        if address < 0x4000 {
+            self.get_lower_rom_bank()
+        } else {
+            self.get_upper_rom_bank()
         }
+    }
+}
 
-        // Set rom select register back to initial value of zero
-        self.write(rom_select_address, 0);
+impl SaveState for MBC1 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_vec(&self.ram);
+        state.write_bool(self.ram_enabled);
+        state.write_u8(self.rom_bank_index);
+        state.write_u8(self.ram_bank_index);
+        state.write_bool(self.using_ram_banking);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_ram_state(&mut self.ram, state)?;
+        self.ram_enabled = state.read_bool()?;
+        self.rom_bank_index = state.read_u8()?;
+        self.ram_bank_index = state.read_u8()?;
+        self.using_ram_banking = state.read_bool()?;
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mbc::RAM_BANK_SIZE;
 
     fn get_test_mbc() -> MBC1 {
-        MBC1::new(0x7F + 2, 4)
+        MBC1::new(0x7F + 2, RAM_BANK_SIZE * 4)
+    }
+
+    // Writes into whichever rom bank is mapped at the address
+    fn init_write(mbc: &mut MBC1, address: u16, data: u8) {
+        if address < 0x4000 {
+            let bank = mbc.get_lower_rom_bank();
+            mbc.rom[bank][address as usize] = data;
+        } else {
+            let bank = mbc.get_upper_rom_bank();
+            mbc.rom[bank][(address - 0x4000) as usize] = data;
+        }
     }
 
     #[test]
@@ -198,7 +224,7 @@ mod tests {
     #[test]
     fn can_access_rom_bank_zero() {
         let mut mbc = get_test_mbc();
-        mbc.init_write(0x0000, 0x11);
+        init_write(&mut mbc, 0x0000, 0x11);
         let data = mbc.read(0x0000);
         assert_eq!(data, 0x11);
     }
@@ -207,7 +233,7 @@ mod tests {
     fn can_access_rom_bank_one() {
         let mut mbc = get_test_mbc();
         mbc.write(0x2000, 0);
-        mbc.init_write(0x4000, 0x11);
+        init_write(&mut mbc, 0x4000, 0x11);
         let data = mbc.read(0x4000);
         assert_eq!(data, 0x11);
     }
@@ -216,7 +242,7 @@ mod tests {
     fn writes_to_rom_bank_2_do_not_affect_rom_bank_one() {
         let mut mbc = get_test_mbc();
         mbc.write(0x2000, 2);
-        mbc.init_write(0x4000, 0x11);
+        init_write(&mut mbc, 0x4000, 0x11);
         mbc.write(0x2000, 1);
         let data = mbc.read(0x4000);
         assert_eq!(data, 0x00);
@@ -229,4 +255,66 @@ mod tests {
         let data = mbc.read(0xA000);
         assert_eq!(data, 0)
     }
+
+    #[test]
+    fn dumped_ram_can_be_loaded_back() {
+        let mut mbc = get_test_mbc();
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0x6000, 1);
+        mbc.write(0x4000, 2);
+        mbc.write(0xA010, 0x42);
+        let data = mbc.dump_ram();
+        assert_eq!(data.len(), RAM_BANK_SIZE * 4);
+
+        let mut other = get_test_mbc();
+        other.load_ram(&data);
+        other.write(0x0000, 0x0A);
+        other.write(0x6000, 1);
+        other.write(0x4000, 2);
+        assert_eq!(other.read(0xA010), 0x42);
+    }
+
+    #[test]
+    fn rom_bank_select_wraps_around_rom_size() {
+        let mut mbc = MBC1::new(4, 0);
+        let program: Vec<u8> = (0..4).flat_map(|bank| [bank; ROM_BANK_SIZE]).collect();
+        mbc.init(&program);
+        mbc.write(0x2000, 6);
+        assert_eq!(mbc.read(0x4000), 2);
+        mbc.write(0x4000, 1);
+        mbc.write(0x6000, 1);
+        assert_eq!(mbc.read(0x0000), 0);
+    }
+
+    #[test]
+    fn small_ram_is_mirrored() {
+        let mut mbc = MBC1::new(2, 0x800);
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0xA000, 0x42);
+        assert_eq!(mbc.read(0xA800), 0x42);
+        assert_eq!(mbc.dump_ram().len(), 0x800);
+
+        let mut mbc = MBC1::new(2, 0);
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0xA000, 0x42);
+        assert_eq!(mbc.read(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn multicart_upper_bits_select_a_game() {
+        let mut mbc = MBC1::new_multicart(64, 0);
+        let program: Vec<u8> = (0..64).flat_map(|bank| [bank; ROM_BANK_SIZE]).collect();
+        mbc.init(&program);
+        mbc.write(0x4000, 2);
+        mbc.write(0x2000, 0x03);
+        assert_eq!(mbc.read(0x4000), 0x23);
+        mbc.write(0x2000, 0x10);
+        assert_eq!(mbc.read(0x4000), 0x20);
+        mbc.write(0x2000, 0x00);
+        assert_eq!(mbc.read(0x4000), 0x21);
+
+        assert_eq!(mbc.read(0x0000), 0x00);
+        mbc.write(0x6000, 1);
+        assert_eq!(mbc.read(0x0000), 0x20);
+    }
 }
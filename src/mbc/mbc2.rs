@@ -0,0 +1,133 @@
+use crate::mbc::{load_ram_data, load_ram_state, load_rom_banks, MBC};
+use crate::memory::Memory;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+// Built into the controller, only the lower nibble of each byte exists
+const RAM_SIZE: usize = 0x200;
+
+pub struct MBC2 {
+    rom: Vec<[u8; ROM_BANK_SIZE]>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank_index: u8,
+}
+
+impl MBC2 {
+    pub fn new(rom_banks: u16) -> Self {
+        MBC2 {
+            rom: vec![[0; ROM_BANK_SIZE]; rom_banks as usize],
+            ram: vec![0; RAM_SIZE],
+            ram_enabled: false,
+            rom_bank_index: 1,
+        }
+    }
+
+    fn get_rom_bank(&self) -> usize {
+        self.rom_bank_index as usize % self.rom.len()
+    }
+}
+
+impl Memory for MBC2 {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            rom_bank_one_address @ 0x0000..=0x3FFF => self.rom[0][rom_bank_one_address as usize],
+            other_rom_banks_address @ 0x4000..=0x7FFF => {
+                self.rom[self.get_rom_bank()][(other_rom_banks_address - 0x4000) as usize]
+            }
+            // The upper nibble isn't connected and reads as ones
+            external_ram_address @ 0xA000..=0xBFFF if self.ram_enabled => {
+                self.ram[external_ram_address as usize % RAM_SIZE] | 0xF0
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            // Bit 8 of the address picks between the ram enable and rom bank select registers
+            0x0000..=0x3FFF if address & 0x0100 == 0 => {
+                self.ram_enabled = data & 0b00001111 == 0x0A;
+            }
+            0x0000..=0x3FFF => {
+                let data = data & 0b00001111;
+                self.rom_bank_index = if data == 0 { 1 } else { data };
+            }
+            external_ram_address @ 0xA000..=0xBFFF if self.ram_enabled => {
+                self.ram[external_ram_address as usize % RAM_SIZE] = data & 0x0F;
+            }
+            _ => (),
+        }
+    }
+}
+
+impl MBC for MBC2 {
+    fn init(&mut self, program: &Vec<u8>) {
+        load_rom_banks(&mut self.rom, program);
+    }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        load_ram_data(&mut self.ram, data);
+    }
+
+    fn get_mapped_rom_bank(&self, address: u16) -> usize {
+        if address < 0x4000 {
+            0
+        } else {
+            self.get_rom_bank()
+        }
+    }
+}
+
+impl SaveState for MBC2 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_vec(&self.ram);
+        state.write_bool(self.ram_enabled);
+        state.write_u8(self.rom_bank_index);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_ram_state(&mut self.ram, state)?;
+        self.ram_enabled = state.read_bool()?;
+        self.rom_bank_index = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_bit_8_selects_register() {
+        let mut mbc = MBC2::new(16);
+        let program: Vec<u8> = (0..16).flat_map(|bank| [bank; ROM_BANK_SIZE]).collect();
+        mbc.init(&program);
+        mbc.write(0x2100, 5);
+        assert_eq!(mbc.read(0x4000), 5);
+        mbc.write(0x0100, 0);
+        assert_eq!(mbc.read(0x4000), 1);
+
+        // Writing 0x0A with bit 8 set is a bank select, not a ram enable
+        mbc.write(0x0100, 0x0A);
+        assert_eq!(mbc.read(0xA000), 0xFF);
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0xA000, 0x00);
+        assert_eq!(mbc.read(0xA000), 0xF0);
+    }
+
+    #[test]
+    fn ram_holds_nibbles_and_is_mirrored() {
+        let mut mbc = MBC2::new(2);
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0xA005, 0x5A);
+        assert_eq!(mbc.read(0xA005), 0xFA);
+        assert_eq!(mbc.read(0xA205), 0xFA);
+        assert_eq!(mbc.read(0xBE05), 0xFA);
+        assert_eq!(mbc.dump_ram().len(), RAM_SIZE);
+    }
+}
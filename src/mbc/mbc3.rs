@@ -1,60 +1,237 @@
-use crate::{mbc::MBC, memory::Memory};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::mbc::{get_ram_offset, load_ram_data, load_ram_state, load_rom_banks, MBC};
+use crate::memory::Memory;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 
 const ROM_BANK_SIZE: usize = 0x4000;
-const RAM_BANK_SIZE: usize = 0x2000;
 
-// Todo: Implement external real time clock (RTC)
+const CYCLES_PER_SECOND: u32 = 4194304;
+// Five current registers and five latched registers as u32s followed by a u64 timestamp
+const RTC_FOOTER_SIZE: usize = 48;
+const TIMESTAMP_SIZE: usize = 8;
+
+const DH_DAY_HIGH_BIT: u8 = 0b00000001;
+const DH_HALT_BIT: u8 = 0b01000000;
+const DH_DAY_CARRY_BIT: u8 = 0b10000000;
+
+// Real time clock that sits next to the MBC3 on some cartridges
+#[derive(Default)]
+struct RTC {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16,
+    halted: bool,
+    day_carry: bool,
+    latched: [u8; 5],
+    latch_armed: bool,
+    cycles: u32,
+}
+
+impl RTC {
+    fn update(&mut self, cycles: u32) {
+        if self.halted {
+            return;
+        }
+        self.cycles += cycles;
+        while self.cycles >= CYCLES_PER_SECOND {
+            self.cycles -= CYCLES_PER_SECOND;
+            self.tick();
+        }
+    }
+
+    // Counters are only six or five bits wide, so out of range values wrap without a carry
+    fn tick(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days == 512 {
+            self.days = 0;
+            self.day_carry = true;
+        }
+    }
+
+    // Catches the clock up on time that passed while the emulator was closed
+    fn advance_seconds(&mut self, seconds: u64) {
+        if self.halted {
+            return;
+        }
+        let total = self.seconds as u64 + seconds;
+        self.seconds = (total % 60) as u8;
+        let total = self.minutes as u64 + total / 60;
+        self.minutes = (total % 60) as u8;
+        let total = self.hours as u64 + total / 60;
+        self.hours = (total % 24) as u8;
+        let total = self.days as u64 + total / 24;
+        if total >= 512 {
+            self.day_carry = true;
+        }
+        self.days = (total % 512) as u16;
+    }
+
+    fn registers(&self) -> [u8; 5] {
+        let mut day_high = (self.days >> 8) as u8 & DH_DAY_HIGH_BIT;
+        if self.halted {
+            day_high |= DH_HALT_BIT;
+        }
+        if self.day_carry {
+            day_high |= DH_DAY_CARRY_BIT;
+        }
+        [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days as u8,
+            day_high,
+        ]
+    }
+
+    fn set_registers(&mut self, registers: [u8; 5]) {
+        self.seconds = registers[0] & 0x3F;
+        self.minutes = registers[1] & 0x3F;
+        self.hours = registers[2] & 0x1F;
+        self.days = ((registers[4] & DH_DAY_HIGH_BIT) as u16) << 8 | registers[3] as u16;
+        self.halted = registers[4] & DH_HALT_BIT != 0;
+        self.day_carry = registers[4] & DH_DAY_CARRY_BIT != 0;
+    }
+
+    // Writing 0x00 then 0x01 copies the counters into the readable registers
+    fn write_latch(&mut self, data: u8) {
+        if self.latch_armed && data == 0x01 {
+            self.latched = self.registers();
+        }
+        self.latch_armed = data == 0x00;
+    }
+
+    fn read(&self, register: u8) -> u8 {
+        let masks = [0x3F, 0x3F, 0x1F, 0xFF, 0xC1];
+        let index = (register - 0x08) as usize;
+        self.latched[index] & masks[index]
+    }
+
+    fn write(&mut self, register: u8, data: u8) {
+        let index = (register - 0x08) as usize;
+        if index == 0 {
+            // Writing the seconds resets the sub second divider
+            self.cycles = 0;
+        }
+        let mut registers = self.registers();
+        registers[index] = data;
+        self.set_registers(registers);
+        self.latched[index] = data;
+    }
+
+    fn dump(&self) -> Vec<u8> {
+        let mut footer = Vec::with_capacity(RTC_FOOTER_SIZE);
+        for register in self.registers().iter().chain(self.latched.iter()) {
+            footer.extend_from_slice(&(*register as u32).to_le_bytes());
+        }
+        footer.extend_from_slice(&unix_time().to_le_bytes());
+        footer
+    }
+
+    fn load(&mut self, footer: &[u8]) {
+        let mut registers = [0; 10];
+        for (i, register) in registers.iter_mut().enumerate() {
+            *register = footer[i * 4];
+        }
+        self.set_registers(registers[..5].try_into().unwrap());
+        self.latched.copy_from_slice(&registers[5..]);
+        let timestamp = u64::from_le_bytes(footer[40..48].try_into().unwrap());
+        self.advance_seconds(unix_time().saturating_sub(timestamp));
+    }
+}
+
+// Unlike the save file footer, states restore the clock exactly as it was
+impl SaveState for RTC {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.registers());
+        state.write_bytes(&self.latched);
+        state.write_bool(self.latch_armed);
+        state.write_u32(self.cycles);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut registers = [0; 5];
+        state.read_bytes(&mut registers)?;
+        self.set_registers(registers);
+        state.read_bytes(&mut self.latched)?;
+        self.latch_armed = state.read_bool()?;
+        self.cycles = state.read_u32()?;
+        Ok(())
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
 pub struct MBC3 {
     rom: Vec<[u8; ROM_BANK_SIZE]>,
-    ram: Vec<[u8; RAM_BANK_SIZE]>,
+    ram: Vec<u8>,
     ram_enabled: bool,
     rom_bank_index: u8,
     ram_bank_index: u8,
+    rtc: Option<RTC>,
+    // The MBC30 connects all eight rom bank bits and a third ram bank bit
+    mbc30: bool,
 }
 
 impl MBC3 {
-    pub fn new(rom_banks: u8, ram_banks: u8) -> Self {
-        let mut mbc = MBC3 {
-            rom: Vec::with_capacity(rom_banks as usize),
-            ram: Vec::with_capacity(ram_banks as usize),
+    pub fn new(rom_banks: u16, ram_size: usize, has_rtc: bool) -> Self {
+        MBC3 {
+            rom: vec![[0; ROM_BANK_SIZE]; rom_banks as usize],
+            ram: vec![0; ram_size],
             ram_enabled: false,
             rom_bank_index: 0,
             ram_bank_index: 0,
-        };
-        // Initialize rom and ram_banks
-        for _ in 0..rom_banks {
-            mbc.rom.push([0; ROM_BANK_SIZE]);
-        }
-        for _ in 0..ram_banks {
-            mbc.ram.push([0; RAM_BANK_SIZE]);
+            rtc: has_rtc.then(RTC::default),
+            mbc30: false,
         }
-        mbc
     }
 
-    fn init_write(&mut self, address: u16, data: u8) {
-        match address {
-            rom_bank_one_address @ 0x0000..=0x3FFF => {
-                self.rom[0][rom_bank_one_address as usize] = data;
-            }
-            other_rom_banks_address @ 0x4000..=0x7FFF => {
-                let bank_number = if self.rom_bank_index == 0 {
-                    1
-                } else {
-                    self.rom_bank_index
-                };
-                self.rom[bank_number as usize][(other_rom_banks_address - 0x4000) as usize] = data;
-            }
-            external_ram_address @ 0xA000..=0xBFFF if self.ram_enabled => {
-                if (0x08..=0x0C).contains(&self.ram_bank_index) {
-                    // Used for RTC registers (not implemented)
-                    return;
-                }
-                self.ram[self.ram_bank_index as usize][(external_ram_address - 0xA000) as usize] =
-                    data;
-            }
-            _ => (),
+    pub fn new_mbc30(rom_banks: u16, ram_size: usize, has_rtc: bool) -> Self {
+        MBC3 {
+            mbc30: true,
+            ..MBC3::new(rom_banks, ram_size, has_rtc)
         }
     }
+
+    fn is_rtc_selected(&self) -> bool {
+        (0x08..=0x0C).contains(&self.ram_bank_index)
+    }
+
+    // Unconnected bank bits are ignored and banks past the end of the chip wrap around
+    fn get_ram_bank(&self) -> usize {
+        let mask = if self.mbc30 { 0b00000111 } else { 0b00000011 };
+        (self.ram_bank_index & mask) as usize
+    }
+
+    fn get_rom_bank(&self) -> usize {
+        let bank_number = if self.rom_bank_index == 0 {
+            1
+        } else {
+            self.rom_bank_index
+        };
+        bank_number as usize % self.rom.len()
+    }
 }
 
 impl Memory for MBC3 {
@@ -62,19 +239,20 @@ impl Memory for MBC3 {
         match address {
             rom_bank_one_address @ 0x0000..=0x3FFF => self.rom[0][rom_bank_one_address as usize],
             other_rom_banks_address @ 0x4000..=0x7FFF => {
-                let bank_number = if self.rom_bank_index == 0 {
-                    1
-                } else {
-                    self.rom_bank_index
-                };
-                self.rom[bank_number as usize][(other_rom_banks_address - 0x4000) as usize]
+                self.rom[self.get_rom_bank()][(other_rom_banks_address - 0x4000) as usize]
             }
             external_ram_address @ 0xA000..=0xBFFF if self.ram_enabled => {
-                if (0x08..=0x0C).contains(&self.ram_bank_index) {
-                    // Used for RTC registers (not implemented)
-                    return 0xFF;
+                if self.is_rtc_selected() {
+                    return match &self.rtc {
+                        Some(rtc) => rtc.read(self.ram_bank_index),
+                        None => 0xFF,
+                    };
+                }
+                let bank = self.get_ram_bank();
+                match get_ram_offset(&self.ram, bank, external_ram_address) {
+                    Some(offset) => self.ram[offset],
+                    None => 0xFF,
                 }
-                self.ram[self.ram_bank_index as usize][(external_ram_address - 0xA000) as usize]
             }
             _ => 0xFF,
         }
@@ -89,19 +267,30 @@ impl Memory for MBC3 {
             }
             // Rom bank select register
             0x2000..=0x3FFF => {
-                self.rom_bank_index = data;
+                let mask = if self.mbc30 { 0xFF } else { 0b01111111 };
+                self.rom_bank_index = data & mask;
             }
             // Ram bank select register
             0x4000..=0x5FFF => {
                 self.ram_bank_index = data;
             }
+            // Clock latch register
+            0x6000..=0x7FFF => {
+                if let Some(rtc) = self.rtc.as_mut() {
+                    rtc.write_latch(data);
+                }
+            }
             external_ram_address @ 0xA000..=0xBFFF if self.ram_enabled => {
-                if (0x08..=0x0C).contains(&self.ram_bank_index) {
-                    // Used for RTC registers (not implemented)
+                if self.is_rtc_selected() {
+                    if let Some(rtc) = self.rtc.as_mut() {
+                        rtc.write(self.ram_bank_index, data);
+                    }
                     return;
                 }
-                self.ram[self.ram_bank_index as usize][(external_ram_address - 0xA000) as usize] =
-                    data;
+                let bank = self.get_ram_bank();
+                if let Some(offset) = get_ram_offset(&self.ram, bank, external_ram_address) {
+                    self.ram[offset] = data;
+                }
             }
             _ => (),
         }
@@ -110,20 +299,178 @@ impl Memory for MBC3 {
 
 impl MBC for MBC3 {
     fn init(&mut self, program: &Vec<u8>) {
-        let rom_select_address = 0x2000;
-        for i in 0..self.rom.len() {
-            self.write(rom_select_address, i as u8);
-            // Figure out whether the data should be written to first or second area of rom
-            let bank_offset = if i == 0 { 0 } else { 0x4000 };
-            for j in 0..ROM_BANK_SIZE {
-                self.init_write(
-                    bank_offset + j as u16,
-                    program[ROM_BANK_SIZE * i as usize + j],
-                )
+        load_rom_banks(&mut self.rom, program);
+    }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if let Some(rtc) = &self.rtc {
+            data.extend(rtc.dump());
+        }
+        data
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        load_ram_data(&mut self.ram, data);
+        let ram_size = self.ram.len();
+        if let Some(rtc) = self.rtc.as_mut() {
+            if data.len() >= ram_size + RTC_FOOTER_SIZE {
+                rtc.load(&data[ram_size..ram_size + RTC_FOOTER_SIZE]);
             }
         }
+    }
+
+    fn get_timestamp_size(&self) -> usize {
+        if self.rtc.is_some() {
+            TIMESTAMP_SIZE
+        } else {
+            0
+        }
+    }
+
+    fn get_mapped_rom_bank(&self, address: u16) -> usize {
+        if address < 0x4000 {
+            0
+        } else {
+            self.get_rom_bank()
+        }
+    }
+
+    fn update(&mut self, cycles: u32) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.update(cycles);
+        }
+    }
+}
+
+impl SaveState for MBC3 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_vec(&self.ram);
+        state.write_bool(self.ram_enabled);
+        state.write_u8(self.rom_bank_index);
+        state.write_u8(self.ram_bank_index);
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_ram_state(&mut self.ram, state)?;
+        self.ram_enabled = state.read_bool()?;
+        self.rom_bank_index = state.read_u8()?;
+        self.ram_bank_index = state.read_u8()?;
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.load_state(state)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::RAM_BANK_SIZE;
+
+    fn setup_mbc_with_rtc() -> MBC3 {
+        let mut mbc = MBC3::new(2, RAM_BANK_SIZE, true);
+        mbc.write(0x0000, 0x0A);
+        mbc
+    }
+
+    fn latch(mbc: &mut MBC3) {
+        mbc.write(0x6000, 0x00);
+        mbc.write(0x6000, 0x01);
+    }
+
+    fn read_rtc(mbc: &mut MBC3, register: u8) -> u8 {
+        mbc.write(0x4000, register);
+        mbc.read(0xA000)
+    }
+
+    #[test]
+    fn rtc_advances_from_cycles_after_latch() {
+        let mut mbc = setup_mbc_with_rtc();
+        mbc.update(CYCLES_PER_SECOND * 61);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 0);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 1);
+        assert_eq!(read_rtc(&mut mbc, 0x09), 1);
+    }
+
+    #[test]
+    fn latch_needs_zero_written_first() {
+        let mut mbc = setup_mbc_with_rtc();
+        mbc.update(CYCLES_PER_SECOND * 5);
+        mbc.write(0x6000, 0x01);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 0);
+    }
+
+    #[test]
+    fn halted_rtc_does_not_advance() {
+        let mut mbc = setup_mbc_with_rtc();
+        mbc.write(0x4000, 0x0C);
+        mbc.write(0xA000, DH_HALT_BIT);
+        mbc.update(CYCLES_PER_SECOND * 5);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 0);
+        assert_eq!(read_rtc(&mut mbc, 0x0C), DH_HALT_BIT);
+    }
+
+    #[test]
+    fn day_counter_overflow_sets_carry() {
+        let mut mbc = setup_mbc_with_rtc();
+        let values = [
+            (0x08, 59),
+            (0x09, 59),
+            (0x0A, 23),
+            (0x0B, 0xFF),
+            (0x0C, 0x01),
+        ];
+        for (register, value) in values {
+            mbc.write(0x4000, register);
+            mbc.write(0xA000, value);
+        }
+        mbc.update(CYCLES_PER_SECOND);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x0B), 0);
+        assert_eq!(read_rtc(&mut mbc, 0x0C), DH_DAY_CARRY_BIT);
+    }
+
+    #[test]
+    fn rtc_footer_follows_ram() {
+        let mut mbc = setup_mbc_with_rtc();
+        mbc.write(0x4000, 0x0A);
+        mbc.write(0xA000, 12);
+        let data = mbc.dump_ram();
+        assert_eq!(data.len(), RAM_BANK_SIZE + RTC_FOOTER_SIZE);
+
+        let mut loaded = setup_mbc_with_rtc();
+        loaded.load_ram(&data);
+        latch(&mut loaded);
+        assert_eq!(read_rtc(&mut loaded, 0x0A), 12);
+    }
+
+    #[test]
+    fn mbc30_reaches_more_rom_and_ram_banks() {
+        let program: Vec<u8> = (0..=255).flat_map(|bank| [bank; ROM_BANK_SIZE]).collect();
+        let mut mbc = MBC3::new_mbc30(256, RAM_BANK_SIZE * 8, true);
+        mbc.init(&program);
+        mbc.write(0x2000, 0xC3);
+        assert_eq!(mbc.read(0x4000), 0xC3);
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0x4000, 0x07);
+        mbc.write(0xA000, 0x42);
+        mbc.write(0x4000, 0x03);
+        assert_eq!(mbc.read(0xA000), 0x00);
 
-        // Set rom select register back to initial value of zero
-        self.write(rom_select_address, 0);
+        let mut mbc = MBC3::new(128, RAM_BANK_SIZE * 4, false);
+        mbc.init(&program);
+        mbc.write(0x2000, 0xC3);
+        assert_eq!(mbc.read(0x4000), 0x43);
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0x4000, 0x07);
+        mbc.write(0xA000, 0x42);
+        mbc.write(0x4000, 0x03);
+        assert_eq!(mbc.read(0xA000), 0x42);
     }
 }
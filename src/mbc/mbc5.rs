@@ -1,11 +1,12 @@
-use crate::{mbc::MBC, memory::Memory};
+use crate::mbc::{get_ram_offset, load_ram_data, load_ram_state, load_rom_banks, MBC};
+use crate::memory::Memory;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 
 const ROM_BANK_SIZE: usize = 0x4000;
-const RAM_BANK_SIZE: usize = 0x2000;
 
 pub struct MBC5 {
     rom: Vec<[u8; ROM_BANK_SIZE]>,
-    ram: Vec<[u8; RAM_BANK_SIZE]>,
+    ram: Vec<u8>,
     ram_enabled: bool,
     lower_rom_bank_index: u8,
     upper_rom_bank_bit: bool,
@@ -13,45 +14,24 @@ pub struct MBC5 {
 }
 
 impl MBC5 {
-    pub fn new(rom_banks: u8, ram_banks: u8) -> Self {
-        let mut mbc = MBC5 {
-            rom: Vec::with_capacity(rom_banks as usize),
-            ram: Vec::with_capacity(ram_banks as usize),
+    pub fn new(rom_banks: u16, ram_size: usize) -> Self {
+        MBC5 {
+            rom: vec![[0; ROM_BANK_SIZE]; rom_banks as usize],
+            ram: vec![0; ram_size],
             ram_enabled: false,
             lower_rom_bank_index: 0,
             upper_rom_bank_bit: false,
             ram_bank_index: 0,
-        };
-        // Initialize rom and ram_banks
-        for _ in 0..rom_banks {
-            mbc.rom.push([0; ROM_BANK_SIZE]);
-        }
-        for _ in 0..ram_banks {
-            mbc.ram.push([0; RAM_BANK_SIZE]);
         }
-        mbc
     }
 
-    fn init_write(&mut self, address: u16, data: u8) {
-        match address {
-            rom_bank_one_address @ 0x0000..=0x3FFF => {
-                self.rom[0][rom_bank_one_address as usize] = data;
-            }
-            other_rom_banks_address @ 0x4000..=0x7FFF => {
-                let high_bit = if self.upper_rom_bank_bit {
-                    0b1_0000_0000
-                } else {
-                    0
-                };
-                self.rom[high_bit | self.lower_rom_bank_index as usize]
-                    [(other_rom_banks_address - 0x4000) as usize] = data;
-            }
-            external_ram_address @ 0xA000..=0xBFFF if self.ram_enabled => {
-                self.ram[self.ram_bank_index as usize][(external_ram_address - 0xA000) as usize] =
-                    data;
-            }
-            _ => (),
-        }
+    fn get_rom_bank(&self) -> usize {
+        let high_bit = if self.upper_rom_bank_bit {
+            0b1_0000_0000
+        } else {
+            0
+        };
+        (high_bit | self.lower_rom_bank_index as usize) % self.rom.len()
     }
 }
 
@@ -60,16 +40,14 @@ impl Memory for MBC5 {
         match address {
             rom_bank_one_address @ 0x0000..=0x3FFF => self.rom[0][rom_bank_one_address as usize],
             other_rom_banks_address @ 0x4000..=0x7FFF => {
-                let high_bit = if self.upper_rom_bank_bit {
-                    0b1_0000_0000
-                } else {
-                    0
-                };
-                self.rom[high_bit | self.lower_rom_bank_index as usize]
-                    [(other_rom_banks_address - 0x4000) as usize]
+                self.rom[self.get_rom_bank()][(other_rom_banks_address - 0x4000) as usize]
             }
             external_ram_address @ 0xA000..=0xBFFF if self.ram_enabled => {
-                self.ram[self.ram_bank_index as usize][(external_ram_address - 0xA000) as usize]
+                let bank = self.ram_bank_index as usize;
+                match get_ram_offset(&self.ram, bank, external_ram_address) {
+                    Some(offset) => self.ram[offset],
+                    None => 0xFF,
+                }
             }
             _ => 0xFF,
         }
@@ -84,26 +62,21 @@ impl Memory for MBC5 {
             }
             // Rom bank select register for lower 8 bits
             0x2000..=0x2FFF => {
-                let mask = if data as usize > self.rom.len() {
-                    // Cut off bits if the rom bank would be too high for the cartridge
-                    self.rom.len() as u8 - 1
-                } else {
-                    // Else use all bits
-                    0xFF
-                };
-                self.lower_rom_bank_index = mask & data;
+                self.lower_rom_bank_index = data;
             }
             // Rom bank select register for highest bit
             0x3000..=0x3FFF => {
-                self.upper_rom_bank_bit = if data == 0 { false } else { true };
+                self.upper_rom_bank_bit = data & 0b00000001 != 0;
             }
             // Ram bank select register
             0x4000..=0x5FFF => {
-                self.ram_bank_index = data;
+                self.ram_bank_index = data & 0b00001111;
             }
             external_ram_address @ 0xA000..=0xBFFF if self.ram_enabled => {
-                self.ram[self.ram_bank_index as usize][(external_ram_address - 0xA000) as usize] =
-                    data;
+                let bank = self.ram_bank_index as usize;
+                if let Some(offset) = get_ram_offset(&self.ram, bank, external_ram_address) {
+                    self.ram[offset] = data;
+                }
             }
             _ => (),
         }
@@ -112,26 +85,64 @@ impl Memory for MBC5 {
 
 impl MBC for MBC5 {
     fn init(&mut self, program: &Vec<u8>) {
-        let lower_rom_select_address = 0x2000;
-        let upper_rom_select_address = 0x3000;
-        for i in 0..self.rom.len() {
-            self.write(lower_rom_select_address, i as u8);
-            if i == 0x100 {
-                // Used to initialize the banks past 0xFF
-                self.write(upper_rom_select_address, 1);
-            }
+        load_rom_banks(&mut self.rom, program);
+    }
 
-            let other_rom_banks_start = 0x4000;
-            for j in 0..ROM_BANK_SIZE {
-                self.init_write(
-                    other_rom_banks_start + j as u16,
-                    program[ROM_BANK_SIZE * i as usize + j],
-                )
-            }
+    fn dump_ram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        load_ram_data(&mut self.ram, data);
+    }
+
+    fn get_mapped_rom_bank(&self, address: u16) -> usize {
+        if address < 0x4000 {
+            0
+        } else {
+            self.get_rom_bank()
         }
+    }
+}
+
+impl SaveState for MBC5 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_vec(&self.ram);
+        state.write_bool(self.ram_enabled);
+        state.write_u8(self.lower_rom_bank_index);
+        state.write_bool(self.upper_rom_bank_bit);
+        state.write_u8(self.ram_bank_index);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_ram_state(&mut self.ram, state)?;
+        self.ram_enabled = state.read_bool()?;
+        self.lower_rom_bank_index = state.read_u8()?;
+        self.upper_rom_bank_bit = state.read_bool()?;
+        self.ram_bank_index = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn banks_past_0xff_are_reachable_and_wrap() {
+        let mut mbc = MBC5::new(512, 0);
+        let program: Vec<u8> = (0..512u16)
+            .flat_map(|bank| [(bank >> 1) as u8; ROM_BANK_SIZE])
+            .collect();
+        mbc.init(&program);
+        mbc.write(0x2000, 0x22);
+        mbc.write(0x3000, 1);
+        assert_eq!(mbc.read(0x4000), 0x91);
 
-        // Set rom select registers back to initial values
-        self.write(lower_rom_select_address, 0);
-        self.upper_rom_bank_bit = false;
+        let mut mbc = MBC5::new(72, 0);
+        mbc.init(&program);
+        mbc.write(0x2000, 72 + 4);
+        mbc.write(0x3000, 0);
+        assert_eq!(mbc.read(0x4000), 2);
     }
 }
@@ -0,0 +1,500 @@
+use crate::mbc::{load_ram_data, load_rom_banks, MBC};
+use crate::memory::Memory;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+// A 93LC56 organised as 128 sixteen bit words
+const EEPROM_WORDS: usize = 128;
+
+// Accelerometer readings when the cartridge is held flat and the change for a full 1g tilt
+const ACCELEROMETER_CENTER: u16 = 0x81D0;
+const ACCELEROMETER_RANGE: f32 = 0x70 as f32;
+// Readings after an erase until the next latch
+const ACCELEROMETER_ERASED: u16 = 0x8000;
+
+const EEPROM_CS_BIT: u8 = 0b10000000;
+const EEPROM_CLK_BIT: u8 = 0b01000000;
+const EEPROM_DI_BIT: u8 = 0b00000010;
+const EEPROM_DO_BIT: u8 = 0b00000001;
+
+// Start bit, two opcode bits and eight address bits
+const EEPROM_COMMAND_BITS: u8 = 11;
+
+// Feeds the accelerometer, each axis goes from -1.0 to 1.0 where positive x is tipped to the
+// right and positive y is tipped towards the player
+pub trait TiltSource {
+    fn get_tilt(&self) -> (f32, f32);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum EEPROMState {
+    Idle,
+    Command,
+    Read,
+    Write,
+    WriteAll,
+}
+
+// Serial eeprom that is bit banged through a single register
+struct EEPROM {
+    words: [u16; EEPROM_WORDS],
+    chip_select: bool,
+    clock: bool,
+    data_in: bool,
+    data_out: bool,
+    write_enabled: bool,
+    state: EEPROMState,
+    shift: u16,
+    bits: u8,
+    address: u8,
+}
+
+impl EEPROM {
+    fn new() -> Self {
+        EEPROM {
+            words: [0xFFFF; EEPROM_WORDS],
+            chip_select: false,
+            clock: false,
+            data_in: false,
+            data_out: true,
+            write_enabled: false,
+            state: EEPROMState::Idle,
+            shift: 0,
+            bits: 0,
+            address: 0,
+        }
+    }
+
+    fn read(&self) -> u8 {
+        let mut value = 0;
+        if self.chip_select {
+            value |= EEPROM_CS_BIT;
+        }
+        if self.clock {
+            value |= EEPROM_CLK_BIT;
+        }
+        if self.data_in {
+            value |= EEPROM_DI_BIT;
+        }
+        if self.data_out {
+            value |= EEPROM_DO_BIT;
+        }
+        value
+    }
+
+    fn write(&mut self, data: u8) {
+        let chip_select = data & EEPROM_CS_BIT != 0;
+        let clock = data & EEPROM_CLK_BIT != 0;
+        self.data_in = data & EEPROM_DI_BIT != 0;
+
+        if !chip_select {
+            // Dropping chip select aborts whatever was going on
+            self.state = EEPROMState::Idle;
+        } else if !self.clock && clock {
+            self.clock_in();
+        }
+        self.chip_select = chip_select;
+        self.clock = clock;
+    }
+
+    // Everything happens on the rising edge of the clock
+    fn clock_in(&mut self) {
+        let bit = self.data_in as u16;
+        match self.state {
+            EEPROMState::Idle => {
+                if bit == 1 {
+                    self.state = EEPROMState::Command;
+                    self.shift = 1;
+                    self.bits = 1;
+                }
+            }
+            EEPROMState::Command => {
+                self.shift = (self.shift << 1) | bit;
+                self.bits += 1;
+                if self.bits == EEPROM_COMMAND_BITS {
+                    self.run_command();
+                }
+            }
+            EEPROMState::Read => {
+                self.data_out = self.shift & 0x8000 != 0;
+                self.shift <<= 1;
+                self.bits -= 1;
+                if self.bits == 0 {
+                    self.state = EEPROMState::Idle;
+                }
+            }
+            EEPROMState::Write | EEPROMState::WriteAll => {
+                self.shift = (self.shift << 1) | bit;
+                self.bits += 1;
+                if self.bits == 16 {
+                    if self.write_enabled {
+                        if self.state == EEPROMState::Write {
+                            self.words[self.address as usize] = self.shift;
+                        } else {
+                            self.words = [self.shift; EEPROM_WORDS];
+                        }
+                    }
+                    // Writes finish instantly so the chip reports ready straight away
+                    self.data_out = true;
+                    self.state = EEPROMState::Idle;
+                }
+            }
+        }
+    }
+
+    fn run_command(&mut self) {
+        let opcode = (self.shift >> 8) & 0b11;
+        let address_bits = self.shift as u8;
+        // The top address bit isn't used by a chip this size
+        let address = address_bits & 0x7F;
+        self.state = EEPROMState::Idle;
+        self.shift = 0;
+        self.bits = 0;
+        match opcode {
+            0b10 => {
+                // A dummy zero comes out before the word
+                self.data_out = false;
+                self.state = EEPROMState::Read;
+                self.shift = self.words[address as usize];
+                self.bits = 16;
+            }
+            0b01 => {
+                self.state = EEPROMState::Write;
+                self.address = address;
+            }
+            0b11 => {
+                if self.write_enabled {
+                    self.words[address as usize] = 0xFFFF;
+                }
+                self.data_out = true;
+            }
+            // The rest are told apart by the top two address bits
+            _ => match address_bits >> 6 {
+                0b00 => self.write_enabled = false,
+                0b01 => self.state = EEPROMState::WriteAll,
+                0b10 => {
+                    if self.write_enabled {
+                        self.words = [0xFFFF; EEPROM_WORDS];
+                    }
+                    self.data_out = true;
+                }
+                _ => self.write_enabled = true,
+            },
+        }
+    }
+
+    fn dump(&self) -> Vec<u8> {
+        self.words
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect()
+    }
+
+    fn load(&mut self, data: &[u8]) {
+        let mut bytes = self.dump();
+        load_ram_data(&mut bytes, data);
+        for (word, chunk) in self.words.iter_mut().zip(bytes.chunks(2)) {
+            *word = u16::from_le_bytes([chunk[0], chunk[1]]);
+        }
+    }
+}
+
+impl SaveState for EEPROM {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_vec(&self.dump());
+        state.write_bool(self.chip_select);
+        state.write_bool(self.clock);
+        state.write_bool(self.data_in);
+        state.write_bool(self.data_out);
+        state.write_bool(self.write_enabled);
+        state.write_u8(self.state as u8);
+        state.write_u16(self.shift);
+        state.write_u8(self.bits);
+        state.write_u8(self.address);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let data = state.read_vec()?;
+        if data.len() != EEPROM_WORDS * 2 {
+            return Err(StateError::Invalid("eeprom size"));
+        }
+        self.load(&data);
+        self.chip_select = state.read_bool()?;
+        self.clock = state.read_bool()?;
+        self.data_in = state.read_bool()?;
+        self.data_out = state.read_bool()?;
+        self.write_enabled = state.read_bool()?;
+        self.state = match state.read_u8()? {
+            0 => EEPROMState::Idle,
+            1 => EEPROMState::Command,
+            2 => EEPROMState::Read,
+            3 => EEPROMState::Write,
+            4 => EEPROMState::WriteAll,
+            _ => return Err(StateError::Invalid("eeprom state")),
+        };
+        self.shift = state.read_u16()?;
+        self.bits = state.read_u8()?;
+        self.address = state.read_u8()?;
+        if self.address as usize >= EEPROM_WORDS {
+            return Err(StateError::Invalid("eeprom address"));
+        }
+        Ok(())
+    }
+}
+
+pub struct MBC7 {
+    rom: Vec<[u8; ROM_BANK_SIZE]>,
+    rom_bank_index: u8,
+    // Registers only show up once both enables are set
+    ram_enabled: bool,
+    registers_enabled: bool,
+    x_latch: u16,
+    y_latch: u16,
+    latched: bool,
+    eeprom: EEPROM,
+    tilt: Option<Box<dyn TiltSource>>,
+}
+
+impl MBC7 {
+    pub fn new(rom_banks: u16) -> Self {
+        MBC7 {
+            rom: vec![[0; ROM_BANK_SIZE]; rom_banks as usize],
+            rom_bank_index: 1,
+            ram_enabled: false,
+            registers_enabled: false,
+            x_latch: ACCELEROMETER_ERASED,
+            y_latch: ACCELEROMETER_ERASED,
+            latched: false,
+            eeprom: EEPROM::new(),
+            tilt: None,
+        }
+    }
+
+    fn latch_accelerometer(&mut self) {
+        let (x, y) = match &self.tilt {
+            Some(tilt) => tilt.get_tilt(),
+            None => (0.0, 0.0),
+        };
+        let to_reading = |tilt: f32| {
+            (ACCELEROMETER_CENTER as f32 - tilt.clamp(-1.0, 1.0) * ACCELEROMETER_RANGE) as u16
+        };
+        self.x_latch = to_reading(x);
+        self.y_latch = to_reading(y);
+        self.latched = true;
+    }
+
+    fn read_register(&self, address: u16) -> u8 {
+        match (address >> 4) & 0x0F {
+            0x2 => self.x_latch as u8,
+            0x3 => (self.x_latch >> 8) as u8,
+            0x4 => self.y_latch as u8,
+            0x5 => (self.y_latch >> 8) as u8,
+            0x6 => 0x00,
+            0x8 => self.eeprom.read(),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_register(&mut self, address: u16, data: u8) {
+        match (address >> 4) & 0x0F {
+            0x0 if data == 0x55 => {
+                self.x_latch = ACCELEROMETER_ERASED;
+                self.y_latch = ACCELEROMETER_ERASED;
+                self.latched = false;
+            }
+            0x1 if data == 0xAA && !self.latched => self.latch_accelerometer(),
+            0x8 => self.eeprom.write(data),
+            _ => (),
+        }
+    }
+
+    fn get_rom_bank(&self) -> usize {
+        self.rom_bank_index as usize % self.rom.len()
+    }
+}
+
+impl Memory for MBC7 {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            rom_bank_one_address @ 0x0000..=0x3FFF => self.rom[0][rom_bank_one_address as usize],
+            other_rom_banks_address @ 0x4000..=0x7FFF => {
+                self.rom[self.get_rom_bank()][(other_rom_banks_address - 0x4000) as usize]
+            }
+            register_address @ 0xA000..=0xAFFF if self.ram_enabled && self.registers_enabled => {
+                self.read_register(register_address)
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            // Ram enable register
+            0x0000..=0x1FFF => {
+                self.ram_enabled = data & 0b00001111 == 0x0A;
+            }
+            // Rom bank select register
+            0x2000..=0x3FFF => {
+                self.rom_bank_index = data;
+            }
+            // Second enable that only accepts 0x40
+            0x4000..=0x5FFF => {
+                self.registers_enabled = data == 0x40;
+            }
+            register_address @ 0xA000..=0xAFFF if self.ram_enabled && self.registers_enabled => {
+                self.write_register(register_address, data);
+            }
+            _ => (),
+        }
+    }
+}
+
+impl MBC for MBC7 {
+    fn init(&mut self, program: &Vec<u8>) {
+        load_rom_banks(&mut self.rom, program);
+    }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        self.eeprom.dump()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        self.eeprom.load(data);
+    }
+
+    fn get_mapped_rom_bank(&self, address: u16) -> usize {
+        if address < 0x4000 {
+            0
+        } else {
+            self.get_rom_bank()
+        }
+    }
+
+    fn set_tilt_source(&mut self, source: Box<dyn TiltSource>) {
+        self.tilt = Some(source);
+    }
+}
+
+impl SaveState for MBC7 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.rom_bank_index);
+        state.write_bool(self.ram_enabled);
+        state.write_bool(self.registers_enabled);
+        state.write_u16(self.x_latch);
+        state.write_u16(self.y_latch);
+        state.write_bool(self.latched);
+        self.eeprom.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rom_bank_index = state.read_u8()?;
+        self.ram_enabled = state.read_bool()?;
+        self.registers_enabled = state.read_bool()?;
+        self.x_latch = state.read_u16()?;
+        self.y_latch = state.read_u16()?;
+        self.latched = state.read_bool()?;
+        self.eeprom.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixedTilt(f32, f32);
+
+    impl TiltSource for FixedTilt {
+        fn get_tilt(&self) -> (f32, f32) {
+            (self.0, self.1)
+        }
+    }
+
+    fn get_test_mbc() -> MBC7 {
+        let mut mbc = MBC7::new(2);
+        mbc.write(0x0000, 0x0A);
+        mbc.write(0x4000, 0x40);
+        mbc
+    }
+
+    fn clock_bit(mbc: &mut MBC7, bit: u16) -> bool {
+        let data_in = if bit != 0 { EEPROM_DI_BIT } else { 0 };
+        mbc.write(0xA080, EEPROM_CS_BIT | data_in);
+        mbc.write(0xA080, EEPROM_CS_BIT | EEPROM_CLK_BIT | data_in);
+        mbc.read(0xA080) & EEPROM_DO_BIT != 0
+    }
+
+    fn send_bits(mbc: &mut MBC7, value: u16, count: u8) {
+        for i in (0..count).rev() {
+            clock_bit(mbc, (value >> i) & 1);
+        }
+    }
+
+    fn end_command(mbc: &mut MBC7) {
+        mbc.write(0xA080, 0x00);
+    }
+
+    fn read_word(mbc: &mut MBC7, address: u16) -> u16 {
+        send_bits(mbc, 0b110_0000_0000 | address, EEPROM_COMMAND_BITS);
+        let mut word = 0;
+        for _ in 0..16 {
+            word = (word << 1) | clock_bit(mbc, 0) as u16;
+        }
+        end_command(mbc);
+        word
+    }
+
+    #[test]
+    fn accelerometer_latches_tilt_once_after_erase() {
+        let mut mbc = get_test_mbc();
+        mbc.set_tilt_source(Box::new(FixedTilt(0.0, 0.0)));
+        mbc.write(0xA000, 0x55);
+        mbc.write(0xA010, 0xAA);
+        assert_eq!(mbc.read(0xA020), 0xD0);
+        assert_eq!(mbc.read(0xA030), 0x81);
+
+        mbc.set_tilt_source(Box::new(FixedTilt(1.0, -1.0)));
+        mbc.write(0xA010, 0xAA);
+        assert_eq!(mbc.read(0xA030), 0x81);
+
+        mbc.write(0xA000, 0x55);
+        assert_eq!(mbc.read(0xA030), 0x80);
+        mbc.write(0xA010, 0xAA);
+        let x = u16::from_le_bytes([mbc.read(0xA020), mbc.read(0xA030)]);
+        let y = u16::from_le_bytes([mbc.read(0xA040), mbc.read(0xA050)]);
+        assert_eq!(x, 0x81D0 - 0x70);
+        assert_eq!(y, 0x81D0 + 0x70);
+    }
+
+    #[test]
+    fn registers_need_both_enables() {
+        let mut mbc = MBC7::new(2);
+        mbc.write(0x0000, 0x0A);
+        assert_eq!(mbc.read(0xA060), 0xFF);
+        mbc.write(0x4000, 0x40);
+        assert_eq!(mbc.read(0xA060), 0x00);
+    }
+
+    #[test]
+    fn eeprom_words_can_be_written_and_read_back() {
+        let mut mbc = get_test_mbc();
+        // Writes are ignored until enabled
+        send_bits(&mut mbc, 0b101_0000_0011, EEPROM_COMMAND_BITS);
+        send_bits(&mut mbc, 0x1234, 16);
+        end_command(&mut mbc);
+        assert_eq!(read_word(&mut mbc, 3), 0xFFFF);
+
+        send_bits(&mut mbc, 0b100_1100_0000, EEPROM_COMMAND_BITS);
+        end_command(&mut mbc);
+        send_bits(&mut mbc, 0b101_0000_0011, EEPROM_COMMAND_BITS);
+        send_bits(&mut mbc, 0x1234, 16);
+        assert!(mbc.read(0xA080) & EEPROM_DO_BIT != 0);
+        end_command(&mut mbc);
+        assert_eq!(read_word(&mut mbc, 3), 0x1234);
+
+        let data = mbc.dump_ram();
+        assert_eq!(data.len(), EEPROM_WORDS * 2);
+        let mut other = get_test_mbc();
+        other.load_ram(&data);
+        assert_eq!(read_word(&mut other, 3), 0x1234);
+    }
+}
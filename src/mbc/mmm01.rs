@@ -0,0 +1,284 @@
+use crate::mbc::{get_ram_offset, load_ram_data, load_ram_state, load_rom_banks, MBC};
+use crate::memory::Memory;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+// Until the menu maps a game every rom line is pulled high, showing the menu in the last 32 KB
+const MENU_LOWER_BANK: usize = 0x1FE;
+const MENU_UPPER_BANK: usize = 0x1FF;
+
+// Multi game collections built on an MBC1 like controller. The menu picks a game by setting the
+// upper bank bits and which bits the game may still change, then locks them in place.
+pub struct MMM01 {
+    rom: Vec<[u8; ROM_BANK_SIZE]>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    // Everything besides ram enable, the unmasked bank bits and the banking mode is frozen once set
+    mapped: bool,
+    rom_bank_low: u8,
+    rom_bank_mid: u8,
+    rom_bank_high: u8,
+    ram_bank_low: u8,
+    ram_bank_high: u8,
+    // Set bits of the low bank registers belong to the menu and can't be changed by the game,
+    // the rom bank mask starts at bit 1
+    rom_bank_mask: u8,
+    ram_bank_mask: u8,
+    using_ram_banking: bool,
+    banking_mode_locked: bool,
+    // Swaps the roles of the low ram bank and middle rom bank bits
+    multiplex: bool,
+}
+
+impl MMM01 {
+    pub fn new(rom_banks: u16, ram_size: usize) -> Self {
+        MMM01 {
+            rom: vec![[0; ROM_BANK_SIZE]; rom_banks as usize],
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            mapped: false,
+            rom_bank_low: 0,
+            rom_bank_mid: 0,
+            rom_bank_high: 0,
+            ram_bank_low: 0,
+            ram_bank_high: 0,
+            rom_bank_mask: 0,
+            ram_bank_mask: 0,
+            using_ram_banking: false,
+            banking_mode_locked: false,
+            multiplex: false,
+        }
+    }
+
+    // Bits 5-6 of the rom bank, the multiplexed low ram bank register only reaches the lower
+    // area in ram banking mode like it would on an MBC1
+    fn get_rom_bank_mid(&self, is_lower_area: bool) -> u8 {
+        if !self.multiplex {
+            self.rom_bank_mid
+        } else if is_lower_area && !self.using_ram_banking {
+            0
+        } else {
+            self.ram_bank_low
+        }
+    }
+
+    fn get_upper_rom_bank(&self) -> usize {
+        if !self.mapped {
+            return MENU_UPPER_BANK % self.rom.len();
+        }
+        // Like the MBC1, bank 0 can't be selected with the bits the game controls
+        let mut low = self.rom_bank_low;
+        if low & !(self.rom_bank_mask << 1) == 0 {
+            low |= 1;
+        }
+        let bank = (self.rom_bank_high as usize) << 7
+            | (self.get_rom_bank_mid(false) as usize) << 5
+            | low as usize;
+        bank % self.rom.len()
+    }
+
+    // The lower area only sees the bits of the low register the menu claimed
+    fn get_lower_rom_bank(&self) -> usize {
+        if !self.mapped {
+            return MENU_LOWER_BANK % self.rom.len();
+        }
+        let low = self.rom_bank_low & (self.rom_bank_mask << 1);
+        let bank = (self.rom_bank_high as usize) << 7
+            | (self.get_rom_bank_mid(true) as usize) << 5
+            | low as usize;
+        bank % self.rom.len()
+    }
+
+    fn get_ram_bank(&self) -> usize {
+        let low = if self.multiplex {
+            self.rom_bank_mid
+        } else if self.using_ram_banking {
+            self.ram_bank_low
+        } else {
+            self.ram_bank_low & self.ram_bank_mask
+        };
+        ((self.ram_bank_high << 2) | low) as usize
+    }
+}
+
+impl Memory for MMM01 {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            rom_bank_one_address @ 0x0000..=0x3FFF => {
+                self.rom[self.get_lower_rom_bank()][rom_bank_one_address as usize]
+            }
+            other_rom_banks_address @ 0x4000..=0x7FFF => {
+                self.rom[self.get_upper_rom_bank()][(other_rom_banks_address - 0x4000) as usize]
+            }
+            external_ram_address @ 0xA000..=0xBFFF if self.ram_enabled => {
+                match get_ram_offset(&self.ram, self.get_ram_bank(), external_ram_address) {
+                    Some(offset) => self.ram[offset],
+                    None => 0xFF,
+                }
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            // Ram enable register, while unmapped it also holds the ram bank mask and the bit
+            // that maps the selected game
+            0x0000..=0x1FFF => {
+                self.ram_enabled = data & 0b00001111 == 0x0A;
+                if !self.mapped {
+                    self.ram_bank_mask = (data >> 4) & 0b00000011;
+                    self.mapped = data & 0b01000000 != 0;
+                }
+            }
+            // Rom bank select register, bits 5-6 are the middle rom bank bits
+            0x2000..=0x3FFF => {
+                let writable = if self.mapped {
+                    !(self.rom_bank_mask << 1) & 0b00011111
+                } else {
+                    0b00011111
+                };
+                self.rom_bank_low = (self.rom_bank_low & !writable) | (data & writable);
+                if !self.mapped {
+                    self.rom_bank_mid = (data >> 5) & 0b00000011;
+                }
+            }
+            // Ram bank select register, the upper bits hold the high ram and rom bank bits
+            0x4000..=0x5FFF => {
+                let writable = if self.mapped {
+                    !self.ram_bank_mask & 0b00000011
+                } else {
+                    0b00000011
+                };
+                self.ram_bank_low = (self.ram_bank_low & !writable) | (data & writable);
+                if !self.mapped {
+                    self.ram_bank_high = (data >> 2) & 0b00000011;
+                    self.rom_bank_high = (data >> 4) & 0b00000011;
+                    self.banking_mode_locked = data & 0b01000000 != 0;
+                }
+            }
+            // Banking mode select register, the upper bits hold the rom bank mask and multiplex
+            0x6000..=0x7FFF => {
+                if !self.banking_mode_locked {
+                    self.using_ram_banking = data & 0b00000001 != 0;
+                }
+                if !self.mapped {
+                    self.rom_bank_mask = (data >> 2) & 0b00001111;
+                    self.multiplex = data & 0b01000000 != 0;
+                }
+            }
+            external_ram_address @ 0xA000..=0xBFFF if self.ram_enabled => {
+                if let Some(offset) =
+                    get_ram_offset(&self.ram, self.get_ram_bank(), external_ram_address)
+                {
+                    self.ram[offset] = data;
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl MBC for MMM01 {
+    fn init(&mut self, program: &Vec<u8>) {
+        load_rom_banks(&mut self.rom, program);
+    }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        load_ram_data(&mut self.ram, data);
+    }
+
+    fn get_mapped_rom_bank(&self, address: u16) -> usize {
+        if address < 0x4000 {
+            self.get_lower_rom_bank()
+        } else {
+            self.get_upper_rom_bank()
+        }
+    }
+}
+
+impl SaveState for MMM01 {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_vec(&self.ram);
+        state.write_bool(self.ram_enabled);
+        state.write_bool(self.mapped);
+        state.write_u8(self.rom_bank_low);
+        state.write_u8(self.rom_bank_mid);
+        state.write_u8(self.rom_bank_high);
+        state.write_u8(self.ram_bank_low);
+        state.write_u8(self.ram_bank_high);
+        state.write_u8(self.rom_bank_mask);
+        state.write_u8(self.ram_bank_mask);
+        state.write_bool(self.using_ram_banking);
+        state.write_bool(self.banking_mode_locked);
+        state.write_bool(self.multiplex);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_ram_state(&mut self.ram, state)?;
+        self.ram_enabled = state.read_bool()?;
+        self.mapped = state.read_bool()?;
+        self.rom_bank_low = state.read_u8()?;
+        self.rom_bank_mid = state.read_u8()?;
+        self.rom_bank_high = state.read_u8()?;
+        self.ram_bank_low = state.read_u8()?;
+        self.ram_bank_high = state.read_u8()?;
+        self.rom_bank_mask = state.read_u8()?;
+        self.ram_bank_mask = state.read_u8()?;
+        self.using_ram_banking = state.read_bool()?;
+        self.banking_mode_locked = state.read_bool()?;
+        self.multiplex = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_mbc() -> MMM01 {
+        let mut mbc = MMM01::new(64, 0);
+        let program: Vec<u8> = (0..64).flat_map(|bank| [bank; ROM_BANK_SIZE]).collect();
+        mbc.init(&program);
+        mbc
+    }
+
+    #[test]
+    fn menu_is_mapped_until_unlocked() {
+        let mut mbc = get_test_mbc();
+        assert_eq!(mbc.read(0x0000), 62);
+        assert_eq!(mbc.read(0x4000), 63);
+
+        // Select the 128 KB game starting at bank 0x28, the mask claims bits 3 and 4
+        mbc.write(0x2000, 0x28);
+        mbc.write(0x6000, 0b00110000);
+        assert_eq!(mbc.read(0x4000), 63);
+        mbc.write(0x0000, 0b01000000);
+        assert_eq!(mbc.read(0x0000), 0x28);
+        assert_eq!(mbc.read(0x4000), 0x29);
+    }
+
+    #[test]
+    fn game_can_only_change_unmasked_bits() {
+        let mut mbc = get_test_mbc();
+        mbc.write(0x2000, 0x08);
+        mbc.write(0x6000, 0b00110000);
+        mbc.write(0x0000, 0b01000000);
+
+        mbc.write(0x2000, 0x1F);
+        assert_eq!(mbc.read(0x4000), 0x0F);
+        mbc.write(0x2000, 0x02);
+        assert_eq!(mbc.read(0x4000), 0x0A);
+        assert_eq!(mbc.read(0x0000), 0x08);
+
+        // The mask and mapping can't be touched again
+        mbc.write(0x6000, 0);
+        mbc.write(0x0000, 0);
+        mbc.write(0x2000, 0x00);
+        assert_eq!(mbc.read(0x4000), 0x09);
+    }
+}
@@ -0,0 +1,97 @@
+use crate::mbc::{get_ram_offset, load_ram_data, load_ram_state, load_rom_banks, MBC};
+use crate::memory::Memory;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+
+// No controller at all, just 32 KB of rom and a ram chip wired straight to 0xA000-0xBFFF
+pub struct RomRam {
+    rom: Vec<[u8; ROM_BANK_SIZE]>,
+    ram: Vec<u8>,
+}
+
+impl RomRam {
+    pub fn new(rom_banks: u16, ram_size: usize) -> Self {
+        RomRam {
+            rom: vec![[0; ROM_BANK_SIZE]; rom_banks.max(2) as usize],
+            ram: vec![0; ram_size],
+        }
+    }
+}
+
+impl Memory for RomRam {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            rom_bank_one_address @ 0x0000..=0x3FFF => self.rom[0][rom_bank_one_address as usize],
+            rom_bank_two_address @ 0x4000..=0x7FFF => {
+                self.rom[1][(rom_bank_two_address - 0x4000) as usize]
+            }
+            external_ram_address @ 0xA000..=0xBFFF => {
+                match get_ram_offset(&self.ram, 0, external_ram_address) {
+                    Some(offset) => self.ram[offset],
+                    None => 0xFF,
+                }
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        if let external_ram_address @ 0xA000..=0xBFFF = address {
+            if let Some(offset) = get_ram_offset(&self.ram, 0, external_ram_address) {
+                self.ram[offset] = data;
+            }
+        }
+    }
+}
+
+impl MBC for RomRam {
+    fn init(&mut self, program: &Vec<u8>) {
+        load_rom_banks(&mut self.rom, program);
+    }
+
+    fn dump_ram(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_ram(&mut self, data: &[u8]) {
+        load_ram_data(&mut self.ram, data);
+    }
+
+    fn get_mapped_rom_bank(&self, address: u16) -> usize {
+        if address < 0x4000 {
+            0
+        } else {
+            1
+        }
+    }
+}
+
+impl SaveState for RomRam {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_vec(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_ram_state(&mut self.ram, state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::RAM_BANK_SIZE;
+
+    #[test]
+    fn ram_is_always_mapped_and_rom_is_fixed() {
+        let mut mbc = RomRam::new(2, RAM_BANK_SIZE);
+        let program: Vec<u8> = (0..2).flat_map(|bank| [bank; ROM_BANK_SIZE]).collect();
+        mbc.init(&program);
+        mbc.write(0x2000, 0x00);
+        assert_eq!(mbc.read(0x4000), 1);
+
+        mbc.write(0xBFFF, 0x42);
+        assert_eq!(mbc.read(0xBFFF), 0x42);
+        assert_eq!(mbc.dump_ram()[RAM_BANK_SIZE - 1], 0x42);
+    }
+}
@@ -1,31 +1,68 @@
+use clap::ValueEnum;
+
+use crate::apu::APU;
+use crate::cheats::Cheats;
 use crate::mbc::MBC;
 use crate::memory::Memory;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+use crate::serial::{Serial, SerialPeer};
 
 use crate::registers::{
-    BCPD_ADDRESS, BCPS_ADDRESS, DIV_ADDRESS, OCPD_ADDRESS, OCPS_ADDRESS, SVBK_ADDRESS, VBK_ADDRESS,
+    BANK_ADDRESS, BCPD_ADDRESS, BCPS_ADDRESS, DIV_ADDRESS, HDMA1_ADDRESS, HDMA2_ADDRESS,
+    HDMA3_ADDRESS, HDMA4_ADDRESS, HDMA5_ADDRESS, IF_ADDRESS, KEY0_ADDRESS, KEY1_ADDRESS,
+    OCPD_ADDRESS, OCPS_ADDRESS, OPRI_ADDRESS, RP_ADDRESS, SVBK_ADDRESS, VBK_ADDRESS,
 };
 
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Model {
+    DMG,
+    CGB,
+}
+
 pub struct MemManager {
+    model: Model,
     memory: [u8; 0xFFFF + 1],
     vram_bank_one: [u8; 0x2000 + 1],
     extra_ram_banks: [[u8; 0x1000 + 1]; 6],
     object_palettes: [u8; 64],
     background_palettes: [u8; 64],
     mbc: Option<Box<dyn MBC>>,
+    cheats: Cheats,
+    apu: APU,
+    serial: Serial,
+    hdma5_write: Option<u8>,
+    boot_rom: Option<Vec<u8>>,
+    boot_rom_mapped: bool,
 }
 
 impl MemManager {
     pub fn new() -> Self {
         MemManager {
+            model: Model::CGB,
             memory: [0; 0xFFFF + 1],
             vram_bank_one: [0; 0x2000 + 1],
             extra_ram_banks: [[0; 0x1000 + 1]; 6],
             object_palettes: [0; 64],
             background_palettes: [0; 64],
             mbc: None,
+            cheats: Cheats::default(),
+            apu: APU::new(),
+            serial: Serial::new(),
+            hdma5_write: None,
+            boot_rom: None,
+            boot_rom_mapped: false,
         }
     }
 
+    // Which console is being emulated, the cgb registers are missing on a dmg
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+    }
+
+    pub fn get_model(&self) -> Model {
+        self.model
+    }
+
     pub fn force_write(&mut self, address: u16, data: u8) {
         self.memory[address as usize] = data;
     }
@@ -72,16 +109,141 @@ impl MemManager {
     pub fn set_mbc(&mut self, mbc: Option<Box<dyn MBC>>) {
         self.mbc = mbc;
     }
+
+    pub fn dump_cartridge_ram(&self) -> Option<Vec<u8>> {
+        self.mbc.as_ref().map(|mbc| mbc.dump_ram())
+    }
+
+    pub fn get_cartridge_timestamp_size(&self) -> usize {
+        self.mbc.as_ref().map_or(0, |mbc| mbc.get_timestamp_size())
+    }
+
+    pub fn set_cheats(&mut self, cheats: Cheats) {
+        self.cheats = cheats;
+    }
+
+    // Returns whether cheats are now on
+    pub fn toggle_cheats(&mut self) -> bool {
+        self.cheats.toggle()
+    }
+
+    // GameShark codes keep overwriting ram so the game can't undo them
+    pub fn apply_ram_cheats(&mut self) {
+        let cheats = std::mem::take(&mut self.cheats);
+        cheats.apply_ram_writes(self);
+        self.cheats = cheats;
+    }
+
+    pub fn apu_mut(&mut self) -> &mut APU {
+        &mut self.apu
+    }
+
+    // Maps the boot rom over the start of the cartridge until the game writes to BANK
+    pub fn set_boot_rom(&mut self, boot_rom: Option<Vec<u8>>) {
+        self.boot_rom_mapped = boot_rom.is_some();
+        self.boot_rom = boot_rom;
+    }
+
+    pub fn is_boot_rom_mapped(&self) -> bool {
+        self.boot_rom_mapped
+    }
+
+    // The cgb boot rom also covers 0x0200-0x08FF but leaves the cartridge header visible
+    fn read_boot_rom(&self, address: u16) -> Option<u8> {
+        if !self.boot_rom_mapped {
+            return None;
+        }
+        let boot_rom = self.boot_rom.as_ref()?;
+        match address {
+            0x0000..=0x00FF | 0x0200..=0x08FF => boot_rom.get(address as usize).copied(),
+            _ => None,
+        }
+    }
+
+    pub fn set_serial_peer(&mut self, peer: Option<Box<dyn SerialPeer>>) {
+        self.serial.set_peer(peer);
+    }
+
+    pub fn is_serial_waiting(&self) -> bool {
+        self.serial.is_waiting()
+    }
+
+    // Hands the last write to HDMA5 to the dma controller, which owns the transfer state
+    pub fn take_hdma5_write(&mut self) -> Option<u8> {
+        self.hdma5_write.take()
+    }
+
+    pub fn get_vram_dma_source(&self) -> u16 {
+        let high = self.memory[HDMA1_ADDRESS as usize] as u16;
+        let low = self.memory[HDMA2_ADDRESS as usize] as u16;
+        ((high << 8) | low) & 0xFFF0
+    }
+
+    pub fn get_vram_dma_destination(&self) -> u16 {
+        let high = self.memory[HDMA3_ADDRESS as usize] as u16;
+        let low = self.memory[HDMA4_ADDRESS as usize] as u16;
+        0x8000 | (((high << 8) | low) & 0x1FF0)
+    }
+
+    // Set when a dmg game is running, the ppu then colors through BGP, OBP0 and OBP1
+    pub fn is_dmg_compat(&self) -> bool {
+        self.memory[KEY0_ADDRESS as usize] & 0b00000100 != 0
+    }
+
+    // True whenever colors come from BGP, OBP0 and OBP1, either on a dmg or a cgb running a dmg game
+    pub fn is_dmg_mode(&self) -> bool {
+        self.model == Model::DMG || self.is_dmg_compat()
+    }
+
+    // Which rom bank the address reads from, banks without a controller are fixed
+    pub fn get_rom_bank(&self, address: u16) -> usize {
+        match self.mbc.as_ref() {
+            Some(mbc) => mbc.get_mapped_rom_bank(address),
+            None => address as usize / 0x4000,
+        }
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        self.memory[KEY1_ADDRESS as usize] & 0b10000000 != 0
+    }
+
+    // Clocks the hardware that sits behind the memory map
+    pub fn update(&mut self, cycles: u32) {
+        let mut div = self.memory[DIV_ADDRESS as usize];
+        if self.is_double_speed() {
+            // DIV runs twice as fast so the frame sequencer follows bit 5 instead of bit 4
+            div >>= 1;
+        }
+        if let Some(mbc) = self.mbc.as_mut() {
+            mbc.update(cycles);
+            self.apu.set_cartridge_output(mbc.get_audio_output());
+        }
+        self.apu.update(cycles, div);
+        if self.serial.update(cycles, self.is_double_speed()) {
+            self.memory[IF_ADDRESS as usize] |= 0b00001000;
+        }
+    }
 }
 
 impl Memory for MemManager {
     fn read(&self, address: u16) -> u8 {
         let ram_bank = self.memory[SVBK_ADDRESS as usize] & 0b00000111;
         let vram_bank = self.memory[VBK_ADDRESS as usize] & 0b00000001;
+        if let Some(data) = self.read_boot_rom(address) {
+            return data;
+        }
+        if self.model == Model::DMG && is_cgb_register(address) {
+            return 0xFF;
+        }
         match address {
+            // Game Genie codes sit between the cartridge and the console
             rom_address @ 0x0000..=0x7FFF if self.mbc.is_some() => {
-                self.mbc.as_ref().unwrap().read(rom_address)
+                let data = self.mbc.as_ref().unwrap().read(rom_address);
+                self.cheats.patch_rom_read(rom_address, data)
             }
+            rom_address @ 0x0000..=0x7FFF => self
+                .cheats
+                .patch_rom_read(rom_address, self.memory[rom_address as usize]),
             external_ram_address @ 0xA000..=0xBFFF if self.mbc.is_some() => {
                 self.mbc.as_ref().unwrap().read(external_ram_address)
             }
@@ -91,6 +253,8 @@ impl Memory for MemManager {
             vram_address @ 0x8000..=0x9FFF if vram_bank == 1 => {
                 self.vram_bank_one[(vram_address - 0x8000) as usize]
             }
+            serial_address @ 0xFF01..=0xFF02 => self.serial.read(serial_address),
+            apu_address @ 0xFF10..=0xFF3F => self.apu.read(apu_address),
             OCPD_ADDRESS => {
                 let palette_index = self.memory[OCPS_ADDRESS as usize] & 0b00111111;
                 self.object_palettes[palette_index as usize]
@@ -99,6 +263,9 @@ impl Memory for MemManager {
                 let palette_index = self.memory[BCPS_ADDRESS as usize] & 0b00111111;
                 self.background_palettes[palette_index as usize]
             }
+            KEY1_ADDRESS => self.memory[address as usize] | 0b01111110,
+            // The dma source and destination can't be read back
+            HDMA1_ADDRESS..=HDMA4_ADDRESS => 0xFF,
             _ => {
                 let result = self.memory[address as usize];
                 result
@@ -109,6 +276,9 @@ impl Memory for MemManager {
     fn write(&mut self, address: u16, data: u8) {
         let ram_bank = self.memory[SVBK_ADDRESS as usize] & 0b00000111;
         let vram_bank = self.memory[VBK_ADDRESS as usize] & 0b00000001;
+        if self.model == Model::DMG && is_cgb_register(address) {
+            return;
+        }
 
         match address {
             joyp_address @ 0xFF00 => {
@@ -130,6 +300,8 @@ impl Memory for MemManager {
             vram_address @ 0x8000..=0x9FFF if vram_bank == 1 => {
                 self.vram_bank_one[(vram_address - 0x8000) as usize] = data
             }
+            serial_address @ 0xFF01..=0xFF02 => self.serial.write(serial_address, data),
+            apu_address @ 0xFF10..=0xFF3F => self.apu.write(apu_address, data),
             OCPD_ADDRESS => {
                 let ocps = self.memory[OCPS_ADDRESS as usize];
                 let palette_index = ocps & 0b00111111;
@@ -151,11 +323,90 @@ impl Memory for MemManager {
                 }
             }
             DIV_ADDRESS => self.memory[address as usize] = 0,
+            // Only the boot rom gets to pick the mode, afterwards it's locked
+            KEY0_ADDRESS => {
+                if self.boot_rom_mapped {
+                    self.memory[address as usize] = data;
+                }
+            }
+            // Only the prepare bit can be written, the current speed is changed by STOP
+            KEY1_ADDRESS => {
+                let current_speed = self.memory[address as usize] & 0b10000000;
+                self.memory[address as usize] = current_speed | (data & 0b00000001);
+            }
+            // Unmapping the boot rom can't be undone
+            BANK_ADDRESS => {
+                if data & 0b00000001 != 0 {
+                    self.boot_rom_mapped = false;
+                }
+                self.memory[address as usize] = data;
+            }
+            // Status is written back by the dma controller with force_write
+            HDMA5_ADDRESS => self.hdma5_write = Some(data),
             _ => self.memory[address as usize] = data,
         }
     }
 }
 
+fn is_cgb_register(address: u16) -> bool {
+    matches!(
+        address,
+        KEY0_ADDRESS
+            | KEY1_ADDRESS
+            | VBK_ADDRESS
+            | HDMA1_ADDRESS..=RP_ADDRESS
+            | BCPS_ADDRESS..=OPRI_ADDRESS
+            | SVBK_ADDRESS
+    )
+}
+
+impl SaveState for MemManager {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.model as u8);
+        state.write_bytes(&self.memory);
+        state.write_bytes(&self.vram_bank_one);
+        for bank in &self.extra_ram_banks {
+            state.write_bytes(bank);
+        }
+        state.write_bytes(&self.object_palettes);
+        state.write_bytes(&self.background_palettes);
+        self.apu.save_state(state);
+        self.serial.save_state(state);
+        state.write_option_u8(self.hdma5_write);
+        state.write_bool(self.boot_rom_mapped);
+        state.write_bool(self.mbc.is_some());
+        if let Some(mbc) = &self.mbc {
+            mbc.save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        if state.read_u8()? != self.model as u8 {
+            return Err(StateError::Invalid("state is for another model"));
+        }
+        state.read_bytes(&mut self.memory)?;
+        state.read_bytes(&mut self.vram_bank_one)?;
+        for bank in self.extra_ram_banks.iter_mut() {
+            state.read_bytes(bank)?;
+        }
+        state.read_bytes(&mut self.object_palettes)?;
+        state.read_bytes(&mut self.background_palettes)?;
+        self.apu.load_state(state)?;
+        self.serial.load_state(state)?;
+        self.hdma5_write = state.read_option_u8()?;
+        self.boot_rom_mapped = state.read_bool()?;
+        if self.boot_rom_mapped && self.boot_rom.is_none() {
+            return Err(StateError::Invalid("boot rom isn't loaded"));
+        }
+        let has_mbc = state.read_bool()?;
+        match self.mbc.as_mut() {
+            Some(mbc) if has_mbc => mbc.load_state(state),
+            None if !has_mbc => Ok(()),
+            _ => Err(StateError::Invalid("memory bank controller")),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::registers::DIV_ADDRESS;
@@ -169,6 +420,59 @@ mod tests {
         assert_eq!(mem.read(DIV_ADDRESS), 0x00);
     }
 
+    #[test]
+    fn cgb_registers_are_missing_on_dmg() {
+        let mut mem = MemManager::new();
+        mem.set_model(Model::DMG);
+        mem.write(SVBK_ADDRESS, 0x02);
+        mem.write(0xD000, 0x12);
+        assert_eq!(mem.read(SVBK_ADDRESS), 0xFF);
+        mem.set_model(Model::CGB);
+        assert_eq!(mem.read(0xD000), 0x12);
+        assert_eq!(mem.read(SVBK_ADDRESS), 0x00);
+    }
+
+    #[test]
+    fn key0_is_locked_once_boot_rom_is_gone() {
+        let mut mem = MemManager::new();
+        mem.set_boot_rom(Some(vec![0; 0x100]));
+        mem.write(KEY0_ADDRESS, 0x04);
+        assert!(mem.is_dmg_compat());
+        mem.write(BANK_ADDRESS, 0x01);
+        mem.write(KEY0_ADDRESS, 0x00);
+        assert!(mem.is_dmg_compat());
+    }
+
+    #[test]
+    fn writing_to_key1_only_sets_prepare_bit() {
+        let mut mem = MemManager::new();
+        mem.write(KEY1_ADDRESS, 0xFF);
+        assert_eq!(mem.read(KEY1_ADDRESS), 0x7F);
+        assert!(!mem.is_double_speed());
+    }
+
+    #[test]
+    fn boot_rom_is_mapped_until_bank_is_written() {
+        let mut mem = MemManager::new();
+        mem.write(0x0000, 0x11);
+        mem.write(0x0100, 0x22);
+        mem.set_boot_rom(Some(vec![0xAA; 0x900]));
+        assert_eq!(mem.read(0x0000), 0xAA);
+        assert_eq!(mem.read(0x0100), 0x22);
+        assert_eq!(mem.read(0x0200), 0xAA);
+        mem.write(BANK_ADDRESS, 0x01);
+        assert_eq!(mem.read(0x0000), 0x11);
+        assert_eq!(mem.read(0x0200), 0x00);
+    }
+
+    #[test]
+    fn dmg_boot_rom_only_covers_first_page() {
+        let mut mem = MemManager::new();
+        mem.set_boot_rom(Some(vec![0xAA; 0x100]));
+        assert_eq!(mem.read(0x00FF), 0xAA);
+        assert_eq!(mem.read(0x0200), 0x00);
+    }
+
     #[test]
     fn ram_bank_two_is_accesible() {
         let mut mem = MemManager::new();
@@ -1,13 +1,16 @@
 use std::{cell::RefCell, collections::VecDeque, rc::Rc};
 
-use crate::mem_manager::MemManager;
+use clap::ValueEnum;
+
+use crate::mem_manager::{MemManager, Model};
 use crate::memory::Memory;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 
 use crate::fetcher::{BackgroundFetcher, SpriteFetcher};
 
 use crate::registers::{
     BCPD_ADDRESS, BCPS_ADDRESS, BGP_ADDRESS, IF_ADDRESS, LCDC_ADDRESS, LYC_ADDRESS, LY_ADDRESS,
-    OCPD_ADDRESS, OCPS_ADDRESS, SCX_ADDRESS, STAT_ADDRESS,
+    OBP0_ADDRESS, OBP1_ADDRESS, OCPD_ADDRESS, OCPS_ADDRESS, SCX_ADDRESS, STAT_ADDRESS,
 };
 
 const V_BLANK_TIME: u32 = 4560;
@@ -32,10 +35,27 @@ pub(crate) struct BackgroundPixel {
 
 type RenderedPixel = u8;
 
+// Four shades a dmg screen is drawn with, from lightest to darkest
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum DMGPalette {
+    Grey,
+    Green,
+    Pocket,
+}
+
+impl DMGPalette {
+    fn get_shades(&self) -> [u16; 4] {
+        match self {
+            DMGPalette::Grey => [0x7FFF, 0x739C, 0x294A, 0x0000],
+            DMGPalette::Green => [0x06F3, 0x06B1, 0x1986, 0x04E1],
+            DMGPalette::Pocket => [0x5338, 0x3651, 0x1D49, 0x0C63],
+        }
+    }
+}
+
 // Todo: Implement ppu vram blocking
 // Todo: Implement window rendering penalty
 // Todo: More complex behavior for cgb palette access
-// Todo: Original gameboy compatibility
 pub struct PPU {
     mode: Rc<RefCell<dyn PPUMode>>,
     pub(crate) memory: Rc<RefCell<MemManager>>,
@@ -46,6 +66,7 @@ pub struct PPU {
     pub(crate) object_pixel_queue: VecDeque<ObjectPixel>,
     pub(crate) background_pixel_queue: VecDeque<BackgroundPixel>,
     pub(crate) screen_x: u8,
+    dmg_shades: [u16; 4],
 }
 
 impl PPU {
@@ -57,6 +78,14 @@ impl PPU {
     }
 
     pub(crate) fn new_test(memory: Rc<RefCell<MemManager>>) -> Self {
+        let ppu = PPU::new_at_reset(memory);
+        ppu.memory.borrow_mut().write(LCDC_ADDRESS, 0x91);
+        ppu.memory.borrow_mut().write(BGP_ADDRESS, 0xFC);
+        ppu
+    }
+
+    // State at power on, the boot rom is left to set up the lcd registers
+    pub fn new_at_reset(memory: Rc<RefCell<MemManager>>) -> Self {
         let initial_mode = Rc::new(RefCell::new(Scan));
         let mut ppu = PPU {
             mode: initial_mode.clone(),
@@ -68,11 +97,9 @@ impl PPU {
             object_pixel_queue: VecDeque::with_capacity(16),
             background_pixel_queue: VecDeque::with_capacity(16),
             screen_x: 0,
+            dmg_shades: DMGPalette::Grey.get_shades(),
         };
         ppu.set_mode(initial_mode);
-
-        ppu.memory.borrow_mut().write(LCDC_ADDRESS, 0x91);
-        ppu.memory.borrow_mut().write(BGP_ADDRESS, 0xFC);
         ppu
     }
 
@@ -81,6 +108,10 @@ impl PPU {
         m.borrow_mut().update(self, dots);
     }
 
+    pub fn set_dmg_palette(&mut self, palette: DMGPalette) {
+        self.dmg_shades = palette.get_shades();
+    }
+
     pub fn get_frame(&self) -> Vec<u8> {
         self.completed_frame.clone()
     }
@@ -167,6 +198,79 @@ trait PPUMode {
     fn update(&mut self, ppu: &mut PPU, dots: u32);
     fn transition(&self, ppu: &mut PPU);
     fn get_mode_number(&self) -> u8;
+    // Saves whatever the mode needs beyond its number to be rebuilt by PPU::load_state
+    fn save_state(&self, _state: &mut StateWriter) {}
+}
+
+impl SaveState for PPU {
+    fn save_state(&self, state: &mut StateWriter) {
+        let mode = self.mode.borrow();
+        state.write_u8(mode.get_mode_number());
+        mode.save_state(state);
+        state.write_vec(&self.current_frame);
+        state.write_vec(&self.completed_frame);
+        state.write_u32(self.mode_dots_passed);
+        state.write_u8(self.objects_on_scanline.len() as u8);
+        for object_address in &self.objects_on_scanline {
+            state.write_u16(*object_address);
+        }
+        state.write_u8(self.object_pixel_queue.len() as u8);
+        for pixel in &self.object_pixel_queue {
+            state.write_u8(pixel.color);
+            state.write_u8(pixel.palette);
+            state.write_u8(pixel.sprite_prio);
+            state.write_bool(pixel.bg_prio);
+        }
+        state.write_u8(self.background_pixel_queue.len() as u8);
+        for pixel in &self.background_pixel_queue {
+            state.write_u8(pixel.color);
+            state.write_u8(pixel.palette);
+        }
+        state.write_u8(self.screen_x);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        // Assigned directly instead of through set_mode so no interrupts are requested
+        self.mode = match state.read_u8()? {
+            0 => Rc::new(RefCell::new(HBlank {
+                dots_until_transition: state.read_u32()?,
+            })),
+            1 => Rc::new(RefCell::new(VBlank)),
+            2 => Rc::new(RefCell::new(Scan)),
+            3 => {
+                let mut draw = Draw::new();
+                draw.bg_fetcher.load_state(state)?;
+                draw.obj_fetcher.load_state(state)?;
+                Rc::new(RefCell::new(draw))
+            }
+            _ => return Err(StateError::Invalid("ppu mode")),
+        };
+        self.current_frame = state.read_vec()?;
+        self.completed_frame = state.read_vec()?;
+        self.mode_dots_passed = state.read_u32()?;
+        self.objects_on_scanline.clear();
+        for _ in 0..state.read_u8()? {
+            self.objects_on_scanline.push(state.read_u16()?);
+        }
+        self.object_pixel_queue.clear();
+        for _ in 0..state.read_u8()? {
+            self.object_pixel_queue.push_back(ObjectPixel {
+                color: state.read_u8()?,
+                palette: state.read_u8()?,
+                sprite_prio: state.read_u8()?,
+                bg_prio: state.read_bool()?,
+            });
+        }
+        self.background_pixel_queue.clear();
+        for _ in 0..state.read_u8()? {
+            self.background_pixel_queue.push_back(BackgroundPixel {
+                color: state.read_u8()?,
+                palette: state.read_u8()?,
+            });
+        }
+        self.screen_x = state.read_u8()?;
+        Ok(())
+    }
 }
 
 pub(crate) struct HBlank {
@@ -196,6 +300,10 @@ impl PPUMode for HBlank {
     fn get_mode_number(&self) -> u8 {
         0
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(self.dots_until_transition);
+    }
 }
 
 pub(crate) struct VBlank;
@@ -313,8 +421,8 @@ impl Draw {
                 self.push_pixel_to_lcd(ppu);
             }
 
-            // Check for objects in this position before moving on
-            ppu.objects_on_scanline.reverse();
+            // Check for objects in this position before moving on, objects that share a
+            // position are fetched in oam order
             for object_address in &ppu.objects_on_scanline {
                 let object_end = ppu.memory.borrow().read(object_address + 1);
                 if object_end < 8 {
@@ -336,7 +444,21 @@ impl Draw {
     }
 
     fn render_object_pixel(&self, ppu: &mut PPU, pixel: ObjectPixel) -> Vec<u8> {
-        let color_index = (4 * pixel.palette + pixel.color) * 2;
+        let color = if ppu.memory.borrow().is_dmg_mode() {
+            let obp_address = if pixel.palette == 0 {
+                OBP0_ADDRESS
+            } else {
+                OBP1_ADDRESS
+            };
+            let shade = get_dmg_shade(ppu.memory.borrow().read(obp_address), pixel.color);
+            if ppu.memory.borrow().get_model() == Model::DMG {
+                return self.render_dmg_shade(ppu, shade);
+            }
+            shade
+        } else {
+            pixel.color
+        };
+        let color_index = (4 * pixel.palette + color) * 2;
         let ocps_value = ppu.memory.borrow().read(OCPS_ADDRESS);
         ppu.memory.borrow_mut().write(OCPS_ADDRESS, color_index);
         let high_byte = ppu.memory.borrow().read(OCPD_ADDRESS);
@@ -347,7 +469,22 @@ impl Draw {
     }
 
     fn render_background_pixel(&self, ppu: &mut PPU, pixel: BackgroundPixel) -> Vec<u8> {
-        let color_index = (4 * pixel.palette + pixel.color) * 2;
+        let color = if ppu.memory.borrow().is_dmg_mode() {
+            // Clearing LCDC bit 0 blanks the background and window
+            let lcdc = ppu.memory.borrow().read(LCDC_ADDRESS);
+            let shade = if lcdc & 0b00000001 != 0 {
+                get_dmg_shade(ppu.memory.borrow().read(BGP_ADDRESS), pixel.color)
+            } else {
+                0
+            };
+            if ppu.memory.borrow().get_model() == Model::DMG {
+                return self.render_dmg_shade(ppu, shade);
+            }
+            shade
+        } else {
+            pixel.color
+        };
+        let color_index = (4 * pixel.palette + color) * 2;
         let bcps_value = ppu.memory.borrow().read(BCPS_ADDRESS);
         ppu.memory.borrow_mut().write(BCPS_ADDRESS, color_index);
         let high_byte = ppu.memory.borrow().read(BCPD_ADDRESS);
@@ -357,12 +494,20 @@ impl Draw {
         vec![high_byte, low_byte]
     }
 
+    fn render_dmg_shade(&self, ppu: &PPU, shade: u8) -> Vec<u8> {
+        let color = ppu.dmg_shades[shade as usize];
+        vec![color as u8, (color >> 8) as u8]
+    }
+
     fn push_pixel_to_lcd(&self, ppu: &mut PPU) {
         assert!(ppu.background_pixel_queue.len() > 8);
-        let bg_pixel = ppu.background_pixel_queue.pop_front();
+        let bg_pixel = ppu.background_pixel_queue.pop_front().unwrap();
         let obj_pixel = ppu.object_pixel_queue.pop_front();
+        // Objects behind the background only show through its color 0, before the palette is
+        // applied. With LCDC bit 0 clear every object is drawn on top.
+        let bg_enabled = ppu.memory.borrow().read(LCDC_ADDRESS) & 0b00000001 != 0;
         if let Some(pixel) = obj_pixel {
-            if !pixel.bg_prio && pixel.color != 0 {
+            if pixel.color != 0 && (!pixel.bg_prio || bg_pixel.color == 0 || !bg_enabled) {
                 let rendered_pixel = self.render_object_pixel(ppu, pixel);
                 for i in rendered_pixel.iter() {
                     ppu.current_frame.push(*i);
@@ -370,13 +515,18 @@ impl Draw {
                 return;
             }
         }
-        let rendered_pixel = self.render_background_pixel(ppu, bg_pixel.unwrap());
+        let rendered_pixel = self.render_background_pixel(ppu, bg_pixel);
         for i in rendered_pixel.iter() {
             ppu.current_frame.push(*i);
         }
     }
 }
 
+// Dmg palette registers hold a two bit shade for each of the four colors
+fn get_dmg_shade(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0b11
+}
+
 impl PPUMode for Draw {
     fn update(&mut self, ppu: &mut PPU, dots: u32) {
         for used_dots in 1..=dots {
@@ -400,11 +550,16 @@ impl PPUMode for Draw {
     fn get_mode_number(&self) -> u8 {
         3
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.bg_fetcher.save_state(state);
+        self.obj_fetcher.save_state(state);
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::registers::SCY_ADDRESS;
+    use crate::registers::{KEY0_ADDRESS, SCY_ADDRESS};
 
     use super::*;
 
@@ -683,6 +838,103 @@ mod tests {
         assert_eq!(pixels, vec![0x7f, 0xff]);
     }
 
+    #[test]
+    fn dmg_compat_colors_go_through_dmg_palettes() {
+        let mut ppu = get_test_ppu();
+        let draw = Draw::new();
+        ppu.memory.borrow_mut().force_write(KEY0_ADDRESS, 0x04);
+        ppu.memory.borrow_mut().write(BGP_ADDRESS, 0b11100100);
+        ppu.memory.borrow_mut().write(OBP1_ADDRESS, 0b00011011);
+        ppu.memory.borrow_mut().write(BCPS_ADDRESS, 0b10000000);
+        ppu.memory.borrow_mut().write(OCPS_ADDRESS, 0b10000000);
+        for i in 0..16 {
+            ppu.memory.borrow_mut().write(BCPD_ADDRESS, i);
+            ppu.memory.borrow_mut().write(OCPD_ADDRESS, i);
+        }
+        let background = draw.render_background_pixel(
+            &mut ppu,
+            BackgroundPixel {
+                palette: 0,
+                color: 2,
+            },
+        );
+        assert_eq!(background, vec![4, 5]);
+        let object = draw.render_object_pixel(
+            &mut ppu,
+            ObjectPixel {
+                color: 3,
+                palette: 1,
+                sprite_prio: 0,
+                bg_prio: false,
+            },
+        );
+        assert_eq!(object, vec![8, 9]);
+    }
+
+    #[test]
+    fn dmg_renders_with_selected_shades() {
+        let mut ppu = get_test_ppu();
+        ppu.memory.borrow_mut().set_model(Model::DMG);
+        ppu.set_dmg_palette(DMGPalette::Green);
+        let draw = Draw::new();
+        let pixel = BackgroundPixel {
+            palette: 0,
+            color: 3,
+        };
+        // BGP of 0xFC maps color 3 to the darkest shade
+        assert_eq!(
+            draw.render_background_pixel(&mut ppu, pixel),
+            vec![0xE1, 0x04]
+        );
+
+        let lcdc = ppu.memory.borrow().read(LCDC_ADDRESS);
+        ppu.memory
+            .borrow_mut()
+            .write(LCDC_ADDRESS, lcdc & 0b11111110);
+        assert_eq!(
+            draw.render_background_pixel(&mut ppu, pixel),
+            vec![0xF3, 0x06]
+        );
+    }
+
+    #[test]
+    fn dmg_objects_behind_background_show_through_color_zero() {
+        let mut ppu = get_test_ppu();
+        ppu.memory.borrow_mut().set_model(Model::DMG);
+        ppu.memory.borrow_mut().write(BGP_ADDRESS, 0b11100100);
+        ppu.memory.borrow_mut().write(OBP0_ADDRESS, 0b11100100);
+        let draw = Draw::new();
+        let object_shade = draw.render_dmg_shade(&ppu, 3);
+        let background_shade = draw.render_dmg_shade(&ppu, 2);
+        let draw_pixel = |ppu: &mut PPU, background_color: u8| {
+            ppu.background_pixel_queue.clear();
+            for _ in 0..9 {
+                ppu.background_pixel_queue.push_back(BackgroundPixel {
+                    color: background_color,
+                    palette: 0,
+                });
+            }
+            ppu.object_pixel_queue.push_back(ObjectPixel {
+                color: 3,
+                palette: 0,
+                sprite_prio: 0,
+                bg_prio: true,
+            });
+            ppu.current_frame.clear();
+            draw.push_pixel_to_lcd(ppu);
+            ppu.current_frame.clone()
+        };
+        assert_eq!(draw_pixel(&mut ppu, 0), object_shade);
+        assert_eq!(draw_pixel(&mut ppu, 2), background_shade);
+
+        // A blanked background hides nothing, even where its color isn't 0
+        let lcdc = ppu.memory.borrow().read(LCDC_ADDRESS);
+        ppu.memory
+            .borrow_mut()
+            .write(LCDC_ADDRESS, lcdc & 0b11111110);
+        assert_eq!(draw_pixel(&mut ppu, 2), object_shade);
+    }
+
     #[test]
     fn get_tile_index_gets_gets_lower_data_for_large_sprites() {
         let mut ppu = get_test_ppu();
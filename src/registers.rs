@@ -1,4 +1,6 @@
 pub const JOYP_ADDRESS: u16 = 0xFF00;
+pub const SB_ADDRESS: u16 = 0xFF01;
+pub const SC_ADDRESS: u16 = 0xFF02;
 pub const IF_ADDRESS: u16 = 0xFF0F;
 pub const LCDC_ADDRESS: u16 = 0xFF40;
 pub const STAT_ADDRESS: u16 = 0xFF41;
@@ -11,13 +13,107 @@ pub const OBP0_ADDRESS: u16 = 0xFF48;
 pub const OBP1_ADDRESS: u16 = 0xFF49;
 pub const WY_ADDRESS: u16 = 0xFF4A;
 pub const WX_ADDRESS: u16 = 0xFF4B;
+pub const KEY0_ADDRESS: u16 = 0xFF4C;
+pub const KEY1_ADDRESS: u16 = 0xFF4D;
+pub const BANK_ADDRESS: u16 = 0xFF50;
+pub const HDMA1_ADDRESS: u16 = 0xFF51;
+pub const HDMA2_ADDRESS: u16 = 0xFF52;
+pub const HDMA3_ADDRESS: u16 = 0xFF53;
+pub const HDMA4_ADDRESS: u16 = 0xFF54;
+pub const HDMA5_ADDRESS: u16 = 0xFF55;
+pub const RP_ADDRESS: u16 = 0xFF56;
 pub const BCPS_ADDRESS: u16 = 0xFF68;
 pub const BCPD_ADDRESS: u16 = 0xFF69;
 pub const OCPS_ADDRESS: u16 = 0xFF6A;
 pub const OCPD_ADDRESS: u16 = 0xFF6B;
+pub const OPRI_ADDRESS: u16 = 0xFF6C;
 pub const VBK_ADDRESS: u16 = 0xFF4F;
 pub const SVBK_ADDRESS: u16 = 0xFF70;
 pub const DIV_ADDRESS: u16 = 0xFF04;
 pub const TIMA_ADDRESS: u16 = 0xFF05;
 pub const TMA_ADDRESS: u16 = 0xFF06;
 pub const TAC_ADDRESS: u16 = 0xFF07;
+pub const NR10_ADDRESS: u16 = 0xFF10;
+pub const NR11_ADDRESS: u16 = 0xFF11;
+pub const NR12_ADDRESS: u16 = 0xFF12;
+pub const NR13_ADDRESS: u16 = 0xFF13;
+pub const NR14_ADDRESS: u16 = 0xFF14;
+pub const NR21_ADDRESS: u16 = 0xFF16;
+pub const NR22_ADDRESS: u16 = 0xFF17;
+pub const NR23_ADDRESS: u16 = 0xFF18;
+pub const NR24_ADDRESS: u16 = 0xFF19;
+pub const NR30_ADDRESS: u16 = 0xFF1A;
+pub const NR31_ADDRESS: u16 = 0xFF1B;
+pub const NR32_ADDRESS: u16 = 0xFF1C;
+pub const NR33_ADDRESS: u16 = 0xFF1D;
+pub const NR34_ADDRESS: u16 = 0xFF1E;
+pub const NR41_ADDRESS: u16 = 0xFF20;
+pub const NR42_ADDRESS: u16 = 0xFF21;
+pub const NR43_ADDRESS: u16 = 0xFF22;
+pub const NR44_ADDRESS: u16 = 0xFF23;
+pub const NR50_ADDRESS: u16 = 0xFF24;
+pub const NR51_ADDRESS: u16 = 0xFF25;
+pub const NR52_ADDRESS: u16 = 0xFF26;
+pub const WAVE_RAM_ADDRESS: u16 = 0xFF30;
+pub const IE_ADDRESS: u16 = 0xFFFF;
+
+// Every register above by name, in address order
+pub const IO_REGISTERS: [(&str, u16); 57] = [
+    ("JOYP", JOYP_ADDRESS),
+    ("SB", SB_ADDRESS),
+    ("SC", SC_ADDRESS),
+    ("DIV", DIV_ADDRESS),
+    ("TIMA", TIMA_ADDRESS),
+    ("TMA", TMA_ADDRESS),
+    ("TAC", TAC_ADDRESS),
+    ("IF", IF_ADDRESS),
+    ("NR10", NR10_ADDRESS),
+    ("NR11", NR11_ADDRESS),
+    ("NR12", NR12_ADDRESS),
+    ("NR13", NR13_ADDRESS),
+    ("NR14", NR14_ADDRESS),
+    ("NR21", NR21_ADDRESS),
+    ("NR22", NR22_ADDRESS),
+    ("NR23", NR23_ADDRESS),
+    ("NR24", NR24_ADDRESS),
+    ("NR30", NR30_ADDRESS),
+    ("NR31", NR31_ADDRESS),
+    ("NR32", NR32_ADDRESS),
+    ("NR33", NR33_ADDRESS),
+    ("NR34", NR34_ADDRESS),
+    ("NR41", NR41_ADDRESS),
+    ("NR42", NR42_ADDRESS),
+    ("NR43", NR43_ADDRESS),
+    ("NR44", NR44_ADDRESS),
+    ("NR50", NR50_ADDRESS),
+    ("NR51", NR51_ADDRESS),
+    ("NR52", NR52_ADDRESS),
+    ("LCDC", LCDC_ADDRESS),
+    ("STAT", STAT_ADDRESS),
+    ("SCY", SCY_ADDRESS),
+    ("SCX", SCX_ADDRESS),
+    ("LY", LY_ADDRESS),
+    ("LYC", LYC_ADDRESS),
+    ("BGP", BGP_ADDRESS),
+    ("OBP0", OBP0_ADDRESS),
+    ("OBP1", OBP1_ADDRESS),
+    ("WY", WY_ADDRESS),
+    ("WX", WX_ADDRESS),
+    ("KEY0", KEY0_ADDRESS),
+    ("KEY1", KEY1_ADDRESS),
+    ("VBK", VBK_ADDRESS),
+    ("BANK", BANK_ADDRESS),
+    ("HDMA1", HDMA1_ADDRESS),
+    ("HDMA2", HDMA2_ADDRESS),
+    ("HDMA3", HDMA3_ADDRESS),
+    ("HDMA4", HDMA4_ADDRESS),
+    ("HDMA5", HDMA5_ADDRESS),
+    ("RP", RP_ADDRESS),
+    ("BCPS", BCPS_ADDRESS),
+    ("BCPD", BCPD_ADDRESS),
+    ("OCPS", OCPS_ADDRESS),
+    ("OCPD", OCPD_ADDRESS),
+    ("OPRI", OPRI_ADDRESS),
+    ("SVBK", SVBK_ADDRESS),
+    ("IE", IE_ADDRESS),
+];
@@ -0,0 +1,246 @@
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"GBCS";
+// Bump whenever the layout of any component's state changes
+const VERSION: u16 = 6;
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    WrongRom,
+    Truncated,
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {version}")
+            }
+            StateError::WrongRom => write!(f, "save state belongs to a different rom"),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Invalid(reason) => write!(f, "invalid save state: {reason}"),
+        }
+    }
+}
+
+// Implemented by every component that holds machine state. Anything attached from the host
+// side, like a link cable peer or a cartridge sensor, isn't saved and stays attached across loads
+pub trait SaveState {
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+// Serializes values in little endian order
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn write_header(&mut self, rom_checksum: u16) {
+        self.write_bytes(MAGIC);
+        self.write_u16(VERSION);
+        self.write_u16(rom_checksum);
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_option_u8(&mut self, value: Option<u8>) {
+        self.write_bool(value.is_some());
+        self.write_u8(value.unwrap_or(0));
+    }
+
+    pub fn write_option_u16(&mut self, value: Option<u16>) {
+        self.write_bool(value.is_some());
+        self.write_u16(value.unwrap_or(0));
+    }
+
+    // Fixed size data whose length the reader already knows
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    // Variable size data prefixed with its length
+    pub fn write_vec(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.write_bytes(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data, position: 0 }
+    }
+
+    pub fn read_header(&mut self, rom_checksum: u16) -> Result<(), StateError> {
+        let mut magic = [0; 4];
+        self.read_bytes(&mut magic)
+            .map_err(|_| StateError::BadMagic)?;
+        if &magic != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = self.read_u16()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        if self.read_u16()? != rom_checksum {
+            return Err(StateError::WrongRom);
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        let end = self.position + length;
+        if end > self.data.len() {
+            return Err(StateError::Truncated);
+        }
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, StateError> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_option_u8(&mut self) -> Result<Option<u8>, StateError> {
+        let is_some = self.read_bool()?;
+        let value = self.read_u8()?;
+        Ok(is_some.then_some(value))
+    }
+
+    pub fn read_option_u16(&mut self) -> Result<Option<u16>, StateError> {
+        let is_some = self.read_bool()?;
+        let value = self.read_u16()?;
+        Ok(is_some.then_some(value))
+    }
+
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), StateError> {
+        bytes.copy_from_slice(self.take(bytes.len())?);
+        Ok(())
+    }
+
+    pub fn read_vec(&mut self) -> Result<Vec<u8>, StateError> {
+        let length = self.read_u32()? as usize;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.position == self.data.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_read_back_in_order() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0x12);
+        writer.write_bool(true);
+        writer.write_u16(0x3456);
+        writer.write_u32(0x789ABCDE);
+        writer.write_option_u8(None);
+        writer.write_option_u16(Some(0xBEEF));
+        writer.write_vec(&[1, 2, 3]);
+        let data = writer.into_bytes();
+
+        let mut reader = StateReader::new(&data);
+        assert_eq!(reader.read_u8(), Ok(0x12));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u16(), Ok(0x3456));
+        assert_eq!(reader.read_u32(), Ok(0x789ABCDE));
+        assert_eq!(reader.read_option_u8(), Ok(None));
+        assert_eq!(reader.read_option_u16(), Ok(Some(0xBEEF)));
+        assert_eq!(reader.read_vec(), Ok(vec![1, 2, 3]));
+        assert!(reader.is_finished());
+    }
+
+    #[test]
+    fn reading_past_end_is_truncated() {
+        let mut reader = StateReader::new(&[0x01]);
+        assert_eq!(reader.read_u16(), Err(StateError::Truncated));
+    }
+
+    #[test]
+    fn header_rejects_other_versions_and_roms() {
+        let mut writer = StateWriter::new();
+        writer.write_header(0x1234);
+        let data = writer.into_bytes();
+        assert_eq!(StateReader::new(&data).read_header(0x1234), Ok(()));
+        assert_eq!(
+            StateReader::new(&data).read_header(0x4321),
+            Err(StateError::WrongRom)
+        );
+
+        let mut old_version = data.clone();
+        old_version[4] = 0;
+        assert_eq!(
+            StateReader::new(&old_version).read_header(0x1234),
+            Err(StateError::UnsupportedVersion(0))
+        );
+        assert_eq!(
+            StateReader::new(b"nope").read_header(0x1234),
+            Err(StateError::BadMagic)
+        );
+    }
+}
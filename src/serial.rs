@@ -0,0 +1,281 @@
+use crate::memory::Memory;
+use crate::registers::{SB_ADDRESS, SC_ADDRESS};
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+
+// Dots per shifted bit at 8192 Hz and with the cgb fast clock at 262144 Hz
+const NORMAL_CLOCK_DOTS_PER_BIT: u32 = 512;
+const FAST_CLOCK_DOTS_PER_BIT: u32 = 16;
+// What the data line reads when nothing is plugged in
+const DISCONNECTED_DATA: u8 = 0xFF;
+
+const SC_TRANSFER_BIT: u8 = 0b10000000;
+const SC_FAST_CLOCK_BIT: u8 = 0b00000010;
+const SC_INTERNAL_CLOCK_BIT: u8 = 0b00000001;
+
+// Something attached to the other end of the link port
+pub trait SerialPeer {
+    // Called when this side drives the clock, returns the byte the peer sends back
+    fn transfer(&mut self, outgoing: u8) -> u8;
+    // Called on every update while this side waits on an external clock with the dots that
+    // passed, returns the peer's byte once it has clocked a transfer and takes the byte that was
+    // shifted out to it
+    fn poll_external(&mut self, _outgoing: u8, _dots: u32) -> Option<u8> {
+        None
+    }
+    // Called on every other update with the dots that passed and the serial register, which is
+    // what gets shifted out if the other side clocks a transfer while this side isn't listening
+    fn idle(&mut self, _data: u8, _dots: u32) {}
+    // True while the peer holds this side back, nothing should run until an update with no dots
+    // passing finds it has let go
+    fn is_waiting(&self) -> bool {
+        false
+    }
+}
+
+// Prints everything that is sent, which is how test roms report their results
+pub struct ConsoleOutput;
+
+impl SerialPeer for ConsoleOutput {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        print!("{}", outgoing as char);
+        DISCONNECTED_DATA
+    }
+}
+
+pub struct Serial {
+    data: u8,
+    control: u8,
+    incoming: u8,
+    bits_left: u8,
+    dots_passed: u32,
+    peer: Option<Box<dyn SerialPeer>>,
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Serial {
+            data: 0,
+            control: 0,
+            incoming: DISCONNECTED_DATA,
+            bits_left: 0,
+            dots_passed: 0,
+            peer: None,
+        }
+    }
+
+    pub fn set_peer(&mut self, peer: Option<Box<dyn SerialPeer>>) {
+        self.peer = peer;
+    }
+
+    pub fn is_waiting(&self) -> bool {
+        self.peer.as_ref().is_some_and(|peer| peer.is_waiting())
+    }
+
+    // Shifts bits while a transfer is running, returns true when the serial interrupt should be requested
+    pub fn update(&mut self, dots: u32, double_speed: bool) -> bool {
+        let waiting_on_external_clock =
+            self.control & SC_TRANSFER_BIT != 0 && self.control & SC_INTERNAL_CLOCK_BIT == 0;
+        if !waiting_on_external_clock {
+            let data = self.data;
+            if let Some(peer) = self.peer.as_mut() {
+                peer.idle(data, dots);
+            }
+        }
+
+        if self.control & SC_TRANSFER_BIT == 0 {
+            return false;
+        }
+
+        if waiting_on_external_clock {
+            let outgoing = self.data;
+            let Some(incoming) = self
+                .peer
+                .as_mut()
+                .and_then(|p| p.poll_external(outgoing, dots))
+            else {
+                return false;
+            };
+            self.data = incoming;
+            self.finish_transfer();
+            return true;
+        }
+
+        let mut dots_per_bit = if self.control & SC_FAST_CLOCK_BIT != 0 {
+            FAST_CLOCK_DOTS_PER_BIT
+        } else {
+            NORMAL_CLOCK_DOTS_PER_BIT
+        };
+        if double_speed {
+            dots_per_bit /= 2;
+        }
+
+        self.dots_passed += dots;
+        while self.dots_passed >= dots_per_bit && self.bits_left > 0 {
+            self.dots_passed -= dots_per_bit;
+            self.bits_left -= 1;
+            let incoming_bit = (self.incoming >> self.bits_left) & 1;
+            self.data = (self.data << 1) | incoming_bit;
+        }
+
+        if self.bits_left == 0 {
+            self.finish_transfer();
+            return true;
+        }
+        false
+    }
+
+    fn start_transfer(&mut self) {
+        self.bits_left = 8;
+        self.dots_passed = 0;
+        if self.control & SC_INTERNAL_CLOCK_BIT != 0 {
+            // Both sides exchange their whole byte up front and the bits are shifted in over time
+            let outgoing = self.data;
+            self.incoming = match self.peer.as_mut() {
+                Some(peer) => peer.transfer(outgoing),
+                None => DISCONNECTED_DATA,
+            };
+        }
+    }
+
+    fn finish_transfer(&mut self) {
+        self.control &= !SC_TRANSFER_BIT;
+        self.bits_left = 0;
+        self.dots_passed = 0;
+    }
+}
+
+impl Memory for Serial {
+    fn read(&self, address: u16) -> u8 {
+        match address {
+            SB_ADDRESS => self.data,
+            SC_ADDRESS => self.control | 0b01111100,
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            SB_ADDRESS => self.data = data,
+            SC_ADDRESS => {
+                self.control = data & (SC_TRANSFER_BIT | SC_FAST_CLOCK_BIT | SC_INTERNAL_CLOCK_BIT);
+                if self.control & SC_TRANSFER_BIT != 0 {
+                    self.start_transfer();
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl SaveState for Serial {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.data);
+        state.write_u8(self.control);
+        state.write_u8(self.incoming);
+        state.write_u8(self.bits_left);
+        state.write_u32(self.dots_passed);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.data = state.read_u8()?;
+        self.control = state.read_u8()?;
+        self.incoming = state.read_u8()?;
+        self.bits_left = state.read_u8()?;
+        self.dots_passed = state.read_u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    struct Recorder {
+        sent: Rc<RefCell<Vec<u8>>>,
+        reply: u8,
+        external_reply: Option<u8>,
+    }
+
+    impl SerialPeer for Recorder {
+        fn transfer(&mut self, outgoing: u8) -> u8 {
+            self.sent.borrow_mut().push(outgoing);
+            self.reply
+        }
+
+        fn poll_external(&mut self, outgoing: u8, _dots: u32) -> Option<u8> {
+            let reply = self.external_reply.take()?;
+            self.sent.borrow_mut().push(outgoing);
+            Some(reply)
+        }
+    }
+
+    fn get_test_serial(reply: u8, external_reply: Option<u8>) -> (Serial, Rc<RefCell<Vec<u8>>>) {
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let mut serial = Serial::new();
+        serial.set_peer(Some(Box::new(Recorder {
+            sent: sent.clone(),
+            reply,
+            external_reply,
+        })));
+        (serial, sent)
+    }
+
+    #[test]
+    fn internal_clock_transfer_takes_eight_bits() {
+        let (mut serial, sent) = get_test_serial(0x5A, None);
+        serial.write(SB_ADDRESS, 0x42);
+        serial.write(SC_ADDRESS, 0x81);
+        assert_eq!(*sent.borrow(), vec![0x42]);
+
+        assert!(!serial.update(NORMAL_CLOCK_DOTS_PER_BIT * 8 - 1, false));
+        assert_eq!(serial.read(SC_ADDRESS) & SC_TRANSFER_BIT, SC_TRANSFER_BIT);
+        assert!(serial.update(1, false));
+        assert_eq!(serial.read(SB_ADDRESS), 0x5A);
+        assert_eq!(serial.read(SC_ADDRESS) & SC_TRANSFER_BIT, 0);
+    }
+
+    #[test]
+    fn bits_are_shifted_in_one_at_a_time() {
+        let (mut serial, _) = get_test_serial(0x00, None);
+        serial.write(SB_ADDRESS, 0xFF);
+        serial.write(SC_ADDRESS, 0x81);
+        serial.update(NORMAL_CLOCK_DOTS_PER_BIT * 3, false);
+        assert_eq!(serial.read(SB_ADDRESS), 0xF8);
+    }
+
+    #[test]
+    fn fast_clock_and_double_speed_shorten_transfer() {
+        let (mut serial, _) = get_test_serial(0x00, None);
+        serial.write(SC_ADDRESS, 0x83);
+        assert!(serial.update(FAST_CLOCK_DOTS_PER_BIT * 8, false));
+
+        serial.write(SC_ADDRESS, 0x81);
+        assert!(serial.update(NORMAL_CLOCK_DOTS_PER_BIT * 4, true));
+    }
+
+    #[test]
+    fn disconnected_transfer_reads_ones() {
+        let mut serial = Serial::new();
+        serial.write(SB_ADDRESS, 0x00);
+        serial.write(SC_ADDRESS, 0x81);
+        assert!(serial.update(NORMAL_CLOCK_DOTS_PER_BIT * 8, false));
+        assert_eq!(serial.read(SB_ADDRESS), 0xFF);
+    }
+
+    #[test]
+    fn external_clock_waits_for_peer() {
+        let mut serial = Serial::new();
+        serial.write(SC_ADDRESS, 0x80);
+        assert!(!serial.update(NORMAL_CLOCK_DOTS_PER_BIT * 16, false));
+
+        let (mut serial, sent) = get_test_serial(0x00, Some(0x99));
+        serial.write(SB_ADDRESS, 0x11);
+        serial.write(SC_ADDRESS, 0x80);
+        assert!(serial.update(4, false));
+        assert_eq!(serial.read(SB_ADDRESS), 0x99);
+        assert_eq!(*sent.borrow(), vec![0x11]);
+    }
+}
@@ -3,6 +3,7 @@ use std::{cell::RefCell, num::Wrapping, rc::Rc};
 use crate::mem_manager::MemManager;
 use crate::memory::Memory;
 use crate::registers::{DIV_ADDRESS, TAC_ADDRESS, TIMA_ADDRESS, TMA_ADDRESS};
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 
 const BASE_SPEED: u32 = 16;
 
@@ -18,13 +19,14 @@ pub struct Timer {
 impl Timer {
     pub fn new(memory: Rc<RefCell<MemManager>>) -> Self {
         const DOTS_PER_SCANLINE: u32 = 456;
-        let mut timer = Timer::new_test(memory);
+        let mut timer = Timer::new_at_reset(memory);
         // Needed because emulator starts at pc = 0x0100 instead of actual hardware that starts at pc = 0x0000
         timer.update(DOTS_PER_SCANLINE * 147 + 180);
         timer
     }
 
-    fn new_test(memory: Rc<RefCell<MemManager>>) -> Self {
+    // State at power on, which is where execution starts when a boot rom is used
+    pub fn new_at_reset(memory: Rc<RefCell<MemManager>>) -> Self {
         let timer = Timer {
             memory,
             available_cycles_div: 0,
@@ -109,12 +111,29 @@ impl Timer {
     }
 }
 
+impl SaveState for Timer {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(self.available_cycles_div);
+        state.write_u32(self.available_cycles_tima);
+        state.write_bool(self.interrupt_ready);
+        state.write_bool(self.set_to_tma_ready);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.available_cycles_div = state.read_u32()?;
+        self.available_cycles_tima = state.read_u32()?;
+        self.interrupt_ready = state.read_bool()?;
+        self.set_to_tma_ready = state.read_bool()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     fn get_test_timer() -> Timer {
-        Timer::new_test(Rc::new(RefCell::new(MemManager::new())))
+        Timer::new_at_reset(Rc::new(RefCell::new(MemManager::new())))
     }
 
     fn read_div_and_tima(tim: Timer) -> (u8, u8) {